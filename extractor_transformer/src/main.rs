@@ -5,10 +5,12 @@
 #[cfg(feature = "ORCHESTRATED")]
 use actix_web::{web, HttpResponse};
 use backon::{ExponentialBuilder, Retryable};
+#[cfg(any(feature = "JSONL", not(feature = "ORCHESTRATED")))]
+use blockchain_etl_indexer::blockchain_config::build_provider;
 use blockchain_etl_indexer::blockchain_config::proto_codegen::etl::request::IndexingRequest;
 use blockchain_etl_indexer::blockchain_config::proto_codegen::etl::simprequest::SimpleIndexingRequest;
 #[cfg(feature = "JSONL")]
-use blockchain_etl_indexer::blockchain_config::{build_provider, save_range};
+use blockchain_etl_indexer::blockchain_config::save_range;
 use blockchain_etl_indexer::metrics::Metrics;
 use clap::{Args, Parser, Subcommand};
 use log::warn;
@@ -56,8 +58,12 @@ enum Commands {
     /// Extract blocks from a list
     #[cfg(not(feature = "ORCHESTRATED"))]
     IndexList(IndexListArgs),
+    /// Follow the tip of the chain, resuming from a checkpoint
+    Follow(FollowArgs),
     /// Save range
     SaveRange(SaveRangeArgs),
+    /// Transform the extractions saved by save-range, without a node
+    TransformSaved(TransformSavedArgs),
     // Creates a test range
     CreateTestSet(CreateTestRangeArgs),
 }
@@ -85,6 +91,15 @@ struct IndexRangeArgs {
     reverse: bool,
 }
 
+#[derive(Args)]
+struct FollowArgs {
+    /// The block to begin indexing from, if there is no checkpoint yet (defaults to the head)
+    start: Option<u64>,
+    /// Path to the checkpoint file
+    #[clap(long, default_value = "./follow_checkpoint")]
+    checkpoint: PathBuf,
+}
+
 #[derive(Args)]
 struct SaveRangeArgs {
     /// The slot to begin indexing from
@@ -95,6 +110,12 @@ struct SaveRangeArgs {
     outdir: PathBuf,
 }
 
+#[derive(Args)]
+struct TransformSavedArgs {
+    /// Path to the directory of the saved extractions
+    dir: PathBuf,
+}
+
 #[derive(Debug, Clone, Args)]
 struct CreateTestRangeArgs {
     /// The slot to begin indexing from
@@ -309,10 +330,88 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .registry
             .register(Box::new(request_count.clone()))
             .unwrap();
+        let extracted_queue_depth = prometheus::IntGauge::new(
+            "extracted_queue_depth",
+            "Number of extracted blocks waiting to be transformed",
+        )
+        .unwrap();
+        let transformed_queue_depth = prometheus::IntGauge::new(
+            "transformed_queue_depth",
+            "Number of transformed blocks waiting to be published",
+        )
+        .unwrap();
+        let pool_endpoint_up = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new(
+                "pool_endpoint_up",
+                "Whether the provider pool endpoint serves calls (1) or is ejected (0)",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let pool_endpoint_latency_seconds = prometheus::GaugeVec::new(
+            prometheus::Opts::new(
+                "pool_endpoint_latency_seconds",
+                "Moving average of the latency of the provider pool endpoint",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let pool_endpoint_error_rate = prometheus::GaugeVec::new(
+            prometheus::Opts::new(
+                "pool_endpoint_error_rate",
+                "Moving average of the error rate of the provider pool endpoint",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let pool_endpoint_head_lag = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new(
+                "pool_endpoint_head_lag",
+                "Number of blocks the provider pool endpoint is behind the highest head",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let pool_endpoint_throttle_seconds = prometheus::CounterVec::new(
+            prometheus::Opts::new(
+                "pool_endpoint_throttle_seconds",
+                "Total time the calls waited for the rate limit of the provider pool endpoint",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
         prometheus
             .registry
             .register(Box::new(failed_request_count.clone()))
             .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(pool_endpoint_up.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(pool_endpoint_latency_seconds.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(pool_endpoint_error_rate.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(pool_endpoint_head_lag.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(pool_endpoint_throttle_seconds.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(extracted_queue_depth.clone()))
+            .unwrap();
+        prometheus
+            .registry
+            .register(Box::new(transformed_queue_depth.clone()))
+            .unwrap();
 
         let srv = HttpServer::new(move || App::new().wrap(prometheus.clone()).service(index))
             .bind((metrics_address, metrics_port))?
@@ -325,6 +424,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let metrics = Metrics {
             request_count,
             failed_request_count,
+            extracted_queue_depth,
+            transformed_queue_depth,
+            pool_endpoint_up,
+            pool_endpoint_latency_seconds,
+            pool_endpoint_error_rate,
+            pool_endpoint_head_lag,
+            pool_endpoint_throttle_seconds,
         };
         (Some(metrics), srv_handle)
     };
@@ -396,9 +502,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
             let cur_publisher = publisher.clone();
 
-            blockchain_config::subscribe_and_extract(subscription, cur_publisher, metrics)
-                .await
-                .unwrap();
+            blockchain_config::subscribe_and_extract(subscription, cur_publisher, metrics).await;
 
             #[cfg(feature = "REQUIRES_DISCONNECT")]
             publisher.disconnect().await;
@@ -420,7 +524,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .into(),
                 cur_publisher,
                 metrics,
-                None,
+                build_provider().await,
                 None,
             )
             .await
@@ -433,6 +537,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Commands::IndexList(_) => {
             unreachable!("IndexList not supported")
         }
+        Commands::Follow(args) => {
+            let publisher = StreamPublisher::new().await;
+
+            let cur_publisher = publisher.clone();
+
+            blockchain_config::follow_chain(args.start, &args.checkpoint, cur_publisher, metrics)
+                .await
+                .expect("Follow the chain");
+
+            #[cfg(feature = "REQUIRES_DISCONNECT")]
+            publisher.disconnect().await;
+        }
         Commands::SaveRange(args) => {
             #[cfg(not(feature = "JSONL"))]
             unimplemented!("SaveRange only implemented when compiled with JSONL feature");
@@ -444,12 +560,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     ..Default::default()
                 },
                 metrics,
-                Some(build_provider()),
+                build_provider().await,
                 &args.outdir,
             )
             .await
             .expect("Save the range");
         }
+        Commands::TransformSaved(args) => {
+            let publisher = StreamPublisher::new().await;
+
+            let cur_publisher = publisher.clone();
+
+            let failed = blockchain_config::transform_saved(&args.dir, cur_publisher, None)
+                .await
+                .expect("List the saved extractions");
+            if !failed.is_empty() {
+                log::error!(
+                    "Failed to transform or publish {} saved blocks: {:?}",
+                    failed.len(),
+                    failed
+                        .iter()
+                        .map(|(block_number, _)| *block_number)
+                        .collect::<Vec<_>>()
+                );
+            }
+
+            #[cfg(feature = "REQUIRES_DISCONNECT")]
+            publisher.disconnect().await;
+
+            if !failed.is_empty() {
+                std::process::exit(1);
+            }
+        }
         #[cfg(feature = "JSONL")]
         Commands::CreateTestSet(args) => {
             use crate::blockchain_config::test::TestSet;
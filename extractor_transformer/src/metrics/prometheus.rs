@@ -1,4 +1,4 @@
-use prometheus::IntCounter;
+use prometheus::{CounterVec, GaugeVec, IntCounter, IntGauge, IntGaugeVec};
 
 /// A wrapper struct around each of our metrics.
 #[cfg(feature="METRICS")]
@@ -8,4 +8,18 @@ pub struct Metrics {
     pub request_count: IntCounter,
     // Total number of failed requests.
     pub failed_request_count: IntCounter,
+    // Number of extracted blocks waiting to be transformed.
+    pub extracted_queue_depth: IntGauge,
+    // Number of transformed blocks waiting to be published.
+    pub transformed_queue_depth: IntGauge,
+    // Whether each endpoint of the provider pool is serving calls (1) or ejected (0).
+    pub pool_endpoint_up: IntGaugeVec,
+    // Moving average of the latency of each endpoint of the provider pool.
+    pub pool_endpoint_latency_seconds: GaugeVec,
+    // Moving average of the error rate of each endpoint of the provider pool.
+    pub pool_endpoint_error_rate: GaugeVec,
+    // Number of blocks each endpoint of the provider pool is behind the highest head.
+    pub pool_endpoint_head_lag: IntGaugeVec,
+    // Total time the calls waited for the rate limit of each endpoint of the provider pool.
+    pub pool_endpoint_throttle_seconds: CounterVec,
 }
\ No newline at end of file
@@ -0,0 +1,245 @@
+//! An archive of the raw extractions of every block indexed, enabled by `RAW_ARCHIVE`.
+//!
+//! The basic extraction and the debug traces of a block are serialized like
+//! [save_block](super::save_block) saves them, compressed with zstd, and written to
+//! `<partition>/basic_<n>.json.zst` and `<partition>/debug_<n>.json.zst`, the blocks being
+//! partitioned into ranges of `RAW_ARCHIVE_PARTITION_SIZE` blocks.  The archive is either a local
+//! directory, or a `gs://<bucket>/<prefix>` location when compiled with the
+//! `GOOGLE_CLOUD_STORAGE` feature.
+//!
+//! A partition can be transformed again without the node, with the `transform-saved` subcommand.
+
+use std::{
+    env::{self, VarError},
+    io,
+    path::PathBuf,
+};
+
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+use super::{positive_count_from_env, BlockExtraction};
+
+pub const RAW_ARCHIVE_ENVKEY: &str = "RAW_ARCHIVE";
+pub const RAW_ARCHIVE_PARTITION_SIZE_ENVKEY: &str = "RAW_ARCHIVE_PARTITION_SIZE";
+
+/// The number of blocks in a partition of the archive when `RAW_ARCHIVE_PARTITION_SIZE` is not
+/// set
+pub const DEFAULT_RAW_ARCHIVE_PARTITION_SIZE: usize = 10000;
+
+/// The zstd compression level of the archived extractions
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The archive shared by every range indexed by this process, `None` if disabled
+static RAW_ARCHIVE: OnceCell<Option<RawArchive>> = OnceCell::const_new();
+
+/// Returns the archive of the raw extractions, `None` if `RAW_ARCHIVE` isn't set (which disables
+/// it).
+pub async fn raw_archive() -> Option<&'static RawArchive> {
+    RAW_ARCHIVE.get_or_init(RawArchive::from_env).await.as_ref()
+}
+
+/// Where the archived extractions are written.
+enum ArchiveSink {
+    Dir(PathBuf),
+    #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+    Gcs {
+        client: google_cloud_storage::client::Client,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+pub struct RawArchive {
+    sink: ArchiveSink,
+    partition_size: u64,
+}
+
+impl RawArchive {
+    async fn from_env() -> Option<Self> {
+        let location = match env::var(RAW_ARCHIVE_ENVKEY) {
+            Ok(location) => location,
+            Err(VarError::NotPresent) => return None,
+            Err(VarError::NotUnicode(badstr)) => {
+                error!(
+                    "Failed to parse the archive location from envkey `{}`, (archive disabled): bad string '{:?}'",
+                    RAW_ARCHIVE_ENVKEY, badstr
+                );
+                return None;
+            }
+        };
+
+        let sink = match location.strip_prefix("gs://") {
+            #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+            Some(path) => {
+                let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+                ArchiveSink::Gcs {
+                    client: gcs_client().await,
+                    bucket: bucket.to_string(),
+                    prefix: prefix.trim_end_matches('/').to_string(),
+                }
+            }
+            #[cfg(not(feature = "GOOGLE_CLOUD_STORAGE"))]
+            Some(_) => {
+                error!(
+                    "Archiving to `{}` requires the GOOGLE_CLOUD_STORAGE feature, (archive disabled)",
+                    location
+                );
+                return None;
+            }
+            None => ArchiveSink::Dir(PathBuf::from(&location)),
+        };
+        info!("Archiving the raw extractions to `{}`", location);
+
+        Some(Self {
+            sink,
+            partition_size: positive_count_from_env(
+                RAW_ARCHIVE_PARTITION_SIZE_ENVKEY,
+                DEFAULT_RAW_ARCHIVE_PARTITION_SIZE,
+            ) as u64,
+        })
+    }
+
+    /// Writes the basic extraction and the debug traces of the block to its partition.
+    pub async fn archive(&self, extraction: &BlockExtraction) -> io::Result<()> {
+        let block_number = extraction.basic.block_number;
+        let partition = partition(block_number, self.partition_size);
+
+        self.write(
+            &partition,
+            &format!("basic_{}.json.zst", block_number),
+            &extraction.basic,
+        )
+        .await?;
+        self.write(
+            &partition,
+            &format!("debug_{}.json.zst", block_number),
+            &extraction.debug,
+        )
+        .await
+    }
+
+    async fn write<T: Serialize>(
+        &self,
+        partition: &str,
+        name: &str,
+        payload: &T,
+    ) -> io::Result<()> {
+        let compressed =
+            zstd::encode_all(serde_json::to_vec(payload)?.as_slice(), COMPRESSION_LEVEL)?;
+
+        match &self.sink {
+            ArchiveSink::Dir(dir) => {
+                let dir = dir.join(partition);
+                tokio::fs::create_dir_all(&dir).await?;
+
+                // written aside then renamed, so that an extraction is never read half written
+                let path = dir.join(name);
+                let tmp_path = path.with_extension("tmp");
+                tokio::fs::write(&tmp_path, &compressed).await?;
+                tokio::fs::rename(&tmp_path, &path).await
+            }
+            #[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+            ArchiveSink::Gcs {
+                client,
+                bucket,
+                prefix,
+            } => {
+                use google_cloud_storage::http::objects::upload::{
+                    Media, UploadObjectRequest, UploadType,
+                };
+
+                let object = [prefix.as_str(), partition, name]
+                    .into_iter()
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                client
+                    .upload_object(
+                        &UploadObjectRequest {
+                            bucket: bucket.clone(),
+                            ..Default::default()
+                        },
+                        compressed,
+                        &UploadType::Simple(Media::new(object)),
+                    )
+                    .await
+                    .map(|_| ())
+                    .map_err(io::Error::other)
+            }
+        }
+    }
+}
+
+/// Returns the name of the partition holding the block, e.g. `000000010000-000000019999`.
+fn partition(block_number: u64, partition_size: u64) -> String {
+    let start = block_number - block_number % partition_size;
+    format!("{:012}-{:012}", start, start + partition_size - 1)
+}
+
+#[cfg(feature = "GOOGLE_CLOUD_STORAGE")]
+async fn gcs_client() -> google_cloud_storage::client::Client {
+    use google_cloud_storage::client::{
+        google_cloud_auth::credentials::CredentialsFile, Client, ClientConfig,
+    };
+
+    let config = match crate::output::environment::get_gcp_credentials_json_path() {
+        Some(key_path) => {
+            let cred_file = CredentialsFile::new_from_file(key_path.to_owned())
+                .await
+                .expect("GCP credentials file exists");
+            ClientConfig::default()
+                .with_credentials(cred_file)
+                .await
+                .unwrap()
+        }
+        None => ClientConfig::default().with_auth().await.unwrap(),
+    };
+    Client::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::FixedBytes;
+
+    use crate::blockchain_config::{
+        extraction::{DebugTraces, EvmExtracted},
+        saved::load_saved_block,
+    };
+
+    #[tokio::test]
+    async fn archives_blocks_readable_as_saved_extractions() {
+        assert_eq!(partition(0, 10000), "000000000000-000000009999");
+        assert_eq!(partition(20000, 10000), "000000020000-000000029999");
+
+        let dir = std::env::temp_dir().join(format!("raw_archive_{}", std::process::id()));
+        let archive = RawArchive {
+            sink: ArchiveSink::Dir(dir.clone()),
+            partition_size: 100,
+        };
+        let extraction = BlockExtraction {
+            basic: EvmExtracted {
+                block_number: 1234,
+                block_hash: FixedBytes::repeat_byte(0x12),
+                parent_hash: FixedBytes::repeat_byte(0x11),
+                block_timestamp: 1_700_000_000,
+                block: None,
+                logs: Some(Vec::new()),
+                receipts: Some(Vec::new()),
+            },
+            debug: DebugTraces::new(1234, None, 1_700_000_000, Vec::new()),
+            state: None,
+            tokens: None,
+        };
+        archive.archive(&extraction).await.unwrap();
+
+        let loaded = load_saved_block(&dir.join("000000001200-000000001299"), 1234).unwrap();
+        assert_eq!(loaded.basic, extraction.basic);
+        assert_eq!(loaded.debug, extraction.debug);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//! Checks that the blocks of a range chain up, i.e. that the parent hash of each block is the hash
+//! of the block indexed before it.
+//!
+//! A block which doesn't chain up is first extracted again, since the node may simply have been
+//! behind (or on a side fork) when it was first extracted.  If it still doesn't, the blocks before
+//! it were reorged out: they are extracted again, walking back until the chain links up with the
+//! blocks already indexed, and each of them is published again along with a [Reorg] record naming
+//! the orphaned block hash, so that the rows indexed from it can be purged downstream.
+
+use std::collections::VecDeque;
+
+use alloy::{primitives::BlockHash, providers::Provider, transports::Transport};
+use log::{debug, error, warn};
+
+use super::{
+    extract, extraction::EvmExtraction, positive_count_from_env, proto_codegen::etl::reorgs::Reorg,
+    BlockExtraction, ExtractTransformErr, IndexingRequest, Metrics,
+};
+
+pub const CONTINUITY_WINDOW_ENVKEY: &str = "CONTINUITY_WINDOW";
+
+/// The number of block hashes remembered when `CONTINUITY_WINDOW` is not set
+pub const DEFAULT_CONTINUITY_WINDOW: usize = 128;
+
+/// Returns the number of block hashes remembered to link a reorged chain back up, read from the
+/// `CONTINUITY_WINDOW` envkey.  Falls back to [DEFAULT_CONTINUITY_WINDOW] if missing or invalid.
+pub fn continuity_window() -> usize {
+    positive_count_from_env(CONTINUITY_WINDOW_ENVKEY, DEFAULT_CONTINUITY_WINDOW)
+}
+
+/// The hashes of the most recent blocks indexed, in ascending block order.
+#[derive(Debug, Clone)]
+pub struct ChainTracker {
+    hashes: VecDeque<(u64, BlockHash)>,
+    capacity: usize,
+}
+
+impl ChainTracker {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The hash the block was indexed with, if still remembered.
+    pub fn hash_of(&self, block_number: u64) -> Option<BlockHash> {
+        self.hashes
+            .iter()
+            .rev()
+            .find(|(number, _)| *number == block_number)
+            .map(|(_, hash)| *hash)
+    }
+
+    /// Whether the block chains up with the one indexed before it.  Blocks without a known
+    /// predecessor (e.g. the first block of a range) are assumed to.
+    pub fn follows(&self, block_number: u64, parent_hash: BlockHash) -> bool {
+        match block_number
+            .checked_sub(1)
+            .and_then(|parent| self.hash_of(parent))
+        {
+            Some(hash) => hash == parent_hash,
+            None => true,
+        }
+    }
+
+    /// Remembers the hash of the block, forgetting the hashes of any block at or above it.
+    pub fn record(&mut self, block_number: u64, hash: BlockHash) {
+        while matches!(self.hashes.back(), Some((number, _)) if *number >= block_number) {
+            self.hashes.pop_back();
+        }
+        if self.hashes.len() == self.capacity {
+            self.hashes.pop_front();
+        }
+        self.hashes.push_back((block_number, hash));
+    }
+}
+
+/// A block extracted again to replace an orphaned one, along with the record of the reorg.
+pub type Replacement = (BlockExtraction, Reorg);
+
+/// Whether the block chains up with the blocks already indexed, and the node didn't flag any of
+/// its logs as removed (which happens when a reorg is underway).
+fn is_consistent(chain: &ChainTracker, extraction: &BlockExtraction) -> bool {
+    let removed_logs = extraction
+        .basic
+        .logs()
+        .map_or(false, |logs| logs.iter().any(|log| log.removed));
+
+    !removed_logs
+        && chain.follows(
+            extraction.basic.block_number(),
+            extraction.basic.parent_hash(),
+        )
+}
+
+/// Makes sure the block chains up with the blocks already indexed, and records it.
+///
+/// Returns the block (extracted again if it didn't chain up at first), preceded by the blocks
+/// extracted again to replace the orphaned ones, in ascending order.  Fails if the chain can't be
+/// linked back up within the [ChainTracker]'s window.
+pub async fn ensure_continuity<T, P>(
+    block_number: u64,
+    extraction: BlockExtraction,
+    chain: &mut ChainTracker,
+    request: &IndexingRequest,
+    metrics: &Option<Metrics>,
+    provider: &P,
+) -> Result<(Vec<Replacement>, BlockExtraction), ExtractTransformErr>
+where
+    T: Transport + Clone,
+    P: Provider<T> + Clone,
+{
+    if is_consistent(chain, &extraction) {
+        chain.record(block_number, extraction.basic.block_hash());
+        return Ok((Vec::new(), extraction));
+    }
+
+    warn!(
+        "Block #{} doesn't chain up with the blocks indexed before it, extracting it again",
+        block_number
+    );
+    // `Metrics` is `()` (thus `Copy`) without the METRICS feature
+    #[allow(clippy::clone_on_copy)]
+    let extraction = extract(
+        block_number,
+        metrics.clone(),
+        Some(request.clone()),
+        provider.clone(),
+    )
+    .await?;
+    if is_consistent(chain, &extraction) {
+        chain.record(block_number, extraction.basic.block_hash());
+        return Ok((Vec::new(), extraction));
+    }
+
+    // the parent hash is now assumed canonical, so the blocks indexed before it were orphaned
+    let mut replacements = Vec::new();
+    let mut expected_hash = extraction.basic.parent_hash();
+    let mut number = block_number;
+
+    loop {
+        number = match number.checked_sub(1) {
+            Some(number) => number,
+            None => break,
+        };
+        let orphaned_hash = match chain.hash_of(number) {
+            Some(hash) if hash == expected_hash => break,
+            Some(hash) => hash,
+            None => {
+                error!(
+                    "Block #{} doesn't chain up with the blocks indexed before it, and the reorg \
+                     goes deeper than the {} blocks remembered",
+                    block_number, chain.capacity
+                );
+                return Err(ExtractTransformErr::Discontinuity(block_number));
+            }
+        };
+
+        debug!("Extracting orphaned block #{} again", number);
+        #[allow(clippy::clone_on_copy)]
+        let replacement = extract(
+            number,
+            metrics.clone(),
+            Some(request.clone()),
+            provider.clone(),
+        )
+        .await?;
+        if replacement.basic.block_hash() != expected_hash {
+            // the chain changed again while walking back, the range has to be retried
+            error!(
+                "Block #{} is {} instead of the parent {} of the block after it",
+                number,
+                replacement.basic.block_hash(),
+                expected_hash
+            );
+            return Err(ExtractTransformErr::Discontinuity(block_number));
+        }
+
+        warn!(
+            "Reorg detected at block #{}: block #{} {} was replaced by {}",
+            block_number, number, orphaned_hash, expected_hash
+        );
+        let reorg = Reorg {
+            block_hash: format!("{}", expected_hash),
+            block_number: number as i64,
+            block_timestamp: replacement.basic.block_timestamp(),
+            orphaned_block_hash: format!("{}", orphaned_hash),
+            detected_at_block_number: block_number as i64,
+        };
+        expected_hash = replacement.basic.parent_hash();
+        replacements.push((replacement, reorg));
+    }
+
+    replacements.reverse();
+    for (replacement, _) in replacements.iter() {
+        chain.record(
+            replacement.basic.block_number(),
+            replacement.basic.block_hash(),
+        );
+    }
+    chain.record(block_number, extraction.basic.block_hash());
+
+    Ok((replacements, extraction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::B256;
+
+    #[test]
+    fn forgets_blocks_above_a_replaced_one() {
+        let hash = |byte: u8| B256::repeat_byte(byte);
+        let mut chain = ChainTracker::new(3);
+
+        for number in 1..=4 {
+            chain.record(number, hash(number as u8));
+        }
+        // the oldest block fell out of the window
+        assert_eq!(chain.hash_of(1), None);
+        assert!(chain.follows(2, hash(0xff)));
+        assert!(chain.follows(5, hash(4)));
+        assert!(!chain.follows(5, hash(0xff)));
+
+        chain.record(3, hash(0x33));
+        assert_eq!(chain.hash_of(3), Some(hash(0x33)));
+        assert_eq!(chain.hash_of(4), None);
+        assert!(chain.follows(4, hash(0x33)));
+    }
+}
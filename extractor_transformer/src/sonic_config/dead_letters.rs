@@ -0,0 +1,122 @@
+//! The dead letters of the blocks which failed to be extracted, transformed or published,
+//! published to `QUEUE_NAME_DEAD_LETTERS` (a topic, or a `.jsonl` file for the JSONL builds) so
+//! that the rest of their range can still be published and acknowledged.
+//!
+//! A dead letter holds the kind, code, message, and field (for transformation errors) of the error,
+//! along with the raw extraction of the block when it got that far, serialized like
+//! [save_block](super::save_block) saves it, so that the block can be investigated and indexed
+//! again later.
+
+use log::error;
+
+use crate::output::publish::StreamPublisherConnection;
+
+use super::{
+    proto_codegen::etl::dead_letters::DeadLetter, publish_records, BlockExtraction,
+    ExtractTransformErr,
+};
+
+/// Builds the dead letter of the block which failed with the error.
+pub fn dead_letter(
+    block_number: u64,
+    err: &ExtractTransformErr,
+    extraction: Option<&BlockExtraction>,
+) -> DeadLetter {
+    let (field, message) = match err {
+        ExtractTransformErr::Transformation(err) => {
+            (err.field().map(str::to_string), err.message().to_string())
+        }
+        other => (None, other.to_string()),
+    };
+
+    DeadLetter {
+        block_number: block_number as i64,
+        error_kind: err.kind().to_string(),
+        error_code: err.code().to_string(),
+        field,
+        message,
+        basic_extraction: extraction.and_then(|extraction| to_json(&extraction.basic)),
+        debug_extraction: extraction.and_then(|extraction| to_json(&extraction.debug)),
+    }
+}
+
+/// Publishes the dead letter of the block which failed with the error.
+pub async fn publish_dead_letter(
+    publisher: &StreamPublisherConnection,
+    block_number: u64,
+    err: &ExtractTransformErr,
+    extraction: Option<&BlockExtraction>,
+) {
+    let timestamp = extraction.map_or(0, |extraction| extraction.basic.block_timestamp);
+    publish_records(
+        publisher,
+        vec![dead_letter(block_number, err, extraction)],
+        Some(&format!("{}", block_number)),
+        vec![timestamp],
+    )
+    .await;
+}
+
+fn to_json<T: serde::Serialize>(extracted: &T) -> Option<String> {
+    match serde_json::to_string(extracted) {
+        Ok(json) => Some(json),
+        Err(err) => {
+            error!(
+                "Failed to serialize the extraction of a dead letter: {}",
+                err
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::FixedBytes;
+
+    use crate::blockchain_config::{
+        extraction::{DebugTraces, EvmExtracted},
+        transformation::err::TransformationErr,
+    };
+
+    #[test]
+    fn records_the_error_and_the_raw_extraction() {
+        let extraction = BlockExtraction {
+            basic: EvmExtracted {
+                block_number: 42,
+                block_hash: FixedBytes::repeat_byte(0x42),
+                parent_hash: FixedBytes::repeat_byte(0x41),
+                block_timestamp: 1_700_000_000,
+                block: None,
+                logs: Some(Vec::new()),
+                receipts: Some(Vec::new()),
+            },
+            debug: DebugTraces::new(42, None, 1_700_000_000, Vec::new()),
+            state: None,
+            tokens: None,
+        };
+        let err = ExtractTransformErr::Transformation(TransformationErr::missing("gas_price"));
+
+        let letter = dead_letter(42, &err, Some(&extraction));
+        assert_eq!(letter.block_number, 42);
+        assert_eq!(letter.error_kind, "transformation");
+        assert_eq!(letter.error_code, "missing_field");
+        assert_eq!(letter.field.as_deref(), Some("gas_price"));
+        assert_eq!(letter.message, "Missing gas_price");
+        let basic: EvmExtracted =
+            serde_json::from_str(letter.basic_extraction.as_deref().unwrap()).unwrap();
+        assert_eq!(basic, extraction.basic);
+        let debug: DebugTraces =
+            serde_json::from_str(letter.debug_extraction.as_deref().unwrap()).unwrap();
+        assert_eq!(debug, extraction.debug);
+
+        // failed before being extracted
+        let letter = dead_letter(43, &ExtractTransformErr::Discontinuity(43), None);
+        assert_eq!(letter.error_kind, "discontinuity");
+        assert_eq!(letter.error_code, "discontinuity");
+        assert_eq!(letter.field, None);
+        assert_eq!(letter.basic_extraction, None);
+    }
+}
@@ -0,0 +1,284 @@
+//! Probes which of the optional RPC methods the node supports, once per process, so that the
+//! extractors fall back to the methods it does support rather than failing every block:
+//!
+//! * without `eth_getBlockReceipts`, the receipts are retrieved one transaction at a time, with
+//!   `eth_getTransactionReceipt`;
+//! * without the RPC of the configured `TRACE_BACKEND`, the traces are retrieved with the other
+//!   one (`trace_block` or `debug_traceBlockByNumber`);
+//! * without JSON-RPC batches, the calls of a block are sent one by one.
+//!
+//! A method failing to be probed for another reason (e.g. the node being unreachable) is assumed
+//! to be supported, as it was before probing.
+
+use alloy::{
+    providers::Provider,
+    rpc::client::{BatchRequest, Waiter},
+    transports::{RpcError, Transport, TransportResult},
+};
+use log::{info, warn};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+use super::{geth, TraceBackend};
+
+/// The number of blocks behind the head of the block the methods are probed at, which every node
+/// has had the time to trace
+const PROBE_DEPTH: u64 = 16;
+
+/// The JSON-RPC error code of an unknown method
+const METHOD_NOT_FOUND_CODE: i64 = -32601;
+
+/// Messages of the nodes answering an unknown or disabled method with a generic error code
+const UNSUPPORTED_MESSAGES: [&str; 4] = [
+    "not supported",
+    "unsupported",
+    "does not exist",
+    "not available",
+];
+
+/// The capabilities of the node, probed once by [probe_capabilities]
+static CAPABILITIES: OnceCell<Capabilities> = OnceCell::const_new();
+
+/// Probes the capabilities of the node behind the provider, unless they already were, and logs
+/// the extraction strategy they lead to.
+pub async fn probe_capabilities<T, P>(provider: &P) -> Capabilities
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    *CAPABILITIES
+        .get_or_init(|| async {
+            let capabilities = Capabilities::probe(provider).await;
+            capabilities.log_strategy(TraceBackend::from_env());
+            capabilities
+        })
+        .await
+}
+
+/// Returns the capabilities probed, every method being assumed supported if they weren't.
+pub fn capabilities() -> Capabilities {
+    CAPABILITIES.get().copied().unwrap_or_default()
+}
+
+/// Which of the optional methods the node supports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub block_receipts: bool,
+    pub trace_block: bool,
+    pub debug_trace: bool,
+    pub batch: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            block_receipts: true,
+            trace_block: true,
+            debug_trace: true,
+            batch: true,
+        }
+    }
+}
+
+impl Capabilities {
+    async fn probe<T, P>(provider: &P) -> Self
+    where
+        T: Transport + Clone,
+        P: Provider<T>,
+    {
+        let head = match provider.get_block_number().await {
+            Ok(head) => head,
+            Err(err) => {
+                warn!(
+                    "Failed to retrieve the head to probe the node, assuming it supports every method: {}",
+                    err
+                );
+                return Self::default();
+            }
+        };
+        let block = format!("0x{:x}", head.saturating_sub(PROBE_DEPTH));
+
+        let block_receipts = supports(
+            "eth_getBlockReceipts",
+            provider
+                .raw_request::<_, Value>("eth_getBlockReceipts".into(), (&block,))
+                .await,
+        );
+        let trace_block = supports(
+            "trace_block",
+            provider
+                .raw_request::<_, Value>("trace_block".into(), (&block,))
+                .await,
+        );
+        let (_, tracer) = geth::call_tracer_params(0);
+        let debug_trace = supports(
+            "debug_traceBlockByNumber",
+            provider
+                .raw_request::<_, Value>("debug_traceBlockByNumber".into(), (&block, tracer))
+                .await,
+        );
+
+        Self {
+            block_receipts,
+            trace_block,
+            debug_trace,
+            batch: supports_batch(provider).await,
+        }
+    }
+
+    /// Returns the backend the traces are retrieved with, the configured one unless only the
+    /// other one is supported.
+    pub fn trace_backend(&self, configured: TraceBackend) -> TraceBackend {
+        match configured {
+            TraceBackend::Parity if !self.trace_block && self.debug_trace => TraceBackend::Geth,
+            TraceBackend::Geth if !self.debug_trace && self.trace_block => TraceBackend::Parity,
+            configured => configured,
+        }
+    }
+
+    fn log_strategy(&self, configured: TraceBackend) {
+        info!("Probed the node: {:?}", self);
+
+        if self.block_receipts {
+            info!("Retrieving the receipts with `eth_getBlockReceipts`");
+        } else {
+            warn!("`eth_getBlockReceipts` isn't supported, retrieving the receipts one transaction at a time");
+        }
+
+        match (configured, self.trace_backend(configured)) {
+            (configured, chosen) if configured != chosen => warn!(
+                "The {:?} trace backend isn't supported, retrieving the traces with the {:?} one",
+                configured, chosen
+            ),
+            (_, TraceBackend::Parity) if !self.trace_block => {
+                warn!("Neither `trace_block` nor `debug_traceBlockByNumber` are supported")
+            }
+            (_, TraceBackend::Geth) if !self.debug_trace => {
+                warn!("Neither `debug_traceBlockByNumber` nor `trace_block` are supported")
+            }
+            (_, chosen) => info!("Retrieving the traces with the {:?} trace backend", chosen),
+        }
+
+        if self.batch {
+            info!("Sending the calls of each block as a single batch");
+        } else {
+            warn!("Batches aren't supported, sending the calls of each block one by one");
+        }
+    }
+}
+
+/// Whether the probe shows the method to be supported.  A `null` result means the node doesn't
+/// serve it either (e.g. receipts not indexed).
+fn supports(method: &str, probed: TransportResult<Value>) -> bool {
+    match probed {
+        Ok(Value::Null) => false,
+        Ok(_) => true,
+        Err(err) if is_unsupported(&err) => false,
+        Err(err) => {
+            warn!(
+                "Failed to probe `{}`, assuming it is supported: {}",
+                method, err
+            );
+            true
+        }
+    }
+}
+
+/// Whether the error means the method is unknown to, or disabled on, the node.
+fn is_unsupported<E>(err: &RpcError<E>) -> bool {
+    match err {
+        RpcError::ErrorResp(payload) => {
+            let message = payload.message.to_lowercase();
+            payload.code == METHOD_NOT_FOUND_CODE
+                || UNSUPPORTED_MESSAGES
+                    .iter()
+                    .any(|unsupported| message.contains(unsupported))
+        }
+        _ => false,
+    }
+}
+
+async fn supports_batch<T, P>(provider: &P) -> bool
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let mut batch = BatchRequest::new(provider.client());
+    let calls: TransportResult<Vec<Waiter<Value>>> = (0..2)
+        .map(|_| batch.add_call("eth_blockNumber", &()))
+        .collect();
+    let calls = match calls {
+        Ok(calls) => calls,
+        Err(_) => return false,
+    };
+
+    if let Err(err) = batch.send().await {
+        warn!("Failed to send a batch to the node: {}", err);
+        return false;
+    }
+    for call in calls {
+        if let Err(err) = call.await {
+            warn!("Batched call failed: {}", err);
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::{rpc::json_rpc::ErrorPayload, transports::TransportError};
+
+    #[test]
+    fn falls_back_to_the_supported_methods() {
+        let error_resp = |code: i64, message: &str| {
+            TransportError::ErrorResp(ErrorPayload {
+                code,
+                message: message.to_string(),
+                data: None,
+            })
+        };
+        assert!(!supports(
+            "trace_block",
+            Err(error_resp(
+                -32601,
+                "the method trace_block does not exist/is not available"
+            ))
+        ));
+        assert!(!supports(
+            "eth_getBlockReceipts",
+            Err(error_resp(-32000, "Method not supported"))
+        ));
+        assert!(!supports("eth_getBlockReceipts", Ok(Value::Null)));
+        assert!(supports("trace_block", Ok(Value::Array(Vec::new()))));
+        assert!(supports(
+            "trace_block",
+            Err(error_resp(429, "Too Many Requests"))
+        ));
+
+        let capabilities = Capabilities {
+            trace_block: false,
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            capabilities.trace_backend(TraceBackend::Parity),
+            TraceBackend::Geth
+        );
+        assert_eq!(
+            capabilities.trace_backend(TraceBackend::Geth),
+            TraceBackend::Geth
+        );
+
+        // neither is supported, so the configured one fails as it did before
+        let capabilities = Capabilities {
+            debug_trace: false,
+            ..capabilities
+        };
+        assert_eq!(
+            capabilities.trace_backend(TraceBackend::Parity),
+            TraceBackend::Parity
+        );
+    }
+}
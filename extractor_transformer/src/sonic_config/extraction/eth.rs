@@ -1,112 +1,577 @@
 use std::marker::PhantomData;
 
 use alloy::{
+    eips::BlockNumberOrTag,
     network::Ethereum,
+    primitives::{BlockHash, Bytes},
     providers::Provider,
+    rpc::{
+        client::{BatchRequest, Waiter},
+        types::{
+            trace::parity::TraceResultsWithTransactionHash, Block, Filter, Log, TransactionReceipt,
+        },
+    },
     transports::{Transport, TransportResult},
 };
+use log::warn;
 
 use crate::blockchain_config::proto_codegen::etl::request::IndexingRequest;
+use crate::blockchain_config::transformation::err::TransformationErr;
+use crate::blockchain_config::ExtractTransformErr;
 
 use super::{
+    capabilities::{self, Capabilities},
+    geth::{self, GethTxTrace},
+    receipt_logs,
+    retry::RetryPolicy,
+    state::{self, GethTxStateDiff},
+    tokens::{self, TokenMetadata},
     traits::{EvmDebugExtractor, EvmExtractor, Extractor},
-    EvmExtracted,
+    DebugTraces, EvmExtracted, StateDiffs, TraceBackend, TxTrace,
 };
 
 /// An [Extractor] implementation for EVM compatible chains.  
 pub struct EthExtractor<T: Transport + Clone, P: Provider<T, Ethereum>> {
     provider: P,
     metrics: Option<crate::metrics::Metrics>,
+    trace_backend: TraceBackend,
+    retry_policy: RetryPolicy,
+    /// The optional methods supported by the node, see [capabilities]
+    capabilities: Capabilities,
+    /// Whether the logs read from the receipts are checked against `eth_getLogs`
+    verify_receipt_logs: bool,
     _transport: PhantomData<T>,
 }
 
+/// The hash of the block, failing the block (instead of the process) if the node left it out.
+#[inline]
+fn block_hash(block: &Block) -> Result<BlockHash, ExtractTransformErr> {
+    block
+        .header
+        .hash
+        .ok_or_else(|| TransformationErr::missing("block_hash").into())
+}
+
 impl<T: Transport + Clone, P: Provider<T>> EthExtractor<T, P> {
     pub fn new(provider: P) -> Self {
-        Self {
-            provider,
-            metrics: None,
-            _transport: PhantomData,
-        }
+        Self::new_with_metrics(provider, None)
     }
 
     pub fn new_with_metrics(provider: P, metrics: Option<crate::metrics::Metrics>) -> Self {
+        let capabilities = capabilities::capabilities();
         Self {
             provider,
             metrics,
+            trace_backend: capabilities.trace_backend(TraceBackend::from_env()),
+            retry_policy: RetryPolicy::from_env(),
+            capabilities,
+            verify_receipt_logs: receipt_logs::verify_from_env(),
             _transport: PhantomData,
         }
     }
 
+    /// Returns the receipts of the block, with `eth_getBlockReceipts` if the node supports it, or
+    /// one transaction at a time otherwise.
+    async fn receipts(
+        &self,
+        block_number: u64,
+        block: &Block,
+    ) -> TransportResult<Vec<TransactionReceipt>> {
+        if self.capabilities.block_receipts {
+            self.get_block_receipts(block_number).await
+        } else {
+            let tx_hashes = block.transactions.hashes().collect::<Vec<_>>();
+            self.get_transaction_receipts(block_number, &tx_hashes)
+                .await
+        }
+    }
+
+    /// Returns the logs of the block, read from its receipts if they were retrieved, or with
+    /// `eth_getLogs` otherwise (or as well, to check them, with `VERIFY_RECEIPT_LOGS`).
+    async fn logs(
+        &self,
+        block_number: u64,
+        receipts: Option<&[TransactionReceipt]>,
+    ) -> TransportResult<Vec<Log>> {
+        match receipts {
+            Some(receipts) if self.verify_receipt_logs => Ok(receipt_logs::check_against_fetched(
+                block_number,
+                receipt_logs::logs_from_receipts(receipts),
+                self.get_logs(block_number).await?,
+            )),
+            Some(receipts) => Ok(receipt_logs::logs_from_receipts(receipts)),
+            None => self.get_logs(block_number).await,
+        }
+    }
+
     /// Extracts the basic data
     pub async fn extract_basic(
         &self,
         block_number: u64,
         request: Option<IndexingRequest>,
-        n_retry: usize,
-        cooldown: usize,
-    ) -> TransportResult<Option<EvmExtracted>> {
+    ) -> Result<Option<EvmExtracted>, ExtractTransformErr> {
         let request = request.unwrap_or_default();
 
-        let (block_timestamp, block_hash, block) = {
-            let block = self
-                .get_block_with_retry(block_number, true, n_retry, cooldown)
-                .await?;
-            match block {
-                Some(block) if (request.blocks || request.transactions) => (
-                    block.header.timestamp,
-                    block.header.hash.expect("Received block with no hash"),
-                    Some(block),
-                ),
-                Some(block) => (
-                    block.header.timestamp,
-                    block.header.hash.expect("Received block with no hash"),
-                    None,
-                ),
-                None => return Ok(None),
-            }
+        let block = match self.get_block(block_number, true).await? {
+            Some(block) => block,
+            None => return Ok(None),
         };
 
-        let logs = {
-            if request.logs || request.decoded_events || request.blocks {
-                Some(
-                    self.get_logs_with_retry(block_number, n_retry, cooldown)
-                        .await?,
-                )
+        let receipts = {
+            if request.receipts {
+                Some(self.receipts(block_number, &block).await?)
             } else {
                 None
             }
         };
 
-        let receipts = {
-            if request.receipts {
-                Some(
-                    self.get_block_receipts_with_retry(block_number, n_retry, cooldown)
-                        .await?,
-                )
+        let logs = {
+            if request.logs || request.decoded_events || request.blocks {
+                Some(self.logs(block_number, receipts.as_deref()).await?)
             } else {
                 None
             }
         };
 
+        let (block_timestamp, block_hash, parent_hash) = (
+            block.header.timestamp,
+            block_hash(&block)?,
+            block.header.parent_hash,
+        );
+        let block = (request.blocks || request.transactions).then_some(block);
+
         Ok(Some(EvmExtracted {
             block_number,
             block_hash,
+            parent_hash,
             block_timestamp: block_timestamp as i64,
             receipts,
             logs,
             block,
         }))
     }
+
+    /// Extracts both the basic and the debug data of a block, sending every call needed as a
+    /// single JSON-RPC batch.  Each call keeps its own result, and a call that failed within the
+    /// batch is retried on its own, with the [RetryPolicy].  The state diffs are only extracted
+    /// when requested.  The calls are sent one by one if the node doesn't support batches.
+    pub async fn extract_batched(
+        &self,
+        block_number: u64,
+        request: Option<IndexingRequest>,
+    ) -> Result<Option<(EvmExtracted, DebugTraces, Option<StateDiffs>)>, ExtractTransformErr> {
+        if !self.capabilities.batch {
+            return self.extract_unbatched(block_number, request).await;
+        }
+        let request = request.unwrap_or_default();
+
+        let mut batch = BatchRequest::new(self.provider.client());
+
+        let block_call: Waiter<Option<Block>> = batch.add_call(
+            "eth_getBlockByNumber",
+            &(BlockNumberOrTag::Number(block_number), true),
+        )?;
+
+        // the logs are read from the receipts when they are requested
+        let logs_requested =
+            request.logs || request.decoded_events || request.blocks || request.tokens();
+        let logs_call: Option<Waiter<Vec<Log>>> =
+            if logs_requested && (!request.receipts || self.verify_receipt_logs) {
+                Some(batch.add_call("eth_getLogs", &(Filter::new().select(block_number),))?)
+            } else {
+                None
+            };
+
+        // without `eth_getBlockReceipts`, the receipts are retrieved once the block is
+        let receipts_call: Option<Waiter<Option<Vec<TransactionReceipt>>>> =
+            if request.receipts && self.capabilities.block_receipts {
+                Some(batch.add_call(
+                    "eth_getBlockReceipts",
+                    &(BlockNumberOrTag::Number(block_number),),
+                )?)
+            } else {
+                None
+            };
+
+        // the genesis block cannot be traced
+        let traces_call: Option<Waiter<Vec<TxTrace>>> =
+            if block_number != 0 && self.trace_backend == TraceBackend::Parity {
+                Some(batch.add_call("trace_block", &(format!("0x{:x}", block_number),))?)
+            } else {
+                None
+            };
+
+        let call_frames_call: Option<Waiter<Vec<GethTxTrace>>> =
+            if block_number != 0 && self.trace_backend == TraceBackend::Geth {
+                Some(batch.add_call(
+                    "debug_traceBlockByNumber",
+                    &geth::call_tracer_params(block_number),
+                )?)
+            } else {
+                None
+            };
+
+        // the genesis block has no transactions to replay
+        let replay_state_diffs = block_number != 0 && request.state_diffs();
+
+        let state_diffs_call: Option<Waiter<Vec<TraceResultsWithTransactionHash>>> =
+            if replay_state_diffs && self.trace_backend == TraceBackend::Parity {
+                Some(batch.add_call(
+                    "trace_replayBlockTransactions",
+                    &state::state_diff_params(block_number),
+                )?)
+            } else {
+                None
+            };
+
+        let prestate_diffs_call: Option<Waiter<Vec<GethTxStateDiff>>> =
+            if replay_state_diffs && self.trace_backend == TraceBackend::Geth {
+                Some(batch.add_call(
+                    "debug_traceBlockByNumber",
+                    &state::prestate_tracer_params(block_number),
+                )?)
+            } else {
+                None
+            };
+
+        // If the batch as a whole fails, every call within it resolves to an error, and is then
+        // retried individually below.
+        self.incr_request();
+        if let Err(err) = batch.send().await {
+            self.incr_fails();
+            warn!(
+                "Failed to send the batch for block #{}, falling back to individual calls: {}",
+                block_number, err
+            );
+        }
+
+        let block = match block_call.await {
+            Ok(Some(block)) => block,
+            mr_attempt => {
+                self.incr_fails();
+                warn!(
+                    "Batched call for block #{} failed ({:?}), retrying on its own",
+                    block_number,
+                    mr_attempt.err()
+                );
+                match self.get_block(block_number, true).await? {
+                    Some(block) => block,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let fetched_logs = match logs_call {
+            Some(call) => Some(match call.await {
+                Ok(logs) => logs,
+                Err(err) => {
+                    self.incr_fails();
+                    warn!(
+                        "Batched call for logs of block #{} failed ({}), retrying on its own",
+                        block_number, err
+                    );
+                    self.get_logs(block_number).await?
+                }
+            }),
+            None => None,
+        };
+
+        let receipts = match receipts_call {
+            Some(call) => Some(match call.await {
+                Ok(Some(receipts)) => receipts,
+                mr_attempt => {
+                    self.incr_fails();
+                    warn!(
+                        "Batched call for receipts of block #{} failed ({:?}), retrying on its own",
+                        block_number,
+                        mr_attempt.err()
+                    );
+                    self.get_block_receipts(block_number).await?
+                }
+            }),
+            None if request.receipts => Some(self.receipts(block_number, &block).await?),
+            None => None,
+        };
+
+        let logs = match (receipts.as_deref(), fetched_logs) {
+            (Some(receipts), Some(fetched)) => Some(receipt_logs::check_against_fetched(
+                block_number,
+                receipt_logs::logs_from_receipts(receipts),
+                fetched,
+            )),
+            (Some(receipts), None) if logs_requested => {
+                Some(receipt_logs::logs_from_receipts(receipts))
+            }
+            (_, fetched) => fetched,
+        };
+
+        let traces = match traces_call {
+            Some(call) => match call.await {
+                Ok(traces) => traces,
+                Err(err) => {
+                    self.incr_fails();
+                    warn!(
+                        "Batched call for traces of block #{} failed ({}), retrying on its own",
+                        block_number, err
+                    );
+                    self.get_block_traces(block_number).await?
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let block_hash = block_hash(&block)?;
+
+        let traces = match call_frames_call {
+            Some(call) => {
+                let call_frames = match call.await {
+                    Ok(call_frames) => call_frames,
+                    Err(err) => {
+                        self.incr_fails();
+                        warn!(
+                            "Batched call for call frames of block #{} failed ({}), retrying on its own",
+                            block_number, err
+                        );
+                        self.get_block_call_frames(block_number).await?
+                    }
+                };
+                geth::flatten_block_traces(
+                    block_number,
+                    block_hash,
+                    block.transactions.as_transactions().unwrap_or_default(),
+                    call_frames,
+                )?
+            }
+            None => traces,
+        };
+        let block_timestamp = block.header.timestamp as i64;
+
+        let state_diffs = match (state_diffs_call, prestate_diffs_call) {
+            (Some(call), _) => {
+                let replayed = match call.await {
+                    Ok(replayed) => replayed,
+                    Err(err) => {
+                        self.incr_fails();
+                        warn!(
+                            "Batched call for state diffs of block #{} failed ({}), retrying on its own",
+                            block_number, err
+                        );
+                        self.get_block_state_diffs(block_number).await?
+                    }
+                };
+                Some(state::from_replayed_transactions(
+                    block_number,
+                    block_hash,
+                    block_timestamp,
+                    replayed,
+                ))
+            }
+            (None, Some(call)) => {
+                let prestate_diffs = match call.await {
+                    Ok(prestate_diffs) => prestate_diffs,
+                    Err(err) => {
+                        self.incr_fails();
+                        warn!(
+                            "Batched call for prestate diffs of block #{} failed ({}), retrying on its own",
+                            block_number, err
+                        );
+                        self.get_block_prestate_diffs(block_number).await?
+                    }
+                };
+                Some(state::from_prestate_diffs(
+                    block_number,
+                    block_hash,
+                    block_timestamp,
+                    &block.transactions.hashes().collect::<Vec<_>>(),
+                    prestate_diffs,
+                )?)
+            }
+            (None, None) if request.state_diffs() => Some(StateDiffs::new(
+                block_number,
+                block_hash,
+                block_timestamp,
+                Vec::new(),
+            )),
+            (None, None) => None,
+        };
+
+        let basic = EvmExtracted {
+            block_number,
+            block_hash,
+            parent_hash: block.header.parent_hash,
+            block_timestamp,
+            receipts,
+            logs,
+            block: (request.blocks || request.transactions).then_some(block),
+        };
+        let debug = DebugTraces::new(block_number, Some(block_hash), block_timestamp, traces);
+
+        Ok(Some((basic, debug, state_diffs)))
+    }
+
+    /// [EthExtractor::extract_batched], sending the calls one by one.
+    async fn extract_unbatched(
+        &self,
+        block_number: u64,
+        request: Option<IndexingRequest>,
+    ) -> Result<Option<(EvmExtracted, DebugTraces, Option<StateDiffs>)>, ExtractTransformErr> {
+        let request = request.unwrap_or_default();
+
+        let block = match self.get_block(block_number, true).await? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        let receipts = if request.receipts {
+            Some(self.receipts(block_number, &block).await?)
+        } else {
+            None
+        };
+
+        let logs = if request.logs || request.decoded_events || request.blocks || request.tokens() {
+            Some(self.logs(block_number, receipts.as_deref()).await?)
+        } else {
+            None
+        };
+
+        let block_hash = block_hash(&block)?;
+        let block_timestamp = block.header.timestamp as i64;
+
+        // the genesis block cannot be traced
+        let traces = match self.trace_backend {
+            _ if block_number == 0 => Vec::new(),
+            TraceBackend::Parity => self.get_block_traces(block_number).await?,
+            TraceBackend::Geth => geth::flatten_block_traces(
+                block_number,
+                block_hash,
+                block.transactions.as_transactions().unwrap_or_default(),
+                self.get_block_call_frames(block_number).await?,
+            )?,
+        };
+
+        // the genesis block has no transactions to replay
+        let state_diffs = match self.trace_backend {
+            _ if !request.state_diffs() => None,
+            _ if block_number == 0 => Some(StateDiffs::new(
+                block_number,
+                block_hash,
+                block_timestamp,
+                Vec::new(),
+            )),
+            TraceBackend::Parity => Some(state::from_replayed_transactions(
+                block_number,
+                block_hash,
+                block_timestamp,
+                self.get_block_state_diffs(block_number).await?,
+            )),
+            TraceBackend::Geth => Some(state::from_prestate_diffs(
+                block_number,
+                block_hash,
+                block_timestamp,
+                &block.transactions.hashes().collect::<Vec<_>>(),
+                self.get_block_prestate_diffs(block_number).await?,
+            )?),
+        };
+
+        let basic = EvmExtracted {
+            block_number,
+            block_hash,
+            parent_hash: block.header.parent_hash,
+            block_timestamp,
+            receipts,
+            logs,
+            block: (request.blocks || request.transactions).then_some(block),
+        };
+        let debug = DebugTraces::new(block_number, Some(block_hash), block_timestamp, traces);
+
+        Ok(Some((basic, debug, state_diffs)))
+    }
+
+    /// Retrieves the metadata of the tokens emitting `Transfer` events within the logs, which
+    /// weren't published before by this process.  The calls are sent as a single JSON-RPC batch, at the
+    /// block of the logs.
+    pub async fn extract_tokens(
+        &self,
+        block_number: u64,
+        logs: &[Log],
+    ) -> TransportResult<Vec<TokenMetadata>> {
+        let new_tokens = tokens::new_tokens(logs);
+        if new_tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // the waiters are consumed by the batch, which is thus built again on every attempt
+        let new_tokens = &new_tokens;
+        let token_calls = self
+            .with_retry("eth_call", block_number, || async move {
+                let mut batch = BatchRequest::new(self.provider.client());
+
+                let mut token_calls = Vec::with_capacity(new_tokens.len());
+                for token in new_tokens.iter() {
+                    let mut calls: Vec<Waiter<Bytes>> = Vec::with_capacity(4);
+                    for selector in [
+                        tokens::NAME_SELECTOR,
+                        tokens::SYMBOL_SELECTOR,
+                        tokens::DECIMALS_SELECTOR,
+                        tokens::TOTAL_SUPPLY_SELECTOR,
+                    ] {
+                        calls.push(batch.add_call(
+                            "eth_call",
+                            &tokens::call_params(*token, selector, block_number),
+                        )?);
+                    }
+                    token_calls.push((*token, calls));
+                }
+
+                batch.send().await?;
+                Ok(token_calls)
+            })
+            .await;
+        let token_calls = token_calls?;
+
+        let mut metadata = Vec::with_capacity(token_calls.len());
+        'tokens: for (token, calls) in token_calls {
+            let mut results = Vec::with_capacity(calls.len());
+            for call in calls {
+                match call.await {
+                    Ok(data) => results.push(Some(data)),
+                    // the token doesn't implement the function
+                    Err(err) if tokens::is_call_error(&err) => results.push(None),
+                    // the token will be queried again the next time it is seen
+                    Err(err) => {
+                        self.incr_fails();
+                        warn!(
+                            "Failed to call token {} at block #{}: {}",
+                            token, block_number, err
+                        );
+                        continue 'tokens;
+                    }
+                }
+            }
+
+            let mut results = results.into_iter();
+            metadata.push(TokenMetadata::decode(
+                token,
+                results.next().flatten(),
+                results.next().flatten(),
+                results.next().flatten(),
+                results.next().flatten(),
+            ));
+        }
+
+        Ok(metadata)
+    }
 }
 
 impl<T: Transport + Clone, P: Provider<T>> Extractor<P, T> for EthExtractor<T, P> {
     fn provider(&self) -> &P {
         &self.provider
     }
-}
 
-// Impl the EvmExtractor to allow calls for all basic data available.
-impl<T: Transport + Clone, P: Provider<T>> EvmExtractor<P, T> for EthExtractor<T, P> {
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
     fn incr_fails(&self) {
         #[cfg(feature = "METRICS")]
         if let Some(metrics) = &self.metrics {
@@ -121,5 +586,15 @@ impl<T: Transport + Clone, P: Provider<T>> EvmExtractor<P, T> for EthExtractor<T
         }
     }
 }
-// Impl the EvmDebugExtractor to allow trace calls (`trace_transaction`, `trace_block`)
-impl<T: Transport + Clone, P: Provider<T>> EvmDebugExtractor<P, T> for EthExtractor<T, P> {}
+
+// Impl the EvmExtractor to allow calls for all basic data available.
+impl<T: Transport + Clone, P: Provider<T>> EvmExtractor<P, T> for EthExtractor<T, P> {}
+
+// Impl the EvmDebugExtractor to allow trace calls (`trace_transaction`, `trace_block`, or
+// `debug_traceBlockByNumber`)
+impl<T: Transport + Clone, P: Provider<T>> EvmDebugExtractor<P, T> for EthExtractor<T, P> {
+    #[inline]
+    fn trace_backend(&self) -> TraceBackend {
+        self.trace_backend
+    }
+}
@@ -0,0 +1,478 @@
+//! Support for the geth-style `debug_traceBlockByNumber` RPC with the `callTracer`, as an
+//! alternative to the Parity-style `trace_block`.  The nested call frames returned are flattened
+//! into the same [TxTrace]s `trace_block` would have returned, so that both trace backends
+//! produce the same `traces` records.
+//!
+//! The one exception is the gas of the top level trace of each transaction (`action.gas` and
+//! `result.gas_used`), which is left out: the `callTracer` includes the intrinsic gas of the
+//! transaction and deducts its refund, while Parity does neither, and neither can be told exactly
+//! from the frame.  The gas of the nested traces is reported by the EVM itself, and is the same
+//! with both backends.
+
+use std::env::{self, VarError};
+
+use alloy::{
+    primitives::{Address, BlockHash, Bytes, TxHash, U256},
+    rpc::types::Transaction,
+    transports::{TransportErrorKind, TransportResult},
+};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    TxTrace, TxTraceActionCall, TxTraceActionCreate, TxTraceActionSuicide, TxTraceCall,
+    TxTraceCreate, TxTraceEmpty, TxTraceResult, TxTraceResultCreate, TxTraceResultEmpty,
+    TxTraceSuicide,
+};
+
+pub const TRACE_BACKEND_ENVKEY: &str = "TRACE_BACKEND";
+
+/// The RPC used to retrieve the traces of a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TraceBackend {
+    /// Parity-style `trace_block`
+    #[default]
+    Parity,
+    /// geth-style `debug_traceBlockByNumber` with the `callTracer`
+    Geth,
+}
+
+impl TraceBackend {
+    /// Reads the backend from the `TRACE_BACKEND` envkey (`parity` or `geth`), defaulting to
+    /// [TraceBackend::Parity].
+    pub fn from_env() -> Self {
+        match env::var(TRACE_BACKEND_ENVKEY) {
+            Ok(backend) => match backend.to_lowercase().as_str() {
+                "parity" | "trace" => Self::Parity,
+                "geth" | "debug" => Self::Geth,
+                other => {
+                    error!(
+                        "Unknown trace backend `{}` in envkey `{}`, (fallback to parity)",
+                        other, TRACE_BACKEND_ENVKEY
+                    );
+                    Self::Parity
+                }
+            },
+            Err(VarError::NotPresent) => Self::Parity,
+            Err(VarError::NotUnicode(badstr)) => {
+                error!(
+                    "Failed to decode env variable `{}`, (fallback to parity): {:?}",
+                    TRACE_BACKEND_ENVKEY, badstr
+                );
+                Self::Parity
+            }
+        }
+    }
+}
+
+/// The parameters of `debug_traceBlockByNumber` selecting the `callTracer`.
+pub fn call_tracer_params(block_number: u64) -> (String, serde_json::Value) {
+    (
+        format!("0x{:x}", block_number),
+        serde_json::json!({ "tracer": "callTracer" }),
+    )
+}
+
+/// A call frame, as returned by the `callTracer`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallFrame {
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub from: Address,
+    #[serde(default)]
+    pub to: Option<Address>,
+    #[serde(default)]
+    pub value: Option<U256>,
+    #[serde(default)]
+    pub gas: U256,
+    #[serde(default)]
+    pub gas_used: U256,
+    #[serde(default)]
+    pub input: Bytes,
+    #[serde(default)]
+    pub output: Option<Bytes>,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub calls: Vec<CallFrame>,
+}
+
+/// The trace of a single transaction, as returned by `debug_traceBlockByNumber`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GethTxTrace {
+    /// Only returned by the more recent nodes, the position in the block is used otherwise.
+    #[serde(default)]
+    pub tx_hash: Option<TxHash>,
+    #[serde(default)]
+    pub result: Option<CallFrame>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Maps a geth error message to the message Parity gives for the same failure.
+pub fn normalize_error(error: &str) -> String {
+    match error {
+        "execution reverted" => "Reverted".to_string(),
+        "out of gas" => "Out of gas".to_string(),
+        "invalid jump destination" => "Bad jump destination".to_string(),
+        "write protection" => "Mutable Call In Static Context".to_string(),
+        "stack limit reached 1024 (1023)" | "max call depth exceeded" => "Out of stack".to_string(),
+        _ if error.starts_with("invalid opcode") => "Bad instruction".to_string(),
+        _ if error.starts_with("stack underflow") => "Stack underflow".to_string(),
+        _ if error.starts_with("stack overflow") => "Out of stack".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// The trace Parity returns for a block without any transaction.
+fn empty_block_trace(block_number: u64, block_hash: BlockHash) -> TxTrace {
+    TxTrace::Empty(TxTraceEmpty {
+        block_hash,
+        block_number,
+        action: TxTraceActionCreate {
+            from: Address::ZERO,
+            value: 0,
+            gas: Some(0),
+            init: "0x".to_string(),
+        },
+        result: Some(TxTraceResultEmpty { gas_used: 0 }),
+        error: Some("Empty block".to_string()),
+        subtraces: 0,
+        trace_address: Vec::new(),
+        transaction_position: 0,
+        transaction_hash: TxHash::ZERO,
+    })
+}
+
+/// Flattens the call frames of every transaction of a block into [TxTrace]s, depth first, in the
+/// same order and shape as `trace_block`.  The `transactions` of the block are required to
+/// resolve the transaction of each trace.
+pub fn flatten_block_traces(
+    block_number: u64,
+    block_hash: BlockHash,
+    transactions: &[Transaction],
+    tx_traces: Vec<GethTxTrace>,
+) -> TransportResult<Vec<TxTrace>> {
+    if transactions.is_empty() {
+        return Ok(vec![empty_block_trace(block_number, block_hash)]);
+    }
+
+    if tx_traces.len() != transactions.len() {
+        return Err(TransportErrorKind::custom_str(&format!(
+            "Received {} transaction traces for the {} transactions of block #{}",
+            tx_traces.len(),
+            transactions.len(),
+            block_number
+        )));
+    }
+
+    let mut traces = Vec::new();
+
+    for (position, (tx_trace, tx)) in tx_traces.into_iter().zip(transactions).enumerate() {
+        let transaction_hash = tx_trace.tx_hash.unwrap_or(tx.hash);
+        if transaction_hash != tx.hash {
+            return Err(TransportErrorKind::custom_str(&format!(
+                "Transaction trace #{} of block #{} is for {} instead of {}",
+                position, block_number, transaction_hash, tx.hash
+            )));
+        }
+
+        let frame = match tx_trace.result {
+            Some(frame) => frame,
+            None => {
+                return Err(TransportErrorKind::custom_str(&format!(
+                    "Failed to trace transaction {} of block #{}: {}",
+                    transaction_hash,
+                    block_number,
+                    tx_trace.error.unwrap_or_default()
+                )))
+            }
+        };
+
+        let tx_info = TxInfo {
+            block_number,
+            block_hash,
+            transaction_hash,
+            transaction_position: position as u64,
+        };
+        flatten_frame(frame, Vec::new(), &tx_info, &mut traces)?;
+    }
+
+    Ok(traces)
+}
+
+/// What every trace of a transaction shares.
+struct TxInfo {
+    block_number: u64,
+    block_hash: BlockHash,
+    transaction_hash: TxHash,
+    transaction_position: u64,
+}
+
+/// Converts an amount of the frame, failing if it doesn't fit in the 128 bits of the traces.
+fn frame_amount(amount: U256, name: &str, tx_info: &TxInfo) -> TransportResult<u128> {
+    amount.try_into().map_err(|_| {
+        TransportErrorKind::custom_str(&format!(
+            "The {} of a trace of transaction {} of block #{} doesn't fit in 128 bits: {}",
+            name, tx_info.transaction_hash, tx_info.block_number, amount
+        ))
+    })
+}
+
+/// Pushes the trace of the frame, then the traces of its subcalls (pre-order).  The gas of the
+/// top level frame is left out, as it doesn't match the one of `trace_block`.
+fn flatten_frame(
+    frame: CallFrame,
+    trace_address: Vec<u64>,
+    tx_info: &TxInfo,
+    traces: &mut Vec<TxTrace>,
+) -> TransportResult<()> {
+    let CallFrame {
+        call_type,
+        from,
+        to,
+        value,
+        gas,
+        gas_used,
+        input,
+        output,
+        error,
+        calls,
+    } = frame;
+
+    let subtraces = calls.len() as u64;
+    let value = value.unwrap_or_default();
+    let (gas, gas_used) = match trace_address.is_empty() {
+        true => (None, None),
+        false => (
+            Some(frame_amount(gas, "gas", tx_info)?),
+            Some(frame_amount(gas_used, "gas used", tx_info)?),
+        ),
+    };
+    let output = output.unwrap_or_default();
+    // Parity leaves out the result of any failed trace
+    let failed = error.is_some();
+    let error = error.as_deref().map(normalize_error);
+
+    let trace = match call_type.to_uppercase().as_str() {
+        "CREATE" | "CREATE2" => TxTrace::Create(TxTraceCreate {
+            block_hash: tx_info.block_hash,
+            block_number: tx_info.block_number,
+            action: TxTraceActionCreate {
+                from,
+                value: frame_amount(value, "value", tx_info)?,
+                gas,
+                init: input.to_string(),
+            },
+            result: (!failed).then(|| TxTraceResultCreate {
+                gas_used,
+                address: to.unwrap_or_default(),
+                code: output.to_string(),
+            }),
+            error,
+            subtraces,
+            trace_address: trace_address.clone(),
+            transaction_position: tx_info.transaction_position,
+            transaction_hash: tx_info.transaction_hash,
+        }),
+        "SELFDESTRUCT" | "SUICIDE" => TxTrace::Suicide(TxTraceSuicide {
+            action: TxTraceActionSuicide {
+                refund_address: to,
+                balance: frame_amount(value, "value", tx_info)?,
+                self_destructed_address: from,
+            },
+            block_hash: tx_info.block_hash,
+            block_number: tx_info.block_number,
+            error,
+            subtraces,
+            trace_address: trace_address.clone(),
+            transaction_hash: tx_info.transaction_hash,
+            transaction_position: tx_info.transaction_position,
+        }),
+        _ => TxTrace::Call(TxTraceCall {
+            action: TxTraceActionCall {
+                from,
+                call_type: call_type.to_lowercase(),
+                gas,
+                input: input.to_string(),
+                to: to.unwrap_or_default(),
+                value: format!("{:#x}", value),
+            },
+            block_hash: tx_info.block_hash,
+            block_number: tx_info.block_number,
+            error,
+            result: (!failed).then(|| TxTraceResult {
+                gas_used,
+                output: output.to_string(),
+            }),
+            subtraces,
+            trace_address: trace_address.clone(),
+            transaction_hash: tx_info.transaction_hash,
+            transaction_position: tx_info.transaction_position,
+        }),
+    };
+    traces.push(trace);
+
+    for (i, call) in calls.into_iter().enumerate() {
+        let mut sub_address = trace_address.clone();
+        sub_address.push(i as u64);
+        flatten_frame(call, sub_address, tx_info, traces)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_nested_frames_like_trace_block() {
+        let tx: Transaction = serde_json::from_value(serde_json::json!({
+            "hash": "0x72997ce3a6008662b70614d9fa0b77a1bd1e6e59da3bd3b16c68c0a8aa0a2e11",
+            "nonce": "0x0",
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionIndex": "0x0",
+            "from": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+            "to": "0x039e2fb66102314ce7b64ce5ce3e5183bc94ad38",
+            "value": "0x0",
+            "gasPrice": "0x1",
+            "gas": "0x30d40",
+            "input": "0x0100",
+            "type": "0x0",
+            "v": "0x1b",
+            "r": "0x1",
+            "s": "0x1"
+        }))
+        .expect("valid transaction");
+
+        let traces: Vec<GethTxTrace> = serde_json::from_value(serde_json::json!([{
+            "result": {
+                "type": "CALL",
+                "from": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+                "to": "0x039e2fb66102314ce7b64ce5ce3e5183bc94ad38",
+                "value": "0x0",
+                "gas": "0x30d40",
+                "gasUsed": "0x61a8",
+                "input": "0x0100",
+                "error": "execution reverted",
+                "calls": [
+                    {
+                        "type": "STATICCALL",
+                        "from": "0x039e2fb66102314ce7b64ce5ce3e5183bc94ad38",
+                        "to": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+                        "gas": "0x100",
+                        "gasUsed": "0x10",
+                        "input": "0x",
+                        "output": "0x01"
+                    },
+                    {
+                        "type": "CREATE2",
+                        "from": "0x039e2fb66102314ce7b64ce5ce3e5183bc94ad38",
+                        "to": "0xa1d376fa5692ab49dd18a23ec323ac593d2a109d",
+                        "value": "0x5",
+                        "gas": "0x200",
+                        "gasUsed": "0x20",
+                        "input": "0x6000",
+                        "output": "0x00",
+                        "calls": [{
+                            "type": "SELFDESTRUCT",
+                            "from": "0xa1d376fa5692ab49dd18a23ec323ac593d2a109d",
+                            "to": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+                            "value": "0x5",
+                            "gas": "0x0",
+                            "gasUsed": "0x0",
+                            "input": "0x"
+                        }]
+                    }
+                ]
+            }
+        }]))
+        .expect("valid traces");
+
+        let flat = flatten_block_traces(7, BlockHash::ZERO, &[tx], traces).expect("traces flatten");
+
+        assert_eq!(flat.len(), 4);
+
+        let top = flat[0].clone().unwrap_call();
+        assert_eq!(top.trace_address, Vec::<u64>::new());
+        assert_eq!(top.subtraces, 2);
+        assert_eq!(top.error.as_deref(), Some("Reverted"));
+        assert!(top.result.is_none());
+        // left out, as the `callTracer` includes the intrinsic gas
+        assert_eq!(top.action.gas, None);
+        assert_eq!(top.action.value, "0x0");
+
+        let staticcall = flat[1].clone().unwrap_call();
+        assert_eq!(staticcall.trace_address, vec![0]);
+        assert_eq!(staticcall.action.call_type, "staticcall");
+        assert_eq!(staticcall.action.gas, Some(0x100));
+        assert_eq!(
+            staticcall.result.map(|r| r.output),
+            Some("0x01".to_string())
+        );
+
+        match &flat[2] {
+            TxTrace::Create(create) => {
+                assert_eq!(create.subtraces, 1);
+                assert_eq!(create.action.value, 5);
+                assert_eq!(create.action.init, "0x6000");
+            }
+            other => panic!("Expected a create trace, got {:?}", other),
+        }
+
+        match &flat[3] {
+            TxTrace::Suicide(suicide) => assert_eq!(suicide.trace_address, vec![1, 0]),
+            other => panic!("Expected a suicide trace, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_values_fail_instead_of_saturating() {
+        let tx: Transaction = serde_json::from_value(serde_json::json!({
+            "hash": "0x72997ce3a6008662b70614d9fa0b77a1bd1e6e59da3bd3b16c68c0a8aa0a2e11",
+            "nonce": "0x0",
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionIndex": "0x0",
+            "from": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+            "to": null,
+            "value": "0x0",
+            "gasPrice": "0x1",
+            "gas": "0x30d40",
+            "input": "0x6000",
+            "type": "0x0",
+            "v": "0x1b",
+            "r": "0x1",
+            "s": "0x1"
+        }))
+        .expect("valid transaction");
+
+        let traces: Vec<GethTxTrace> = serde_json::from_value(serde_json::json!([{
+            "result": {
+                "type": "CREATE",
+                "from": "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0",
+                "to": "0xa1d376fa5692ab49dd18a23ec323ac593d2a109d",
+                "value": format!("0x1{:032x}", 0),
+                "gas": "0x30d40",
+                "gasUsed": "0x61a8",
+                "input": "0x6000"
+            }
+        }]))
+        .expect("valid traces");
+
+        assert!(flatten_block_traces(7, BlockHash::ZERO, &[tx], traces).is_err());
+    }
+
+    #[test]
+    fn empty_block_gives_an_empty_trace() {
+        let flat = flatten_block_traces(503, BlockHash::ZERO, &[], Vec::new()).unwrap();
+        assert_eq!(flat.len(), 1);
+        match &flat[0] {
+            TxTrace::Empty(empty) => assert_eq!(empty.error.as_deref(), Some("Empty block")),
+            other => panic!("Expected an empty trace, got {:?}", other),
+        }
+    }
+}
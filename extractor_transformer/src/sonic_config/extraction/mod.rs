@@ -1,5 +1,15 @@
+mod capabilities;
 mod eth;
+mod geth;
+mod receipt_logs;
+mod retry;
+mod state;
+mod tokens;
 mod traits;
 
+pub use capabilities::probe_capabilities;
 pub use eth::EthExtractor;
+pub use geth::TraceBackend;
+pub use state::StateDiffs;
+pub use tokens::{is_seen as is_seen_token, mark_seen as mark_tokens_seen, TokenMetadata};
 pub use traits::*;
@@ -0,0 +1,140 @@
+//! The logs of a block are also within the receipts of its transactions, so whenever the receipts
+//! are retrieved, the logs are read from them rather than downloaded again with `eth_getLogs`.
+//!
+//! Setting `VERIFY_RECEIPT_LOGS` still retrieves the logs with `eth_getLogs`, and checks them
+//! against the ones read from the receipts, e.g. to validate a new node.
+
+use std::env::{self, VarError};
+
+use alloy::rpc::types::{Log, TransactionReceipt};
+use log::error;
+
+pub const VERIFY_RECEIPT_LOGS_ENVKEY: &str = "VERIFY_RECEIPT_LOGS";
+
+/// Whether the logs read from the receipts are checked against `eth_getLogs`.
+pub fn verify_from_env() -> bool {
+    match env::var(VERIFY_RECEIPT_LOGS_ENVKEY) {
+        Ok(verify) => match verify.to_lowercase().as_str() {
+            "1" | "true" | "yes" => true,
+            "" | "0" | "false" | "no" => false,
+            other => {
+                error!(
+                    "Invalid flag `{}` in envkey `{}`, (fallback to false)",
+                    other, VERIFY_RECEIPT_LOGS_ENVKEY
+                );
+                false
+            }
+        },
+        Err(VarError::NotPresent) => false,
+        Err(VarError::NotUnicode(badstr)) => {
+            error!(
+                "Failed to decode env variable `{}`, (fallback to false): {:?}",
+                VERIFY_RECEIPT_LOGS_ENVKEY, badstr
+            );
+            false
+        }
+    }
+}
+
+/// Returns the logs of the transactions, in the order `eth_getLogs` returns them.
+pub fn logs_from_receipts(receipts: &[TransactionReceipt]) -> Vec<Log> {
+    receipts
+        .iter()
+        .flat_map(|receipt| receipt.inner.logs().iter().cloned())
+        .collect()
+}
+
+/// Checks the logs read from the receipts against the ones retrieved with `eth_getLogs`.  On a
+/// mismatch, which is logged, the latter are returned.
+pub fn check_against_fetched(
+    block_number: u64,
+    from_receipts: Vec<Log>,
+    fetched: Vec<Log>,
+) -> Vec<Log> {
+    let mismatch = from_receipts
+        .iter()
+        .zip(fetched.iter())
+        .position(|(from_receipt, fetched)| !same_log(from_receipt, fetched));
+
+    match mismatch {
+        None if from_receipts.len() == fetched.len() => from_receipts,
+        mismatch => {
+            error!(
+                "The {} logs of the receipts of block #{} don't match the {} logs of `eth_getLogs` (first mismatch at #{}), keeping the latter",
+                from_receipts.len(),
+                block_number,
+                fetched.len(),
+                mismatch.unwrap_or(from_receipts.len().min(fetched.len()))
+            );
+            fetched
+        }
+    }
+}
+
+/// Whether both are the same log, regardless of the fields only some nodes fill in
+/// (e.g. `blockTimestamp`).
+fn same_log(a: &Log, b: &Log) -> bool {
+    a.inner == b.inner
+        && a.block_hash == b.block_hash
+        && a.block_number == b.block_number
+        && a.transaction_hash == b.transaction_hash
+        && a.transaction_index == b.transaction_index
+        && a.log_index == b.log_index
+        && a.removed == b.removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_logs_from_the_receipts() {
+        let log = |index: u64, data: &str| {
+            serde_json::json!({
+                "address": "0x00000000000000000000000000000000000000aa",
+                "topics": [],
+                "data": data,
+                "blockHash": "0x1111111111111111111111111111111111111111111111111111111111111111",
+                "blockNumber": "0x10",
+                "transactionHash": "0x2222222222222222222222222222222222222222222222222222222222222222",
+                "transactionIndex": "0x0",
+                "logIndex": format!("0x{:x}", index),
+                "removed": false
+            })
+        };
+        let receipt: TransactionReceipt = serde_json::from_value(serde_json::json!({
+            "type": "0x2",
+            "status": "0x1",
+            "cumulativeGasUsed": "0x5208",
+            "logs": [log(0, "0x01"), log(1, "0x02")],
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "transactionHash": "0x2222222222222222222222222222222222222222222222222222222222222222",
+            "transactionIndex": "0x0",
+            "blockHash": "0x1111111111111111111111111111111111111111111111111111111111111111",
+            "blockNumber": "0x10",
+            "gasUsed": "0x5208",
+            "effectiveGasPrice": "0x1",
+            "from": "0x00000000000000000000000000000000000000bb",
+            "to": "0x00000000000000000000000000000000000000aa",
+            "contractAddress": null
+        }))
+        .unwrap();
+
+        let logs = logs_from_receipts(&[receipt]);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[1].log_index, Some(1));
+
+        let fetched: Vec<Log> =
+            serde_json::from_value(serde_json::json!([log(0, "0x01"), log(1, "0x02")])).unwrap();
+        assert_eq!(
+            check_against_fetched(16, logs.clone(), fetched.clone()),
+            logs
+        );
+
+        // a log missing from the receipts
+        assert_eq!(
+            check_against_fetched(16, logs[..1].to_vec(), fetched.clone()),
+            fetched
+        );
+    }
+}
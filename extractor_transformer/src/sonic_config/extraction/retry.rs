@@ -0,0 +1,285 @@
+//! The retry policy applied to every call made to the node.
+//!
+//! A failed call is retried with an exponential backoff (with jitter) if its error is transient,
+//! i.e. the node or the network failed rather than the request itself: rate limits, timeouts,
+//! server errors, or an unreachable node are retried, while invalid params, unknown methods, or
+//! reverted calls fail at once.  The number of retries can be overridden per RPC method.
+//!
+//! A block (or receipt) the node doesn't have yet isn't a failure of the node: it is retried
+//! separately, as many times as `EXTRACTION_NOT_FOUND_N_RETRY`, and doesn't count towards the
+//! circuit breaker.
+//!
+//! When too many calls fail in a row, the node is considered down and a circuit breaker opens:
+//! calls then fail at once (instead of each backing off on its own) until the breaker cools down
+//! and lets calls through again.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use alloy::transports::{RpcError, TransportError, TransportErrorKind};
+use backon::ExponentialBuilder;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+
+use crate::blockchain_config::{count_from_env, method_overrides_from_env};
+
+pub const EXTRACT_N_RETRY_ENVKEY: &str = "EXTRACTION_N_RETRY";
+pub const EXTRACT_RETRY_COOLDOWN_ENVKEY: &str = "EXTRACTION_RETRY_COOLDOWN";
+pub const EXTRACT_RETRY_MAX_COOLDOWN_ENVKEY: &str = "EXTRACTION_RETRY_MAX_COOLDOWN";
+pub const EXTRACT_RETRY_OVERRIDES_ENVKEY: &str = "EXTRACTION_RETRY_OVERRIDES";
+pub const EXTRACT_NOT_FOUND_N_RETRY_ENVKEY: &str = "EXTRACTION_NOT_FOUND_N_RETRY";
+pub const EXTRACT_BREAKER_THRESHOLD_ENVKEY: &str = "EXTRACTION_BREAKER_THRESHOLD";
+pub const EXTRACT_BREAKER_COOLDOWN_ENVKEY: &str = "EXTRACTION_BREAKER_COOLDOWN";
+
+/// The number of retries of a failed call when `EXTRACTION_N_RETRY` is not set
+pub const DEFAULT_EXTRACT_N_RETRY: u64 = 5;
+/// The number of seconds before the first retry when `EXTRACTION_RETRY_COOLDOWN` is not set
+pub const DEFAULT_EXTRACT_RETRY_COOLDOWN: u64 = 1;
+/// The maximum number of seconds between two retries when `EXTRACTION_RETRY_MAX_COOLDOWN` is not
+/// set
+pub const DEFAULT_EXTRACT_RETRY_MAX_COOLDOWN: u64 = 30;
+/// The number of retries of a call returning nothing when `EXTRACTION_NOT_FOUND_N_RETRY` is not
+/// set
+pub const DEFAULT_EXTRACT_NOT_FOUND_N_RETRY: u64 = 5;
+/// The number of consecutive failures opening the breaker when `EXTRACTION_BREAKER_THRESHOLD` is
+/// not set
+pub const DEFAULT_EXTRACT_BREAKER_THRESHOLD: u64 = 20;
+/// The number of seconds the breaker stays open when `EXTRACTION_BREAKER_COOLDOWN` is not set
+pub const DEFAULT_EXTRACT_BREAKER_COOLDOWN: u64 = 30;
+
+/// JSON-RPC error codes of requests which are wrong in themselves (parse error, invalid request,
+/// method not found, invalid params), and would fail again
+const INVALID_REQUEST_CODES: [i64; 4] = [-32700, -32600, -32601, -32602];
+
+/// The breaker shared by every call of this process
+static BREAKER: Lazy<Mutex<CircuitBreaker>> = Lazy::new(|| {
+    Mutex::new(CircuitBreaker::new(
+        count_from_env(
+            EXTRACT_BREAKER_THRESHOLD_ENVKEY,
+            DEFAULT_EXTRACT_BREAKER_THRESHOLD,
+        ),
+        Duration::from_secs(count_from_env(
+            EXTRACT_BREAKER_COOLDOWN_ENVKEY,
+            DEFAULT_EXTRACT_BREAKER_COOLDOWN,
+        )),
+    ))
+});
+
+#[inline]
+pub fn breaker() -> MutexGuard<'static, CircuitBreaker> {
+    // the breaker stays consistent even if a holder panicked
+    BREAKER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// How the calls to the node are retried, read from the environment.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub n_retry: usize,
+    pub cooldown: Duration,
+    pub max_cooldown: Duration,
+    /// The number of retries of specific RPC methods, overriding `n_retry`
+    pub overrides: HashMap<String, usize>,
+    /// The number of retries of a call returning nothing, e.g. a block the node doesn't have yet
+    pub not_found_n_retry: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            n_retry: DEFAULT_EXTRACT_N_RETRY as usize,
+            cooldown: Duration::from_secs(DEFAULT_EXTRACT_RETRY_COOLDOWN),
+            max_cooldown: Duration::from_secs(DEFAULT_EXTRACT_RETRY_MAX_COOLDOWN),
+            overrides: HashMap::new(),
+            not_found_n_retry: DEFAULT_EXTRACT_NOT_FOUND_N_RETRY as usize,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            n_retry: count_from_env(EXTRACT_N_RETRY_ENVKEY, DEFAULT_EXTRACT_N_RETRY) as usize,
+            cooldown: Duration::from_secs(count_from_env(
+                EXTRACT_RETRY_COOLDOWN_ENVKEY,
+                DEFAULT_EXTRACT_RETRY_COOLDOWN,
+            )),
+            max_cooldown: Duration::from_secs(count_from_env(
+                EXTRACT_RETRY_MAX_COOLDOWN_ENVKEY,
+                DEFAULT_EXTRACT_RETRY_MAX_COOLDOWN,
+            )),
+            overrides: method_overrides_from_env(EXTRACT_RETRY_OVERRIDES_ENVKEY),
+            not_found_n_retry: count_from_env(
+                EXTRACT_NOT_FOUND_N_RETRY_ENVKEY,
+                DEFAULT_EXTRACT_NOT_FOUND_N_RETRY,
+            ) as usize,
+        }
+    }
+
+    /// Returns the backoff the calls to the RPC method are retried with.
+    pub fn backoff(&self, method: &str) -> ExponentialBuilder {
+        let n_retry = self.overrides.get(method).copied().unwrap_or(self.n_retry);
+        self.backoff_of(n_retry)
+    }
+
+    /// Returns the backoff the calls returning nothing are retried with.
+    pub fn not_found_backoff(&self) -> ExponentialBuilder {
+        self.backoff_of(self.not_found_n_retry)
+    }
+
+    fn backoff_of(&self, n_retry: usize) -> ExponentialBuilder {
+        ExponentialBuilder::default()
+            .with_min_delay(self.cooldown)
+            .with_max_delay(self.max_cooldown.max(self.cooldown))
+            .with_max_times(n_retry)
+            .with_jitter()
+    }
+}
+
+/// The error of a call refused because the breaker is open.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl std::fmt::Display for CircuitOpen {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Circuit breaker open, the node is considered down")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// Whether the call failed because of the node or the network (and may succeed if retried), as
+/// opposed to the request itself being wrong.
+pub fn is_retryable(err: &TransportError) -> bool {
+    match err {
+        RpcError::ErrorResp(payload) => {
+            !INVALID_REQUEST_CODES.contains(&payload.code)
+                && !payload.message.contains("execution reverted")
+        }
+        // rate limited, timed out, or a server error
+        RpcError::Transport(TransportErrorKind::HttpError(err)) => {
+            matches!(err.status, 408 | 429) || err.status >= 500
+        }
+        RpcError::Transport(TransportErrorKind::Custom(err)) => !err.is::<CircuitOpen>(),
+        RpcError::Transport(_) => true,
+        RpcError::NullResp => true,
+        // a response which isn't even JSON comes from a proxy (e.g. an HTML error page)
+        RpcError::DeserError { text, .. } => {
+            serde_json::from_str::<serde_json::Value>(text).is_err()
+        }
+        RpcError::SerError(_) | RpcError::UnsupportedFeature(_) | RpcError::LocalUsageError(_) => {
+            false
+        }
+    }
+}
+
+/// Counts the consecutive transient failures of the calls, opening once they reach the threshold.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u64,
+    cooldown: Duration,
+    failures: u64,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u64, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            failures: 0,
+            opened_at: None,
+        }
+    }
+
+    /// Fails if the breaker is open.  Once it has cooled down, calls are let through again, the
+    /// first failure opening it right back.
+    pub fn admit(&self) -> Result<(), TransportError> {
+        match self.opened_at {
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => {
+                Err(TransportErrorKind::custom(CircuitOpen))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if self.opened_at.take().is_some() {
+            info!("The node is responding again, closing the circuit breaker");
+        }
+        self.failures = 0;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+        // a threshold of 0 disables the breaker
+        if self.threshold == 0 || self.failures < self.threshold {
+            return;
+        }
+        if self.opened_at.is_none() {
+            error!(
+                "{} calls to the node failed in a row, opening the circuit breaker for {:?}",
+                self.failures, self.cooldown
+            );
+        } else {
+            warn!("The node is still failing, keeping the circuit breaker open");
+        }
+        self.opened_at = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::rpc::json_rpc::ErrorPayload;
+    use backon::BackoffBuilder;
+
+    use crate::blockchain_config::parse_method_overrides;
+
+    #[test]
+    fn retries_transient_errors_until_the_breaker_opens() {
+        let error_resp = |code: i64, message: &str| {
+            TransportError::ErrorResp(ErrorPayload {
+                code,
+                message: message.to_string(),
+                data: None,
+            })
+        };
+        assert!(is_retryable(&error_resp(429, "Too Many Requests")));
+        assert!(is_retryable(&error_resp(-32000, "header not found")));
+        assert!(!is_retryable(&error_resp(-32602, "invalid argument 0")));
+        assert!(!is_retryable(&error_resp(3, "execution reverted")));
+
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record_failure();
+        assert!(breaker.admit().is_ok());
+        breaker.record_failure();
+        let refused = breaker.admit().unwrap_err();
+        assert!(!is_retryable(&refused));
+
+        breaker.record_success();
+        assert!(breaker.admit().is_ok());
+
+        let policy = RetryPolicy {
+            not_found_n_retry: 2,
+            ..Default::default()
+        };
+        let n_retry = |backoff: ExponentialBuilder| backoff.build().count();
+        assert_eq!(n_retry(policy.backoff("eth_getBlockByNumber")), 5);
+        assert_eq!(n_retry(policy.not_found_backoff()), 2);
+
+        assert_eq!(
+            parse_method_overrides::<usize>("ENVKEY", "trace_block=10, eth_getLogs = 2,bogus"),
+            HashMap::from([
+                ("trace_block".to_string(), 10),
+                ("eth_getLogs".to_string(), 2)
+            ])
+        );
+    }
+}
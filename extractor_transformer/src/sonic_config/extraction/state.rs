@@ -0,0 +1,352 @@
+//! Support for extracting the state changed by each transaction of a block, either with the
+//! Parity-style `trace_replayBlockTransactions` and the `stateDiff` trace type, or with the
+//! geth-style `debug_traceBlockByNumber` and the `prestateTracer` in diff mode.  The pre and post
+//! states returned by the latter are converted into the same [StateDiff]s the former returns, so
+//! that both trace backends produce the same `state_diffs` records.
+
+use std::collections::BTreeMap;
+
+use alloy::{
+    primitives::{BlockHash, TxHash, B256, U64},
+    rpc::types::trace::{
+        geth::{AccountState, DiffMode},
+        parity::{
+            AccountDiff, ChangedType, Delta, StateDiff, TraceResultsWithTransactionHash, TraceType,
+        },
+    },
+    transports::{TransportErrorKind, TransportResult},
+};
+use serde::{Deserialize, Serialize};
+
+/// The state changed by a single transaction.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TxStateDiff {
+    pub transaction_hash: TxHash,
+    pub transaction_position: u64,
+    pub state_diff: StateDiff,
+}
+
+/// The state changed by every transaction within a block.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StateDiffs {
+    block_number: u64,
+    block_hash: BlockHash,
+    block_timestamp: i64,
+    diffs: Vec<TxStateDiff>,
+}
+
+impl StateDiffs {
+    #[inline]
+    pub fn new(
+        block_number: u64,
+        block_hash: BlockHash,
+        block_timestamp: i64,
+        diffs: Vec<TxStateDiff>,
+    ) -> Self {
+        Self {
+            block_number,
+            block_hash,
+            block_timestamp,
+            diffs,
+        }
+    }
+
+    #[inline]
+    pub fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    #[inline]
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
+
+    #[inline]
+    pub fn block_timestamp(&self) -> i64 {
+        self.block_timestamp
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &TxStateDiff> {
+        self.diffs.iter()
+    }
+}
+
+/// The parameters of `trace_replayBlockTransactions` selecting the `stateDiff` trace type.
+pub fn state_diff_params(block_number: u64) -> (String, [TraceType; 1]) {
+    (format!("0x{:x}", block_number), [TraceType::StateDiff])
+}
+
+/// The parameters of `debug_traceBlockByNumber` selecting the `prestateTracer` in diff mode.
+pub fn prestate_tracer_params(block_number: u64) -> (String, serde_json::Value) {
+    (
+        format!("0x{:x}", block_number),
+        serde_json::json!({ "tracer": "prestateTracer", "tracerConfig": { "diffMode": true } }),
+    )
+}
+
+/// The pre and post states of a single transaction, as returned by `debug_traceBlockByNumber`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GethTxStateDiff {
+    /// Only returned by the more recent nodes, the position in the block is used otherwise.
+    #[serde(default)]
+    pub tx_hash: Option<TxHash>,
+    #[serde(default)]
+    pub result: Option<DiffMode>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Collects the state diffs of the replayed transactions of a block, in the order they were
+/// returned (the order of the transactions within the block).
+pub fn from_replayed_transactions(
+    block_number: u64,
+    block_hash: BlockHash,
+    block_timestamp: i64,
+    replayed: Vec<TraceResultsWithTransactionHash>,
+) -> StateDiffs {
+    let diffs = replayed
+        .into_iter()
+        .enumerate()
+        .map(|(position, replayed)| TxStateDiff {
+            transaction_hash: replayed.transaction_hash,
+            transaction_position: position as u64,
+            state_diff: replayed.full_trace.state_diff.unwrap_or_default(),
+        })
+        .collect();
+
+    StateDiffs::new(block_number, block_hash, block_timestamp, diffs)
+}
+
+/// Converts the pre and post states of every transaction of a block into [StateDiff]s.  The
+/// hashes of the transactions of the block are required to resolve the transaction of each diff.
+pub fn from_prestate_diffs(
+    block_number: u64,
+    block_hash: BlockHash,
+    block_timestamp: i64,
+    transaction_hashes: &[TxHash],
+    tx_diffs: Vec<GethTxStateDiff>,
+) -> TransportResult<StateDiffs> {
+    if tx_diffs.len() != transaction_hashes.len() {
+        return Err(TransportErrorKind::custom_str(&format!(
+            "Received {} transaction state diffs for the {} transactions of block #{}",
+            tx_diffs.len(),
+            transaction_hashes.len(),
+            block_number
+        )));
+    }
+
+    let mut diffs = Vec::with_capacity(tx_diffs.len());
+
+    for (position, (tx_diff, tx_hash)) in tx_diffs.into_iter().zip(transaction_hashes).enumerate() {
+        let transaction_hash = tx_diff.tx_hash.unwrap_or(*tx_hash);
+        if transaction_hash != *tx_hash {
+            return Err(TransportErrorKind::custom_str(&format!(
+                "Transaction state diff #{} of block #{} is for {} instead of {}",
+                position, block_number, transaction_hash, tx_hash
+            )));
+        }
+
+        let diff_mode = match tx_diff.result {
+            Some(diff_mode) => diff_mode,
+            None => {
+                return Err(TransportErrorKind::custom_str(&format!(
+                    "Failed to trace the state diff of transaction {} of block #{}: {}",
+                    transaction_hash,
+                    block_number,
+                    tx_diff.error.unwrap_or_default()
+                )))
+            }
+        };
+
+        diffs.push(TxStateDiff {
+            transaction_hash,
+            transaction_position: position as u64,
+            state_diff: diff_mode_to_state_diff(diff_mode),
+        });
+    }
+
+    Ok(StateDiffs::new(
+        block_number,
+        block_hash,
+        block_timestamp,
+        diffs,
+    ))
+}
+
+/// Converts the pre and post states of a transaction into the [StateDiff] Parity returns.
+///
+/// Accounts only found in the post state were created, and accounts only found in the pre state
+/// were destroyed.  For the others, the post state only holds the fields which changed, and the
+/// pre state leaves out zero values (both for the fields and the storage slots).
+pub fn diff_mode_to_state_diff(diff_mode: DiffMode) -> StateDiff {
+    let DiffMode { post, mut pre } = diff_mode;
+
+    let mut state_diff = StateDiff::default();
+
+    for (address, post_state) in post {
+        let account_diff = match pre.remove(&address) {
+            Some(pre_state) => changed_account(pre_state, post_state),
+            None => AccountDiff {
+                balance: Delta::Added(post_state.balance.unwrap_or_default()),
+                code: Delta::Added(post_state.code.unwrap_or_default()),
+                nonce: Delta::Added(U64::from(post_state.nonce.unwrap_or_default())),
+                storage: post_state
+                    .storage
+                    .into_iter()
+                    .map(|(key, value)| (key, Delta::Added(value)))
+                    .collect(),
+            },
+        };
+        state_diff.insert(address, account_diff);
+    }
+
+    for (address, pre_state) in pre {
+        state_diff.insert(
+            address,
+            AccountDiff {
+                balance: Delta::Removed(pre_state.balance.unwrap_or_default()),
+                code: Delta::Removed(pre_state.code.unwrap_or_default()),
+                nonce: Delta::Removed(U64::from(pre_state.nonce.unwrap_or_default())),
+                storage: pre_state
+                    .storage
+                    .into_iter()
+                    .map(|(key, value)| (key, Delta::Removed(value)))
+                    .collect(),
+            },
+        );
+    }
+
+    state_diff
+}
+
+/// The diff of an account found in both the pre and post states.
+fn changed_account(pre: AccountState, post: AccountState) -> AccountDiff {
+    let AccountState {
+        balance,
+        code,
+        nonce,
+        mut storage,
+    } = pre;
+
+    let mut storage_diff = BTreeMap::new();
+    for (key, to) in post.storage {
+        let from = storage.remove(&key).unwrap_or_default();
+        storage_diff.insert(key, delta(from, to));
+    }
+    // the slots set back to zero are left out of the post state
+    for (key, from) in storage {
+        storage_diff.insert(key, delta(from, B256::ZERO));
+    }
+    storage_diff.retain(|_, diff| !matches!(diff, Delta::Unchanged));
+
+    AccountDiff {
+        balance: post.balance.map_or(Delta::Unchanged, |to| {
+            delta(balance.unwrap_or_default(), to)
+        }),
+        code: post
+            .code
+            .map_or(Delta::Unchanged, |to| delta(code.unwrap_or_default(), to)),
+        nonce: post.nonce.map_or(Delta::Unchanged, |to| {
+            delta(U64::from(nonce.unwrap_or_default()), U64::from(to))
+        }),
+        storage: storage_diff,
+    }
+}
+
+fn delta<T: PartialEq>(from: T, to: T) -> Delta<T> {
+    if from == to {
+        Delta::Unchanged
+    } else {
+        Delta::Changed(ChangedType { from, to })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::{address, b256, Bytes, U256};
+
+    #[test]
+    fn prestate_diff_converts_like_state_diff() {
+        let diff_mode: DiffMode = serde_json::from_value(serde_json::json!({
+            "pre": {
+                "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0": {
+                    "balance": "0x10",
+                    "nonce": 1,
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000001":
+                            "0x0000000000000000000000000000000000000000000000000000000000000005"
+                    }
+                },
+                "0x039e2fb66102314ce7b64ce5ce3e5183bc94ad38": {
+                    "balance": "0x1",
+                    "code": "0x6000"
+                }
+            },
+            "post": {
+                "0x00ad14c150da7a21872d4e934f4fa37a13e3d8d0": {
+                    "balance": "0x8",
+                    "nonce": 2,
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000002":
+                            "0x0000000000000000000000000000000000000000000000000000000000000007"
+                    }
+                },
+                "0x7ae8a64a3d3a0a0f1c0a5b1bd2f0ce0e3a9b1f35": {
+                    "balance": "0x2",
+                    "nonce": 1
+                }
+            }
+        }))
+        .expect("valid diff mode");
+
+        let state_diff = diff_mode_to_state_diff(diff_mode);
+        assert_eq!(state_diff.len(), 3);
+
+        let changed = &state_diff[&address!("00ad14c150da7a21872d4e934f4fa37a13e3d8d0")];
+        assert_eq!(
+            changed.balance,
+            Delta::Changed(ChangedType {
+                from: U256::from(16),
+                to: U256::from(8)
+            })
+        );
+        assert_eq!(
+            changed.nonce,
+            Delta::Changed(ChangedType {
+                from: U64::from(1),
+                to: U64::from(2)
+            })
+        );
+        assert_eq!(changed.code, Delta::Unchanged);
+        // the first slot was set back to zero, the second one was set from zero
+        assert_eq!(
+            changed.storage
+                [&b256!("0000000000000000000000000000000000000000000000000000000000000001")],
+            Delta::Changed(ChangedType {
+                from: b256!("0000000000000000000000000000000000000000000000000000000000000005"),
+                to: B256::ZERO
+            })
+        );
+        assert_eq!(
+            changed.storage
+                [&b256!("0000000000000000000000000000000000000000000000000000000000000002")],
+            Delta::Changed(ChangedType {
+                from: B256::ZERO,
+                to: b256!("0000000000000000000000000000000000000000000000000000000000000007")
+            })
+        );
+
+        let removed = &state_diff[&address!("039e2fb66102314ce7b64ce5ce3e5183bc94ad38")];
+        assert_eq!(removed.balance, Delta::Removed(U256::from(1)));
+        assert_eq!(removed.code, Delta::Removed(Bytes::from(vec![0x60, 0x00])));
+
+        let added = &state_diff[&address!("7ae8a64a3d3a0a0f1c0a5b1bd2f0ce0e3a9b1f35")];
+        assert_eq!(added.balance, Delta::Added(U256::from(2)));
+        assert_eq!(added.nonce, Delta::Added(U64::from(1)));
+        assert_eq!(added.code, Delta::Added(Bytes::new()));
+    }
+}
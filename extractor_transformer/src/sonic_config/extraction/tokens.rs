@@ -0,0 +1,194 @@
+//! Retrieves the metadata (`name()`, `symbol()`, `decimals()` and `totalSupply()`) of the token
+//! contracts emitting `Transfer` events, with `eth_call`s at the block where each token is first
+//! seen.  The tokens already published are cached for the lifetime of the process, so that each
+//! token is only queried and published once per run.  A token is only marked as seen once the
+//! records of its block are published, so that a block failing (or not being published for any
+//! other reason) leaves its tokens to be queried again by the next block they show up in.
+
+use std::{
+    collections::HashSet,
+    sync::{Mutex, MutexGuard},
+};
+
+use alloy::{
+    primitives::{b256, Address, Bytes, B256, U256},
+    rpc::types::Log,
+    transports::RpcError,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// `Transfer(address,address,uint256)`, shared by ERC-20 and ERC-721
+pub const TRANSFER_TOPIC: B256 =
+    b256!("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef");
+
+pub const NAME_SELECTOR: [u8; 4] = [0x06, 0xfd, 0xde, 0x03];
+pub const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+pub const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+pub const TOTAL_SUPPLY_SELECTOR: [u8; 4] = [0x18, 0x16, 0x0d, 0xdd];
+
+/// The tokens already published by this process
+static SEEN_TOKENS: Lazy<Mutex<HashSet<Address>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[inline]
+fn seen_tokens() -> MutexGuard<'static, HashSet<Address>> {
+    // the set stays consistent even if a holder panicked
+    SEEN_TOKENS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Returns the tokens emitting `Transfer` events within the logs which weren't published before.
+pub fn new_tokens(logs: &[Log]) -> Vec<Address> {
+    let seen = seen_tokens();
+
+    let mut new_tokens = Vec::new();
+    for log in logs {
+        let token = log.address();
+        if log.topics().first() == Some(&TRANSFER_TOPIC)
+            && !seen.contains(&token)
+            && !new_tokens.contains(&token)
+        {
+            new_tokens.push(token);
+        }
+    }
+    new_tokens
+}
+
+/// Whether the token was already published by this process.
+pub fn is_seen(token: &Address) -> bool {
+    seen_tokens().contains(token)
+}
+
+/// Marks the tokens as seen, once their records are published.
+pub fn mark_seen(tokens: &[Address]) {
+    seen_tokens().extend(tokens.iter().copied());
+}
+
+/// The parameters of the `eth_call` of a token's function at a block.
+pub fn call_params(
+    token: Address,
+    selector: [u8; 4],
+    block_number: u64,
+) -> (serde_json::Value, String) {
+    (
+        serde_json::json!({ "to": token, "data": Bytes::from(selector.to_vec()) }),
+        format!("0x{:x}", block_number),
+    )
+}
+
+/// The metadata of a token, each value being `None` if the call reverted or returned something
+/// that couldn't be decoded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenMetadata {
+    pub address: Address,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    pub total_supply: Option<U256>,
+}
+
+impl TokenMetadata {
+    /// Decodes the metadata from the return data of each call (`None` if the call failed).
+    pub fn decode(
+        address: Address,
+        name: Option<Bytes>,
+        symbol: Option<Bytes>,
+        decimals: Option<Bytes>,
+        total_supply: Option<Bytes>,
+    ) -> Self {
+        Self {
+            address,
+            name: name.and_then(|data| decode_string(&data)),
+            symbol: symbol.and_then(|data| decode_string(&data)),
+            decimals: decimals
+                .and_then(|data| decode_uint(&data))
+                .and_then(|decimals| u8::try_from(decimals).ok()),
+            total_supply: total_supply.and_then(|data| decode_uint(&data)),
+        }
+    }
+}
+
+/// Whether the error is the node refusing the call (e.g. a revert), rather than a failure to
+/// reach the node.
+#[inline]
+pub fn is_call_error<E>(err: &RpcError<E>) -> bool {
+    matches!(err, RpcError::ErrorResp(_))
+}
+
+/// Decodes a `uint` return value.
+fn decode_uint(data: &[u8]) -> Option<U256> {
+    if data.len() < 32 {
+        return None;
+    }
+    Some(U256::from_be_slice(&data[..32]))
+}
+
+/// Decodes a `string` return value, or a `bytes32` one as returned by some older tokens (e.g.
+/// MKR's `symbol()`).  Null characters are trimmed, and invalid UTF-8 is replaced.
+fn decode_string(data: &[u8]) -> Option<String> {
+    let bytes = if data.len() == 32 {
+        data
+    } else {
+        let offset = usize::try_from(decode_uint(data)?).ok()?;
+        let len = usize::try_from(decode_uint(data.get(offset..)?)?).ok()?;
+        data.get(offset.checked_add(32)?..offset.checked_add(32)?.checked_add(len)?)?
+    };
+
+    let decoded = String::from_utf8_lossy(bytes)
+        .trim_matches(char::from(0))
+        .to_string();
+    (!decoded.is_empty()).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::hex;
+
+    #[test]
+    fn decodes_strings_and_bytes32() {
+        let abi_string = hex::decode(concat!(
+            "0000000000000000000000000000000000000000000000000000000000000020",
+            "0000000000000000000000000000000000000000000000000000000000000004",
+            "5553444300000000000000000000000000000000000000000000000000000000"
+        ))
+        .unwrap();
+        assert_eq!(decode_string(&abi_string).as_deref(), Some("USDC"));
+
+        let bytes32 =
+            hex::decode("4d4b520000000000000000000000000000000000000000000000000000000000")
+                .unwrap();
+        assert_eq!(decode_string(&bytes32).as_deref(), Some("MKR"));
+
+        // a length running past the returned data
+        let truncated = hex::decode(concat!(
+            "0000000000000000000000000000000000000000000000000000000000000020",
+            "0000000000000000000000000000000000000000000000000000000000000040",
+            "5553444300000000000000000000000000000000000000000000000000000000"
+        ))
+        .unwrap();
+        assert_eq!(decode_string(&truncated), None);
+        assert_eq!(decode_string(&[]), None);
+    }
+
+    #[test]
+    fn tokens_are_only_seen_once_published() {
+        let token = alloy::primitives::address!("00000000000000000000000000000000000070c3");
+        let transfer = Log {
+            inner: alloy::primitives::Log::new_unchecked(token, vec![TRANSFER_TOPIC], Bytes::new()),
+            ..Default::default()
+        };
+        let logs = vec![transfer.clone(), transfer];
+
+        // extracting the block (twice, e.g. as it failed) doesn't mark its token
+        assert_eq!(new_tokens(&logs), vec![token]);
+        assert_eq!(new_tokens(&logs), vec![token]);
+        assert!(!is_seen(&token));
+
+        mark_seen(&[token]);
+        assert!(is_seen(&token));
+        assert!(new_tokens(&logs).is_empty());
+    }
+}
@@ -1,12 +1,12 @@
 use alloy::{
     eips::BlockNumberOrTag,
     network::{Ethereum, Network},
-    primitives::FixedBytes,
+    primitives::{FixedBytes, TxHash},
     providers::Provider,
     rpc::types::{Block, Filter, Log, TransactionReceipt},
-    transports::{BoxTransport, Transport, TransportResult},
+    transports::{BoxTransport, RpcError, Transport, TransportResult},
 };
-use log::{info, warn};
+use futures::future::try_join_all;
 
 use super::Extractor;
 
@@ -20,150 +20,56 @@ pub trait EvmExtractor<
     N: Network = Ethereum,
 >: Extractor<P, T, N>
 {
-    fn incr_request(&self);
-
-    fn incr_fails(&self);
-
     /// Returns a [Block] given the `block_number` as a [u64]. `full` [bool] determines whether
-    /// or not you will receive the transactions as well.
+    /// or not you will receive the transactions as well.  A block the node doesn't have (yet) is
+    /// asked for again, and is `None` if the node still doesn't have it.
     async fn get_block(&self, block_number: u64, full: bool) -> TransportResult<Option<Block>> {
-        self.provider()
-            .get_block_by_number(BlockNumberOrTag::Number(block_number), full)
-            .await
-    }
-
-    /// Like [EvmExtractor::get_block], except includes `n_retry` attempts, and a `cooldown`
-    /// time period in seconds
-    async fn get_block_with_retry(
-        &self,
-        block_number: u64,
-        full: bool,
-        n_retry: usize,
-        cooldown: usize,
-    ) -> TransportResult<Option<Block>> {
-        if n_retry == 0 {
-            self.get_block(block_number, full).await
-        } else {
-            let mut mr_attempt: TransportResult<Option<Block>> = Ok(None);
-            for attempt_n in 0..n_retry {
-                mr_attempt = self.get_block(block_number, full).await;
-                match &mr_attempt {
-                    Ok(Some(_)) => return mr_attempt,
-                    Ok(None) => {
-                        self.incr_fails();
-                        warn!(
-                            "Failed to retrieve block #{} (attempt #{}/{}), retrieved None.",
-                            block_number, attempt_n, n_retry
-                        );
-                    }
-                    Err(err) => {
-                        self.incr_fails();
-                        warn!(
-                            "Failed to retrieve block #{} (attempt #{}/{}), error returned: {}",
-                            block_number, attempt_n, n_retry, err
-                        );
-                    }
-                }
-
-                if cooldown > 0 {
-                    info!(
-                        "Due to failure to retrieve block #{}, will retry after {} seconds.",
-                        block_number, cooldown
-                    );
-                    tokio::time::sleep(std::time::Duration::from_secs(cooldown as u64)).await;
-                }
-            }
-
-            mr_attempt
-        }
+        self.with_retry_until_found("eth_getBlockByNumber", block_number, || {
+            self.provider()
+                .get_block_by_number(BlockNumberOrTag::Number(block_number), full)
+        })
+        .await
     }
 
     /// Returns a [Vec] of [Log] given the `block_number` as a [u64]
     async fn get_logs(&self, block_number: u64) -> TransportResult<Vec<Log>> {
-        self.incr_request();
-        self.provider()
-            .get_logs(&Filter::new().select(block_number))
-            .await
-    }
-
-    /// Like [EvmExtractor::get_logs], except includes `n_retry` attempts, and a `cooldown`
-    /// time period in seconds
-    async fn get_logs_with_retry(
-        &self,
-        block_number: u64,
-        n_retry: usize,
-        cooldown: usize,
-    ) -> TransportResult<Vec<Log>> {
-        if n_retry == 0 {
-            self.get_logs(block_number).await
-        } else {
-            let mut mr_attempt = Ok(Vec::new());
-
-            for attempt_n in 0..n_retry {
-                mr_attempt = self.get_logs(block_number).await;
-
-                match &mr_attempt {
-                    Ok(_) => return mr_attempt,
-                    Err(err) => {
-                        self.incr_fails();
-                        warn!("Failed to retrieve logs from block #{} (attempt #{}/{}), error returned: {}", block_number, attempt_n, n_retry, err);
-                    }
-                }
-
-                if cooldown > 0 {
-                    info!("Due to failure to retrieve logs from block #{}, will retry after {} seconds.", block_number, cooldown);
-                    tokio::time::sleep(std::time::Duration::from_secs(cooldown as u64)).await;
-                }
-            }
-
-            mr_attempt
-        }
+        let filter = Filter::new().select(block_number);
+        self.with_retry("eth_getLogs", block_number, || {
+            self.provider().get_logs(&filter)
+        })
+        .await
     }
 
-    /// Returns a [Vec] of ReceiptResponses (Network-dependent) given the `block_number` as a [u64]
+    /// Returns a [Vec] of ReceiptResponses (Network-dependent) given the `block_number` as a [u64].
+    /// Receipts the node doesn't have (yet) are asked for again, and fail if the node still
+    /// doesn't have them.
     async fn get_block_receipts(
         &self,
         block_number: u64,
     ) -> TransportResult<Vec<N::ReceiptResponse>> {
-        self.incr_request();
-        self.provider()
-            .get_block_receipts(block_number.into())
-            .await
-            .map(|opt| opt.unwrap_or_default())
+        self.with_retry_until_found("eth_getBlockReceipts", block_number, || {
+            self.provider().get_block_receipts(block_number.into())
+        })
+        .await?
+        .ok_or(RpcError::NullResp)
     }
 
-    /// Like [EvmExtractor::get_block_receipts], except includes `n_retry` attempts, and a `cooldown`
-    /// time period in seconds
-    async fn get_block_receipts_with_retry(
+    /// Returns the ReceiptResponses (Network-dependent) of the transactions of the block, one
+    /// `eth_getTransactionReceipt` call per transaction, for the nodes without
+    /// `eth_getBlockReceipts`.
+    async fn get_transaction_receipts(
         &self,
         block_number: u64,
-        n_retry: usize,
-        cooldown: usize,
+        tx_hashes: &[TxHash],
     ) -> TransportResult<Vec<N::ReceiptResponse>> {
-        if n_retry == 0 {
-            self.get_block_receipts(block_number).await
-        } else {
-            let mut mr_attempt = Ok(Vec::new());
-
-            for attempt_n in 0..n_retry {
-                mr_attempt = self.get_block_receipts(block_number).await;
-
-                match &mr_attempt {
-                    Ok(_) => return mr_attempt,
-                    Err(err) => {
-                        self.incr_fails();
-                        warn!("Failed to retrieve receipts from block #{} (attempt ${}/{}), error returned: {}", block_number, attempt_n, n_retry, err);
-                    }
-                }
-
-                if cooldown > 0 {
-                    info!("Due to failure to retrieve receipts from block #{}, will retry after {} seconds.", block_number, cooldown);
-                    tokio::time::sleep(std::time::Duration::from_secs(cooldown as u64)).await;
-                }
-            }
-
-            mr_attempt
-        }
+        try_join_all(tx_hashes.iter().map(|tx_hash| async move {
+            self.with_retry_until_found("eth_getTransactionReceipt", block_number, || {
+                self.provider().get_transaction_receipt(*tx_hash)
+            })
+            .await?
+            .ok_or(RpcError::NullResp)
+        }))
+        .await
     }
 }
 
@@ -172,6 +78,8 @@ pub trait EvmExtraction: serde::Serialize + Clone {
 
     fn block_hash(&self) -> FixedBytes<32>;
 
+    fn parent_hash(&self) -> FixedBytes<32>;
+
     fn block_timestamp(&self) -> i64;
 
     fn block(&self) -> Option<&Block>;
@@ -186,6 +94,9 @@ pub trait EvmExtraction: serde::Serialize + Clone {
 pub struct EvmExtracted {
     pub block_number: u64,
     pub block_hash: FixedBytes<32>,
+    /// Missing from the extractions saved before it was added
+    #[serde(default)]
+    pub parent_hash: FixedBytes<32>,
     pub block_timestamp: i64,
     pub block: Option<Block>,
     pub logs: Option<Vec<Log>>,
@@ -201,6 +112,10 @@ impl EvmExtraction for EvmExtracted {
     fn block_hash(&self) -> FixedBytes<32> {
         self.block_hash
     }
+    /// Returns the hash of the parent block
+    fn parent_hash(&self) -> FixedBytes<32> {
+        self.parent_hash
+    }
     /// Returns the block_number
     fn block_number(&self) -> u64 {
         self.block_number
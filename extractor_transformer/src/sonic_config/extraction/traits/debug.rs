@@ -2,11 +2,18 @@ use alloy::{
     network::{Ethereum, Network},
     primitives::{Address, BlockHash, BlockNumber, TxHash},
     providers::Provider,
+    rpc::types::trace::parity::TraceResultsWithTransactionHash,
     transports::{BoxTransport, Transport, TransportResult},
 };
 use serde::{Deserialize, Serialize};
 
-use super::Extractor;
+use super::{
+    super::{
+        geth::{self, GethTxTrace, TraceBackend},
+        state::{self, GethTxStateDiff},
+    },
+    Extractor,
+};
 
 /// Allows extractor trace debug data from chains
 #[allow(async_fn_in_trait)]
@@ -16,31 +23,52 @@ pub trait EvmDebugExtractor<
     N: Network = Ethereum,
 >: Extractor<P, T, N>
 {
+    /// The RPC used to retrieve the traces of a block
+    #[inline]
+    fn trace_backend(&self) -> TraceBackend {
+        TraceBackend::Parity
+    }
+
     #[inline]
     async fn extract_debug(&self, block_number: u64) -> TransportResult<Option<DebugTraces>> {
         let provider = self.provider();
+        let backend = self.trace_backend();
 
-        let (block_hash, block_timestamp) = match provider
-            .get_block(
-                block_number.into(),
-                alloy::rpc::types::BlockTransactionsKind::Hashes,
-            )
-            .await? {
-                Some(block) => (block.header.hash, block.header.timestamp),
-                None => return Ok(None),
-            };
+        // flattening the call frames requires the transactions of the block
+        let kind = match backend {
+            TraceBackend::Parity => alloy::rpc::types::BlockTransactionsKind::Hashes,
+            TraceBackend::Geth => alloy::rpc::types::BlockTransactionsKind::Full,
+        };
 
-        let traces = if block_number != 0 {
-            self.get_block_traces(block_number).await?
-        } else {
+        let block = match self
+            .with_retry("eth_getBlockByNumber", block_number, || {
+                provider.get_block(block_number.into(), kind)
+            })
+            .await?
+        {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        let traces = if block_number == 0 {
             Vec::new()
+        } else {
+            match backend {
+                TraceBackend::Parity => self.get_block_traces(block_number).await?,
+                TraceBackend::Geth => geth::flatten_block_traces(
+                    block_number,
+                    block.header.hash.unwrap_or_default(),
+                    block.transactions.as_transactions().unwrap_or_default(),
+                    self.get_block_call_frames(block_number).await?,
+                )?,
+            }
         };
 
         Ok(Some(DebugTraces {
             block_number,
-            block_hash,
-            block_timestamp: block_timestamp as i64,
-            traces
+            block_hash: block.header.hash,
+            block_timestamp: block.header.timestamp as i64,
+            traces,
         }))
     }
 
@@ -53,15 +81,60 @@ pub trait EvmDebugExtractor<
             .await
     }
 
+    /// Returns the `callTracer` call frames of every transaction within a singular block
+    #[inline]
+    async fn get_block_call_frames(&self, block_number: u64) -> TransportResult<Vec<GethTxTrace>> {
+        self.with_retry("debug_traceBlockByNumber", block_number, || {
+            self.provider().raw_request::<_, Vec<GethTxTrace>>(
+                "debug_traceBlockByNumber".into(),
+                geth::call_tracer_params(block_number),
+            )
+        })
+        .await
+    }
+
+    /// Returns the `stateDiff` of every transaction within a singular block, replaying them
+    #[inline]
+    async fn get_block_state_diffs(
+        &self,
+        block_number: u64,
+    ) -> TransportResult<Vec<TraceResultsWithTransactionHash>> {
+        self.with_retry("trace_replayBlockTransactions", block_number, || {
+            self.provider()
+                .raw_request::<_, Vec<TraceResultsWithTransactionHash>>(
+                    "trace_replayBlockTransactions".into(),
+                    state::state_diff_params(block_number),
+                )
+        })
+        .await
+    }
+
+    /// Returns the `prestateTracer` pre and post states of every transaction within a singular
+    /// block
+    #[inline]
+    async fn get_block_prestate_diffs(
+        &self,
+        block_number: u64,
+    ) -> TransportResult<Vec<GethTxStateDiff>> {
+        self.with_retry("debug_traceBlockByNumber", block_number, || {
+            self.provider().raw_request::<_, Vec<GethTxStateDiff>>(
+                "debug_traceBlockByNumber".into(),
+                state::prestate_tracer_params(block_number),
+            )
+        })
+        .await
+    }
+
     /// Returns all the transaction traces within a singular block
     #[inline]
     async fn get_block_traces(&self, block_number: u64) -> TransportResult<Vec<TxTrace>> {
-        self.provider()
-            .raw_request::<_, Vec<TxTrace>>(
+        self.with_retry("trace_block", block_number, || {
+            self.provider().raw_request::<_, Vec<TxTrace>>(
                 "trace_block".into(),
                 vec![format!("0x{:x}", block_number)],
             )
-            .await
+        })
+        .await
     }
 }
 
@@ -86,7 +159,7 @@ impl std::fmt::Display for CallType {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum TraceType {
     Call,
@@ -130,8 +203,9 @@ pub struct TxTraceActionCall {
     pub from: Address,
     #[serde(rename = "callType")]
     pub call_type: String,
-    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity")]
-    pub gas: u128,
+    /// `None` when the trace backend can't tell it exactly (the top level trace with `geth`)
+    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity::opt")]
+    pub gas: Option<u128>,
     pub input: String,
     pub to: Address,
     pub value: String,
@@ -153,8 +227,9 @@ pub struct TxTraceActionCreate {
     pub from: Address,
     #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity")]
     pub value: u128,
-    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity")]
-    pub gas: u128,
+    /// `None` when the trace backend can't tell it exactly (the top level trace with `geth`)
+    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity::opt")]
+    pub gas: Option<u128>,
     pub init: String,
 }
 
@@ -170,15 +245,25 @@ pub struct TxTraceActionSuicide {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TxTraceResult {
-    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity")]
-    pub gas_used: u128,
+    /// `None` when the trace backend can't tell it exactly (the top level trace with `geth`)
+    #[serde(
+        default,
+        with = "alloy::rpc::types::serde_helpers::quantity::opt",
+        alias = "gasUsed"
+    )]
+    pub gas_used: Option<u128>,
     pub output: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct TxTraceResultCreate {
-    #[serde(default, with = "alloy::rpc::types::serde_helpers::quantity")]
-    pub gas_used: u128,
+    /// `None` when the trace backend can't tell it exactly (the top level trace with `geth`)
+    #[serde(
+        default,
+        with = "alloy::rpc::types::serde_helpers::quantity::opt",
+        alias = "gasUsed"
+    )]
+    pub gas_used: Option<u128>,
     pub address: Address,
     pub code: String,
 }
@@ -252,6 +337,8 @@ pub struct TxTraceCreate {
     pub result: Option<TxTraceResultCreate>,
     pub error: Option<String>,
     pub subtraces: u64,
+    #[serde(rename = "traceAddress", default)]
+    pub trace_address: Vec<u64>,
     #[serde(rename = "transactionPosition")]
     pub transaction_position: u64,
     #[serde(rename = "transactionHash")]
@@ -268,6 +355,8 @@ pub struct TxTraceEmpty {
     pub result: Option<TxTraceResultEmpty>,
     pub error: Option<String>,
     pub subtraces: u64,
+    #[serde(rename = "traceAddress", default)]
+    pub trace_address: Vec<u64>,
     #[serde(rename = "transactionPosition")]
     pub transaction_position: u64,
     #[serde(rename = "transactionHash")]
@@ -341,6 +430,50 @@ impl TxTrace {
             Self::Empty(empty) => (Some(empty.transaction_hash), Some(empty.transaction_position))
         }
     }
+
+    #[inline]
+    pub const fn trace_type(&self) -> TraceType {
+        match self {
+            Self::Call(_) => TraceType::Call,
+            Self::Create(_) => TraceType::Create,
+            Self::Reward(_) => TraceType::Reward,
+            Self::Suicide(_) => TraceType::Suicide,
+            Self::Empty(_) => TraceType::Empty,
+        }
+    }
+
+    #[inline]
+    pub fn trace_address(&self) -> &[u64] {
+        match self {
+            Self::Call(call) => &call.trace_address,
+            Self::Create(create) => &create.trace_address,
+            Self::Reward(reward) => &reward.trace_address,
+            Self::Suicide(suicide) => &suicide.trace_address,
+            Self::Empty(empty) => &empty.trace_address,
+        }
+    }
+
+    #[inline]
+    pub fn subtraces(&self) -> u64 {
+        match self {
+            Self::Call(call) => call.subtraces,
+            Self::Create(create) => create.subtraces,
+            Self::Reward(reward) => reward.subtraces,
+            Self::Suicide(suicide) => suicide.subtraces,
+            Self::Empty(empty) => empty.subtraces,
+        }
+    }
+
+    #[inline]
+    pub fn error(&self) -> Option<&str> {
+        match self {
+            Self::Call(call) => call.error.as_deref(),
+            Self::Create(create) => create.error.as_deref(),
+            Self::Reward(reward) => reward.error.as_deref(),
+            Self::Suicide(suicide) => suicide.error.as_deref(),
+            Self::Empty(empty) => empty.error.as_deref(),
+        }
+    }
 }
 
 pub trait DebugExtraction: serde::Serialize + Clone {
@@ -381,6 +514,21 @@ impl DebugExtraction for DebugTraces {
 
 #[allow(dead_code)]
 impl DebugTraces {
+    #[inline]
+    pub fn new(
+        block_number: u64,
+        block_hash: Option<BlockHash>,
+        block_timestamp: i64,
+        traces: Vec<TxTrace>,
+    ) -> Self {
+        Self {
+            block_number,
+            block_hash,
+            block_timestamp,
+            traces,
+        }
+    }
+
     #[inline]
     pub fn unwrap(self) -> Vec<TxTrace> {
         self.traces
@@ -4,14 +4,111 @@ mod debug;
 pub use basic::*;
 pub use debug::*;
 
+use std::future::Future;
+
 use alloy::{
     network::{Ethereum, Network},
     providers::Provider,
-    transports::{BoxTransport, Transport},
+    transports::{BoxTransport, RpcError, Transport, TransportResult},
 };
+use backon::Retryable;
+use log::warn;
+
+use super::retry::{self, RetryPolicy};
 
 #[allow(async_fn_in_trait)]
 pub trait Extractor<P: Provider<T, N>, T: Transport + Clone = BoxTransport, N: Network = Ethereum> {
     /// Return the underlying provider used for data extraction
     fn provider(&self) -> &P;
+
+    /// Return the policy the calls to the provider are retried with
+    fn retry_policy(&self) -> &RetryPolicy;
+
+    fn incr_request(&self);
+
+    fn incr_fails(&self);
+
+    /// Makes the `call` to the RPC `method`, retrying it as configured by the [RetryPolicy] if it
+    /// fails with a transient error.  Fails at once while the circuit breaker is open.
+    async fn with_retry<R, F, Fut>(
+        &self,
+        method: &'static str,
+        block_number: u64,
+        mut call: F,
+    ) -> TransportResult<R>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = TransportResult<R>>,
+    {
+        let attempt = || {
+            let call = retry::breaker().admit().map(|_| call());
+            async move {
+                let call = call?;
+
+                self.incr_request();
+                let result = call.await;
+                match &result {
+                    Ok(_) => retry::breaker().record_success(),
+                    Err(err) => {
+                        self.incr_fails();
+                        if retry::is_retryable(err) {
+                            retry::breaker().record_failure();
+                        }
+                    }
+                }
+                result
+            }
+        };
+
+        attempt
+            .retry(self.retry_policy().backoff(method))
+            .sleep(tokio::time::sleep)
+            .when(retry::is_retryable)
+            .notify(|err, cooldown| {
+                warn!(
+                    "Call to `{}` for block #{} failed, retrying after {:?}: {}",
+                    method, block_number, cooldown, err
+                )
+            })
+            .await
+    }
+
+    /// Makes the `call` to the RPC `method` [with retries](Extractor::with_retry), and makes it
+    /// again while it returns nothing (e.g. a block the node doesn't have yet), as many times as
+    /// the [RetryPolicy] allows.  As the node did respond, an empty response doesn't count
+    /// towards the circuit breaker.  Returns `None` if the call still returns nothing.
+    async fn with_retry_until_found<R, F, Fut>(
+        &self,
+        method: &'static str,
+        block_number: u64,
+        call: F,
+    ) -> TransportResult<Option<R>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = TransportResult<Option<R>>>,
+    {
+        let attempt = || async {
+            match self.with_retry(method, block_number, &call).await? {
+                Some(found) => Ok(Some(found)),
+                None => Err(RpcError::NullResp),
+            }
+        };
+
+        let found = attempt
+            .retry(self.retry_policy().not_found_backoff())
+            .sleep(tokio::time::sleep)
+            .when(|err| matches!(err, RpcError::NullResp))
+            .notify(|_, cooldown| {
+                warn!(
+                    "Call to `{}` for block #{} returned nothing, retrying after {:?}",
+                    method, block_number, cooldown
+                )
+            })
+            .await;
+
+        match found {
+            Err(RpcError::NullResp) => Ok(None),
+            found => found,
+        }
+    }
 }
@@ -0,0 +1,299 @@
+//! Follows the tip of the chain, indexing every new block once it is `FOLLOW_CONFIRMATIONS`
+//! blocks deep.
+//!
+//! The number of the last block published is saved to a checkpoint file after every block, so
+//! that a restarted follower resumes right after it.  New heads are either polled every
+//! `FOLLOW_POLL_INTERVAL` seconds, or received from a `newHeads` subscription when
+//! `FOLLOW_NEW_HEADS_URL` is set (still polling as a fallback, in case a head is missed or the
+//! subscription drops).
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use alloy::providers::{Provider, ProviderBuilder};
+use log::{error, info, warn};
+
+use super::{
+    build_provider_with_metrics, continuity::continuity_window, continuity::ChainTracker,
+    count_from_env, extract_transform_range_on_chain, output,
+    proto_codegen::etl::simprequest::SimpleIndexingRequest, shutdown::handle_shutdown_signals,
+    ErcEventCatalog, Metrics,
+};
+
+pub const FOLLOW_CONFIRMATIONS_ENVKEY: &str = "FOLLOW_CONFIRMATIONS";
+pub const FOLLOW_POLL_INTERVAL_ENVKEY: &str = "FOLLOW_POLL_INTERVAL";
+pub const FOLLOW_BATCH_SIZE_ENVKEY: &str = "FOLLOW_BATCH_SIZE";
+pub const FOLLOW_NEW_HEADS_URL_ENVKEY: &str = "FOLLOW_NEW_HEADS_URL";
+
+/// The number of blocks a block must be buried under before being indexed, when
+/// `FOLLOW_CONFIRMATIONS` is not set
+pub const DEFAULT_FOLLOW_CONFIRMATIONS: u64 = 2;
+/// The number of seconds between two polls of the head, when `FOLLOW_POLL_INTERVAL` is not set
+pub const DEFAULT_FOLLOW_POLL_INTERVAL: u64 = 1;
+/// The maximum number of blocks indexed as a single range while catching up, when
+/// `FOLLOW_BATCH_SIZE` is not set
+pub const DEFAULT_FOLLOW_BATCH_SIZE: u64 = 100;
+
+/// The file holding the number of the last block published.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Returns the number of the last block published, if any was.
+    pub fn load(&self) -> io::Result<Option<u64>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => content
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Saves the number of the last block published.  The checkpoint is written to a temporary
+    /// file first, then renamed over the previous one, so it is never left half written.
+    pub fn save(&self, block_number: u64) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, block_number.to_string())?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// Where the new heads come from.
+enum Heads {
+    Polled,
+    Subscribed(async_channel::Receiver<u64>),
+}
+
+impl Heads {
+    /// Subscribes to the new heads of the node at the url, polling them if it fails.
+    async fn connect(url: Option<String>) -> Self {
+        let url = match url {
+            Some(url) => url,
+            None => return Self::Polled,
+        };
+
+        let provider = match ProviderBuilder::new().on_builtin(&url).await {
+            Ok(provider) => provider,
+            Err(err) => {
+                warn!("Failed to connect to `{}`, polling the heads: {}", url, err);
+                return Self::Polled;
+            }
+        };
+        let subscription = match provider.subscribe_blocks().await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                warn!("Failed to subscribe to new heads, polling them: {}", err);
+                return Self::Polled;
+            }
+        };
+
+        let (heads_tx, heads_rx) = async_channel::unbounded();
+        tokio::spawn(async move {
+            // keeps the connection open for as long as the subscription
+            let _provider = provider;
+            let mut subscription = subscription;
+            loop {
+                match subscription.recv().await {
+                    Ok(block) => {
+                        if let Some(number) = block.header.number {
+                            if heads_tx.send(number).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("New heads subscription ended, polling the heads: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+        info!("Subscribed to the new heads of `{}`", url);
+
+        Self::Subscribed(heads_rx)
+    }
+
+    /// Waits for the next head.  Returns `None` if the head couldn't be retrieved.
+    async fn next<T, P>(&mut self, provider: &P, poll_interval: Duration) -> Option<u64>
+    where
+        T: alloy::transports::Transport + Clone,
+        P: Provider<T>,
+    {
+        match self {
+            Self::Subscribed(heads) => {
+                match tokio::time::timeout(poll_interval, heads.recv()).await {
+                    Ok(Ok(head)) => return Some(head),
+                    // no head within the interval, polled below in case one was missed
+                    Err(_) => {}
+                    Ok(Err(_)) => *self = Self::Polled,
+                }
+            }
+            Self::Polled => tokio::time::sleep(poll_interval).await,
+        }
+
+        match provider.get_block_number().await {
+            Ok(head) => Some(head),
+            Err(err) => {
+                warn!("Failed to retrieve the head: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Follows the tip of the chain, indexing every new block once it has the configured number of
+/// confirmations.  Resumes right after the block saved in the checkpoint if there is one, or
+/// starts from `start` (or from the current head, if not given) otherwise.
+///
+/// Runs until a SIGTERM or SIGINT is received.  A range failing to be indexed is logged, and
+/// retried from the first block which wasn't published on the next head.
+pub async fn follow_chain(
+    start: Option<u64>,
+    checkpoint_path: &Path,
+    mut publisher: output::publish::StreamPublisher,
+    metrics: Option<Metrics>,
+) -> io::Result<()> {
+    // a failed block is retried from the next head on, rather than published as a dead letter
+    publisher.dead_letters = None;
+
+    let confirmations = count_from_env(FOLLOW_CONFIRMATIONS_ENVKEY, DEFAULT_FOLLOW_CONFIRMATIONS);
+    let poll_interval = Duration::from_secs(count_from_env(
+        FOLLOW_POLL_INTERVAL_ENVKEY,
+        DEFAULT_FOLLOW_POLL_INTERVAL,
+    ));
+    let batch_size = count_from_env(FOLLOW_BATCH_SIZE_ENVKEY, DEFAULT_FOLLOW_BATCH_SIZE).max(1);
+
+    // stops the range in progress at the blocks in flight, each of them moving the checkpoint
+    let shutdown = handle_shutdown_signals();
+
+    #[allow(clippy::clone_on_copy)]
+    let provider = build_provider_with_metrics(metrics.clone()).await;
+    let catalog = Some(ErcEventCatalog::default());
+    let mut heads = Heads::connect(dotenvy::var(FOLLOW_NEW_HEADS_URL_ENVKEY).ok()).await;
+
+    let checkpoint = Checkpoint::new(checkpoint_path);
+    let mut next = match checkpoint.load()? {
+        Some(last_published) => {
+            info!(
+                "Resuming after block #{} from the checkpoint `{}`",
+                last_published,
+                checkpoint_path.display()
+            );
+            last_published + 1
+        }
+        None => match start {
+            Some(start) => start,
+            None => loop {
+                if let Some(head) = heads.next(&provider, poll_interval).await {
+                    break head.saturating_sub(confirmations);
+                }
+            },
+        },
+    };
+    info!(
+        "Following the chain from block #{}, {} blocks behind the head",
+        next, confirmations
+    );
+
+    // carried over from range to range, so that a reorg across two ranges is still detected
+    let mut chain = ChainTracker::new(continuity_window());
+
+    while !shutdown.is_cancelled() {
+        let head = match heads.next(&provider, poll_interval).await {
+            Some(head) => head,
+            None => continue,
+        };
+        let target = match head.checked_sub(confirmations) {
+            Some(target) if target >= next => target,
+            _ => continue,
+        };
+
+        while next <= target && !shutdown.is_cancelled() {
+            let (start, end) = (next, target.min(next.saturating_add(batch_size - 1)));
+            info!(
+                "Indexing blocks [{},{}], the head being #{}",
+                start, end, head
+            );
+
+            // only a block right after the last one published moves the checkpoint, so neither
+            // a block republished after a reorg nor a block following a failed one do
+            let mut save_err = None;
+            #[allow(clippy::clone_on_copy)]
+            let result = extract_transform_range_on_chain(
+                SimpleIndexingRequest { start, end }.into(),
+                publisher.clone(),
+                metrics.clone(),
+                provider.clone(),
+                catalog.clone(),
+                &mut chain,
+                |block_number, published| {
+                    if published && block_number == next && save_err.is_none() {
+                        match checkpoint.save(block_number) {
+                            Ok(_) => next += 1,
+                            Err(err) => save_err = Some(err),
+                        }
+                    }
+                },
+            )
+            .await;
+
+            if let Some(err) = save_err {
+                error!("Failed to save the checkpoint: {}", err);
+                return Err(err);
+            }
+            // an interrupted range is resumed on restart instead
+            if let Err(errors) = result {
+                if shutdown.is_cancelled() {
+                    break;
+                }
+                error!(
+                    "Failed to index {} blocks of [{},{}], retrying from block #{}",
+                    errors.len(),
+                    start,
+                    end,
+                    next
+                );
+                break;
+            }
+        }
+    }
+
+    info!(
+        "Received a shutdown signal. Shutting down, resuming from block #{} on restart...",
+        next
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_round_trips() {
+        let path = std::env::temp_dir().join(format!("follow_checkpoint_{}", std::process::id()));
+        let checkpoint = Checkpoint::new(&path);
+
+        assert_eq!(checkpoint.load().unwrap(), None);
+        checkpoint.save(41).unwrap();
+        checkpoint.save(42).unwrap();
+        assert_eq!(checkpoint.load().unwrap(), Some(42));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! Keeps the lease on the Pub/Sub message of the range being indexed, so that a range taking
+//! longer than the ack deadline of the subscription isn't redelivered to another indexer in the
+//! meantime (duplicating its records).
+//!
+//! The ack deadline is extended by `ACK_DEADLINE_EXTENSION` seconds right away, then every half
+//! of that, for as long as the range makes progress.  Once no block is indexed (published or
+//! failed) for `LEASE_STALL_TIMEOUT` seconds, the range is given up for its message to be nacked.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use tokio::time::MissedTickBehavior;
+
+use super::count_from_env;
+
+pub const ACK_DEADLINE_EXTENSION_ENVKEY: &str = "ACK_DEADLINE_EXTENSION";
+pub const LEASE_STALL_TIMEOUT_ENVKEY: &str = "LEASE_STALL_TIMEOUT";
+
+/// The number of seconds the ack deadline is extended by when `ACK_DEADLINE_EXTENSION` is not set
+pub const DEFAULT_ACK_DEADLINE_EXTENSION: u64 = 60;
+/// The number of seconds without progress before a range is given up, when
+/// `LEASE_STALL_TIMEOUT` is not set
+pub const DEFAULT_LEASE_STALL_TIMEOUT: u64 = 600;
+
+/// The bounds of an ack deadline accepted by Pub/Sub, in seconds
+const MIN_ACK_DEADLINE: u64 = 10;
+const MAX_ACK_DEADLINE: u64 = 600;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LeaseConfig {
+    /// How long the ack deadline is extended by
+    extension: Duration,
+    /// How long the range may go without progress
+    stall_timeout: Duration,
+}
+
+impl LeaseConfig {
+    pub fn from_env() -> Self {
+        let extension = count_from_env(
+            ACK_DEADLINE_EXTENSION_ENVKEY,
+            DEFAULT_ACK_DEADLINE_EXTENSION,
+        )
+        .clamp(MIN_ACK_DEADLINE, MAX_ACK_DEADLINE);
+
+        Self {
+            extension: Duration::from_secs(extension),
+            stall_timeout: Duration::from_secs(count_from_env(
+                LEASE_STALL_TIMEOUT_ENVKEY,
+                DEFAULT_LEASE_STALL_TIMEOUT,
+            )),
+        }
+    }
+
+    pub fn stall_timeout(&self) -> Duration {
+        self.stall_timeout
+    }
+}
+
+/// When the range last made progress.
+#[derive(Debug)]
+pub struct Progress {
+    last: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Records that a block was indexed.
+    pub fn tick(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn stalled_for(&self) -> Duration {
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outcome of a range indexed under a lease.
+pub enum Leased<R> {
+    Done(R),
+    /// The range made no progress for the stall timeout, and was given up
+    Stalled,
+}
+
+/// Indexes the range, extending the ack deadline of its message with `extend` (given the new
+/// deadline in seconds) until it's done, unless it stalls.
+pub async fn keep_leased<R, F, Fut, E>(
+    config: &LeaseConfig,
+    progress: &Progress,
+    extend: F,
+    range: impl Future<Output = R>,
+) -> Leased<R>
+where
+    F: Fn(i32) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Debug,
+{
+    let keeper = async {
+        let mut renewals = tokio::time::interval(config.extension / 2);
+        renewals.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            // the first tick completes right away, extending the deadline of the subscription
+            renewals.tick().await;
+            if progress.stalled_for() >= config.stall_timeout {
+                return;
+            }
+            match extend(config.extension.as_secs() as i32).await {
+                Ok(_) => debug!("Extended the ack deadline by {:?}", config.extension),
+                Err(err) => warn!("Failed to extend the ack deadline: {:?}", err),
+            }
+        }
+    };
+
+    tokio::select! {
+        result = range => Leased::Done(result),
+        _ = keeper => Leased::Stalled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn extends_the_deadline_until_the_range_stalls() {
+        let config = LeaseConfig {
+            extension: Duration::from_millis(20),
+            stall_timeout: Duration::from_millis(60),
+        };
+        let extensions = AtomicUsize::new(0);
+        let extend = |_| async {
+            extensions.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, ()>(())
+        };
+
+        // a block every 10ms for 100ms, so longer than the stall timeout in total
+        let progress = Progress::new();
+        let range = async {
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                progress.tick();
+            }
+            "done"
+        };
+        match keep_leased(&config, &progress, extend, range).await {
+            Leased::Done(result) => assert_eq!(result, "done"),
+            Leased::Stalled => panic!("the range was making progress"),
+        }
+        assert!(extensions.load(Ordering::Relaxed) >= 2);
+
+        let progress = Progress::new();
+        let range = std::future::pending::<()>();
+        assert!(matches!(
+            keep_leased(&config, &progress, extend, range).await,
+            Leased::Stalled
+        ));
+    }
+}
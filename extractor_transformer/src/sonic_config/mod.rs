@@ -1,12 +1,7 @@
 // TODO: this file will contain the high-level logic (glue).
 //  e.g. main() will call the function in this file for the indexing logic as well as the data extraction and record outputting
 
-use std::{
-    env::{self, VarError},
-    fs::File,
-    path::Path,
-    time::Duration,
-};
+use std::{collections::HashMap, env::VarError, fs::File, path::Path, str::FromStr};
 
 #[cfg(feature = "JSONL")]
 pub mod test;
@@ -15,29 +10,47 @@ use crate::metrics::Metrics;
 
 use super::output;
 
+mod archive;
+mod continuity;
+mod dead_letters;
 mod extraction;
+mod follow;
+#[cfg(feature = "ORCHESTRATED")]
+mod lease;
+pub mod pool;
 pub mod proto_codegen;
 mod proto_support;
+pub mod ratelimit;
+pub mod rpc_cache;
+mod saved;
+mod shutdown;
 pub mod streampublisher;
 mod transformation;
 
 use alloy::{
+    primitives::Address,
     providers::{Provider, ProviderBuilder, RootProvider},
-    transports::{
-        http::{Client, Http},
-        RpcError, TransportErrorKind,
-    },
+    rpc::client::RpcClient,
+    transports::{BoxTransport, RpcError, Transport, TransportErrorKind},
 };
-use extraction::EvmDebugExtractor;
+use continuity::{continuity_window, ensure_continuity, ChainTracker};
+use extraction::{
+    DebugTraces, EvmDebugExtractor, EvmExtracted, EvmExtraction, StateDiffs, TokenMetadata,
+};
+pub use follow::follow_chain;
+use futures::{stream, StreamExt};
 use log::{debug, error, info, warn};
-use tokio::time::sleep;
+use pool::{PoolConfig, ProviderPool};
+use rpc_cache::{RpcCache, RpcCacheConfig};
+pub use saved::transform_saved;
 use transformation::{
     bq::integer::TryIntoInteger,
     common::{
         set_event_count, transform_block, transform_logs_and_events, transform_receipts,
-        transform_traces, transform_transactions,
+        transform_state_diffs, transform_tokens, transform_traces, transform_transactions,
     },
-    err::TransformationErr,
+    contracts::transform_contracts,
+    err::{TransformationErr, TransformationErrCode},
     events::{ErcEventCatalog, EventCatalog},
 };
 
@@ -45,78 +58,75 @@ use proto_codegen::etl::request::IndexingRequest;
 
 pub const PROVIDER_URL_ENVKEY: &str = "PROVIDER_URL";
 pub const FALLBACK_PROVIDER_URL_ENVKEY: &str = "FALLBACK_PROVIDER_URL";
-pub const EXTRACT_N_RETRY_ENVKEY: &str = "EXTRACTION_N_RETRY";
-pub const EXTRACT_RETRY_COOLDOWN_ENVKEY: &str = "EXTRACTION_RETRY_COOLDOWN";
+pub const EXTRACT_WORKERS_ENVKEY: &str = "EXTRACTION_WORKERS";
+
+pub const PIPELINE_QUEUE_SIZE_ENVKEY: &str = "PIPELINE_QUEUE_SIZE";
+
+/// The number of blocks extracted concurrently when `EXTRACTION_WORKERS` is not set
+pub const DEFAULT_EXTRACT_WORKERS: usize = 4;
+/// The capacity of each queue between pipeline stages when `PIPELINE_QUEUE_SIZE` is not set
+pub const DEFAULT_PIPELINE_QUEUE_SIZE: usize = 8;
+/// How long a pull of the subscription is waited for before pulling again
+#[cfg(feature = "ORCHESTRATED")]
+const PULL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
 
 /// This function creates a pubsub subscription to create requests for what tx versions we
 /// want to index.
+///
+/// Runs until a SIGTERM or SIGINT is received, nacking the message of the range in progress if
+/// it couldn't be drained in time (see [shutdown]).
 #[cfg(feature = "ORCHESTRATED")]
 pub async fn subscribe_and_extract(
     pubsub_subscription: google_cloud_pubsub::subscription::Subscription,
     publisher: output::publish::StreamPublisher,
     metrics: Option<Metrics>,
-) -> Result<(), Vec<(u64, ExtractTransformErr)>> {
-    use std::sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    };
-
+) {
     use log::warn;
     use prost::Message;
-    use tokio::signal::unix::{signal, SignalKind};
 
     info!("Starting the indexer...");
 
-    // shared between the terminator thread for signal handling, and the main subscriber thread.
-    let terminated = Arc::new(AtomicBool::new(false));
+    let shutdown = shutdown::handle_shutdown_signals();
 
-    let mut provider = Some(build_provider());
+    #[allow(clippy::clone_on_copy)]
+    let provider = build_provider_with_metrics(metrics.clone()).await;
 
     let catalog = Some(ErcEventCatalog::default());
-
-    // spawns a thread that just listens for a SIGTERM signal, and sets a shutdown flag upon receiving it.
-    let terminator = terminated.clone();
-    tokio::spawn(async move {
-        let mut sigterm =
-            signal(SignalKind::terminate()).expect("Failed to set up SIGTERM handler");
-        let mut sigint = signal(SignalKind::interrupt()).expect("Failed to set up SIGINT handler");
-
-        // hangs until receiving the signal.
-        //sigterm.recv().await;
-        tokio::select! {
-            _ = sigterm.recv() => {
-                warn!("SIGTERM received, shutting down gracefully...");
-            },
-            _ = sigint.recv() => {
-                warn!("SIGINT received, shutting down gracefully...");
-            },
-        }
-
-        // set terminated flag to true to indicate a shutdown should occur.
-        // uses release ordering because this is the writer thread.
-        terminator.store(true, Ordering::Release);
-    });
-
-    // continually pulls pub/sub messages to determine which ranges to index.
-    // uses acquire ordering for reader thread
-    // TODO: add a timer to this .await and continue to the next iteration. this ensures that if there is no message in the pub/sub subscription, then this instance can still be shutdown (otherwise this .await will hang)
-
-    while !terminated.load(Ordering::Acquire) {
-        let message = match pubsub_subscription.pull(1, None).await {
-            Ok(mut o) => match o.pop() {
+    let lease_config = lease::LeaseConfig::from_env();
+
+    // kept across the messages, so that the first block of a range is checked against the last
+    // block of the previous one when the ranges follow each other
+    let mut chain = ChainTracker::new(continuity_window());
+
+    // continually pulls pub/sub messages to determine which ranges to index, until shutting down.
+    // the pull is given up after a while, in case it hangs (e.g. on a broken connection).
+    loop {
+        let pulled = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            pulled = tokio::time::timeout(PULL_TIMEOUT, pubsub_subscription.pull(1, None)) => pulled,
+        };
+        let message = match pulled {
+            Ok(Ok(mut o)) => match o.pop() {
                 None => {
                     warn!("Didn't receive a message from the subscription. Retrying...");
                     continue;
                 }
                 Some(m) => m,
             },
-            Err(e) => {
+            Ok(Err(e)) => {
                 warn!(
                     "Could not pull a pub/sub message from the subscription: {:?}. Retrying...",
                     e
                 );
                 continue;
             }
+            Err(_) => {
+                warn!(
+                    "Pulling a pub/sub message timed out after {:?}. Retrying...",
+                    PULL_TIMEOUT
+                );
+                continue;
+            }
         };
 
         info!("Got Message: {:?}", message.message);
@@ -128,30 +138,75 @@ pub async fn subscribe_and_extract(
                 .expect("pub/sub message uses the IndexingRange protobuf format")
         };
 
-        match extract_transform_range(
+        #[allow(clippy::clone_on_copy)]
+        let range_metrics = metrics.clone();
+
+        // the ack deadline of the message is extended for as long as its range makes progress
+        let progress = lease::Progress::new();
+        let range = extract_transform_range_on_chain(
             cur_request,
             publisher.clone(),
-            metrics,
+            range_metrics,
             provider.clone(),
             catalog.clone(),
+            &mut chain,
+            |_, _| progress.tick(),
+        );
+        let leased = lease::keep_leased(
+            &lease_config,
+            &progress,
+            |seconds| message.modify_ack_deadline(seconds),
+            range,
         )
-        .await
-        {
-            Err(extract_error) => {
-                match build_active_provider().await {
-                    Ok(new_provider) => provider = Some(new_provider),
-                    Err(e) => error!("Failed to build new provider: {:?}", e),
-                }
+        .await;
+        let result = match leased {
+            lease::Leased::Done(result) => result,
+            lease::Leased::Stalled => {
+                match message.nack().await {
+                    Ok(_) => error!(
+                        "Nacked the message, as no block of its range was indexed for {:?}",
+                        lease_config.stall_timeout()
+                    ),
+                    Err(status) => {
+                        error!("Nack returned a status: {:?}", status);
+                    }
+                };
+                continue;
+            }
+        };
 
+        match result {
+            Ok(_) => info!("Extraction and transformation succeeded"),
+            // shutting down: the blocks published are published again along with the rest of
+            // the range, as a message can't be acked partially
+            Err(errors)
+                if errors
+                    .iter()
+                    .any(|(_, err)| matches!(err, ExtractTransformErr::Interrupted)) =>
+            {
+                match message.nack().await {
+                    Ok(_) => warn!("Nacked the message of the range interrupted by the shutdown"),
+                    Err(status) => {
+                        error!("Nack returned a status: {:?}", status);
+                    }
+                };
+                break;
+            }
+            // the failed blocks were published as dead letters, so the range is still acked
+            Err(errors) if publisher.dead_letters.is_some() => warn!(
+                "{} blocks of the range failed and were published as dead letters",
+                errors.len()
+            ),
+            Err(_) => {
+                // the message is redelivered later, while the indexer keeps serving the others
                 match message.nack().await {
                     Ok(_) => error!("Nacked the message due to extraction or transformation error"),
                     Err(status) => {
                         error!("Nack returned a status: {:?}", status);
                     }
                 };
-                return Err(extract_error);
+                continue;
             }
-            Ok(_) => info!("Extraction and transformation succeeded"),
         }
 
         // ack the message to prevent the message from being re-delivered.
@@ -165,135 +220,460 @@ pub async fn subscribe_and_extract(
     }
 
     info!("Received a shutdown signal. Shutting down...");
-
-    Ok(())
 }
 
 type EventCatalogType = ErcEventCatalog;
 
-pub fn build_provider(
-) -> RootProvider<alloy::transports::http::Http<alloy::transports::http::Client>> {
-    sleep(Duration::from_secs(3));
-    match env::var(PROVIDER_URL_ENVKEY) {
-        Ok(raw_url) => {
-            let rpc_url: alloy::transports::http::reqwest::Url = match raw_url.parse() {
-                Ok(url) => url,
-                Err(err) => panic!(
-                    "Failed to parse url from env given key `{}`: {}",
-                    PROVIDER_URL_ENVKEY, err
-                ),
-            };
+pub async fn build_provider() -> RootProvider<BoxTransport> {
+    build_provider_with_metrics(None).await
+}
 
-            ProviderBuilder::new().on_http(rpc_url.clone())
-        }
-        Err(env::VarError::NotPresent) => {
-            panic!("Missing `{}` envkey for provider", PROVIDER_URL_ENVKEY)
-        }
-        Err(env::VarError::NotUnicode(badstr)) => panic!(
-            "Failed to decode env variable `{}`: {:?}",
-            PROVIDER_URL_ENVKEY, badstr
-        ),
-    }
+/// Builds a provider over the pool of endpoints configured in the environment, see [pool].  Each
+/// endpoint may be an `http(s)://` or `ws(s)://` url, or the path to an IPC socket.  The responses
+/// are cached on disk if `RPC_CACHE_DIR` is set, see [rpc_cache].  The optional methods supported
+/// by the node are probed the first time a provider is built.
+pub async fn build_provider_with_metrics(metrics: Option<Metrics>) -> RootProvider<BoxTransport> {
+    let pool = match ProviderPool::connect(&pool::endpoint_urls(), PoolConfig::from_env(), metrics)
+        .await
+    {
+        Ok(pool) => pool,
+        Err(err) => panic!("Failed to connect to any provider of the pool: {}", err),
+    };
+
+    let transport = match RpcCacheConfig::from_env() {
+        Some(config) => match RpcCache::open(pool, config) {
+            Ok(cache) => cache.boxed(),
+            Err(err) => panic!("Failed to open the RPC cache: {}", err),
+        },
+        None => pool.boxed(),
+    };
+
+    let provider = ProviderBuilder::new().on_client(RpcClient::new(transport, false));
+    extraction::probe_capabilities(&provider).await;
+    provider
 }
 
-pub fn build_provider_fallback(
-) -> RootProvider<alloy::transports::http::Http<alloy::transports::http::Client>> {
-    sleep(Duration::from_secs(3));
-    match env::var(FALLBACK_PROVIDER_URL_ENVKEY) {
-        Ok(raw_url) => {
-            let rpc_url: alloy::transports::http::reqwest::Url = match raw_url.parse() {
-                Ok(url) => url,
-                Err(err) => panic!(
-                    "Failed to parse url from env given key `{}`: {}",
-                    PROVIDER_URL_ENVKEY, err
-                ),
-            };
+pub fn build_catalog() -> EventCatalogType {
+    ErcEventCatalog::default()
+}
 
-            ProviderBuilder::new().on_http(rpc_url.clone())
+/// Reads a strictly positive count from the envkey, falling back to `default` if missing or invalid.
+fn positive_count_from_env(envkey: &str, default: usize) -> usize {
+    match std::env::var(envkey) {
+        Ok(count) => match count.parse::<usize>() {
+            Ok(0) => {
+                error!(
+                    "Envkey `{}` must be at least 1, (fallback to {})",
+                    envkey, default
+                );
+                default
+            }
+            Ok(count) => count,
+            Err(err) => {
+                error!(
+                    "Failed to parse count from envkey `{}`, (fallback to {}): {}",
+                    envkey, default, err
+                );
+                default
+            }
+        },
+        Err(VarError::NotPresent) => default,
+        Err(VarError::NotUnicode(badstr)) => {
+            error!(
+                "Failed to parse count from envkey `{}`, (fallback to {}): bad string '{:?}'",
+                envkey, default, badstr
+            );
+            default
         }
-        Err(env::VarError::NotPresent) => {
-            panic!("Missing `{}` envkey for provider", PROVIDER_URL_ENVKEY)
+    }
+}
+
+/// Reads a count from the envkey, falling back to `default` if missing or invalid.
+fn count_from_env(envkey: &str, default: u64) -> u64 {
+    match std::env::var(envkey) {
+        Ok(count) => match count.parse::<u64>() {
+            Ok(count) => count,
+            Err(err) => {
+                error!(
+                    "Failed to parse count from envkey `{}`, (fallback to {}): {}",
+                    envkey, default, err
+                );
+                default
+            }
+        },
+        Err(VarError::NotPresent) => default,
+        Err(VarError::NotUnicode(badstr)) => {
+            error!(
+                "Failed to parse count from envkey `{}`, (fallback to {}): bad string '{:?}'",
+                envkey, default, badstr
+            );
+            default
         }
-        Err(env::VarError::NotUnicode(badstr)) => panic!(
-            "Failed to decode env variable `{}`: {:?}",
-            PROVIDER_URL_ENVKEY, badstr
-        ),
     }
 }
 
-pub async fn build_active_provider() -> Result<
-    RootProvider<alloy::transports::http::Http<alloy::transports::http::Client>>,
-    (RpcError<TransportErrorKind>, RpcError<TransportErrorKind>),
-> {
-    let primary = build_provider();
-
-    if let Err(err1) = primary.get_client_version().await {
-        warn!("Primary node failed: {}", err1);
-        let secondary = build_provider_fallback();
-        if let Err(err2) = secondary.get_client_version().await {
-            warn!("Secondary node failed: {}", err2);
-            Err((err1, err2))
-        } else {
-            Ok(secondary)
+/// Reads a comma separated list of `method=value` pairs from the envkey, e.g. to override a
+/// setting for some RPC methods.  Invalid pairs are skipped.
+fn method_overrides_from_env<V: FromStr>(envkey: &str) -> HashMap<String, V> {
+    match std::env::var(envkey) {
+        Ok(overrides) => parse_method_overrides(envkey, &overrides),
+        Err(VarError::NotPresent) => HashMap::new(),
+        Err(VarError::NotUnicode(badstr)) => {
+            error!(
+                "Failed to parse overrides from envkey `{}`, (ignored): bad string '{:?}'",
+                envkey, badstr
+            );
+            HashMap::new()
         }
-    } else {
-        Ok(primary)
     }
 }
 
-pub fn build_catalog() -> EventCatalogType {
-    ErcEventCatalog::default()
+fn parse_method_overrides<V: FromStr>(envkey: &str, overrides: &str) -> HashMap<String, V> {
+    overrides
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            match pair
+                .split_once('=')
+                .and_then(|(method, value)| Some((method.trim(), value.trim().parse().ok()?)))
+            {
+                Some((method, value)) => Some((method.to_string(), value)),
+                None => {
+                    error!(
+                        "Invalid override `{}` in envkey `{}` (ignored), expected `method=value`",
+                        pair, envkey
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns the number of blocks to extract concurrently, read from the `EXTRACTION_WORKERS`
+/// envkey.  Falls back to [DEFAULT_EXTRACT_WORKERS] if missing or invalid.
+pub fn extraction_workers() -> usize {
+    positive_count_from_env(EXTRACT_WORKERS_ENVKEY, DEFAULT_EXTRACT_WORKERS)
+}
+
+/// Returns the capacity of the queues between the pipeline stages, read from the
+/// `PIPELINE_QUEUE_SIZE` envkey.  Falls back to [DEFAULT_PIPELINE_QUEUE_SIZE] if missing or invalid.
+pub fn pipeline_queue_size() -> usize {
+    positive_count_from_env(PIPELINE_QUEUE_SIZE_ENVKEY, DEFAULT_PIPELINE_QUEUE_SIZE)
+}
+
+/// The queues connecting the stages of [extract_transform_range].
+#[derive(Debug, Clone, Copy)]
+enum PipelineQueue {
+    /// Blocks extracted, waiting to be transformed
+    Extracted,
+    /// Blocks transformed, waiting to be published
+    Transformed,
+}
+
+/// Reports the number of blocks currently waiting in a queue of the pipeline.  A queue that
+/// stays full points at the stage consuming it as the bottleneck.
+#[allow(unused_variables)]
+fn report_queue_depth(metrics: &Option<Metrics>, queue: PipelineQueue, depth: usize) {
+    debug!("{:?} queue depth: {}", queue, depth);
+
+    #[cfg(feature = "METRICS")]
+    if let Some(metrics) = metrics {
+        let gauge = match queue {
+            PipelineQueue::Extracted => &metrics.extracted_queue_depth,
+            PipelineQueue::Transformed => &metrics.transformed_queue_depth,
+        };
+        gauge.set(depth as i64);
+    }
 }
 
-pub async fn extract_transform_range(
+/// Extracts, transforms and publishes every block in the request.
+///
+/// The work is split into three stages connected by bounded queues of [pipeline_queue_size]
+/// blocks, so a slow stage applies backpressure to the ones before it instead of stalling them:
+/// - extraction, with up to [extraction_workers] blocks requested from the node concurrently
+/// - transformation
+/// - publishing, always in ascending block order
+///
+/// The blocks failing to be extracted, transformed or published are returned, after publishing
+/// their dead letters when `QUEUE_NAME_DEAD_LETTERS` is set.  Once shutting down, no new block is started,
+/// and the first one left is returned as [ExtractTransformErr::Interrupted].
+pub async fn extract_transform_range<T, P>(
     request: IndexingRequest,
     publisher: output::publish::StreamPublisher,
     metrics: Option<Metrics>,
-    provider: Option<RootProvider<Http<Client>>>,
+    provider: P,
+    catalog: Option<ErcEventCatalog>,
+) -> Result<(), Vec<(u64, ExtractTransformErr)>>
+where
+    T: Transport + Clone,
+    P: Provider<T> + Clone,
+{
+    let mut chain = ChainTracker::new(continuity_window());
+    extract_transform_range_on_chain(
+        request,
+        publisher,
+        metrics,
+        provider,
+        catalog,
+        &mut chain,
+        |_, _| {},
+    )
+    .await
+}
+
+/// [extract_transform_range], checking the continuity of the blocks against the ones already
+/// recorded by the [ChainTracker] (e.g. by the previous ranges indexed by this process), and
+/// calling `on_indexed` with the number of every block done with, and whether it was published.
+async fn extract_transform_range_on_chain<T, P>(
+    mut request: IndexingRequest,
+    publisher: output::publish::StreamPublisher,
+    metrics: Option<Metrics>,
+    provider: P,
     catalog: Option<ErcEventCatalog>,
-) -> Result<(), Vec<(u64, ExtractTransformErr)>> {
+    chain: &mut ChainTracker,
+    mut on_indexed: impl FnMut(u64, bool),
+) -> Result<(), Vec<(u64, ExtractTransformErr)>>
+where
+    T: Transport + Clone,
+    P: Provider<T> + Clone,
+{
+    let n_workers = extraction_workers();
+    let queue_size = pipeline_queue_size();
+
+    // replaying the transactions is the expensive part, so it is skipped when the state diffs
+    // would have nowhere to be published
+    if request.state_diffs() && publisher.state_diffs.is_none() {
+        error!(
+            "State diffs of blocks [{},{}] were requested, but `{}` is not set (skipping them)",
+            request.start,
+            request.end,
+            streampublisher::QUEUE_NAME_STATE_DIFFS
+        );
+        request.state_diffs = Some(false);
+    }
+
     info!(
-        "Extracting & Transforming blocks [{},{})",
-        request.start, request.end
+        "Extracting & Transforming blocks [{},{}] with {} workers",
+        request.start, request.end, n_workers
     );
 
-    let mut errors = Vec::new();
-
     let catalog = catalog.unwrap_or_default();
+    let metrics = &metrics;
+    let archive = archive::raw_archive().await;
+    let shutdown = shutdown::shutdown_token();
+
+    // the extractions of the published blocks are only kept for the dead letter of a failed publish
+    let keep_extractions = publisher.dead_letters.is_some();
+
+    let (extracted_tx, extracted_rx) = async_channel::bounded(queue_size);
+    let (transformed_tx, transformed_rx) = async_channel::bounded(queue_size);
+
+    let extraction_stage = {
+        let request = request.clone();
+        let provider = provider.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            // `buffered` yields the extractions in the same order as the block numbers, so even
+            // though several blocks are in flight at once, the next stages receive them in order.
+            // Once shutting down, no block is started anymore, but the ones in flight still are.
+            let mut extractions = stream::iter(request.start..=request.end)
+                .take_until(Box::pin(shutdown.cancelled_owned()))
+                .map(|block_number| {
+                    // `Metrics` is `()` (thus `Copy`) without the METRICS feature
+                    #[allow(clippy::clone_on_copy)]
+                    let metrics = metrics.clone();
+                    let request = request.clone();
+                    let provider = provider.clone();
+                    async move {
+                        debug!("Extracting block #{}", block_number);
+                        let extracted =
+                            extract(block_number, metrics, Some(request), provider).await;
+                        (block_number, extracted)
+                    }
+                })
+                .buffered(n_workers);
+
+            let mut next = request.start;
+            while let Some(extracted) = extractions.next().await {
+                next = extracted.0 + 1;
+                // waits here while the queue is full, pausing the extraction
+                if extracted_tx.send(extracted).await.is_err() {
+                    return;
+                }
+                report_queue_depth(metrics, PipelineQueue::Extracted, extracted_tx.len());
+            }
 
-    for block_number in request.start..=request.end {
-        let et_results = extract_transform(
-            block_number,
-            metrics.clone(),
-            Some(request.clone()),
-            provider.clone(),
-            catalog.clone(),
-        )
-        .await;
+            // the blocks left unstarted fail the range, so that it is indexed again
+            if next <= request.end {
+                let _ = extracted_tx
+                    .send((next, Err(ExtractTransformErr::Interrupted)))
+                    .await;
+            }
+        }
+    };
 
-        match et_results {
-            Ok(perblock) => {
-                debug!("Completed extract_transform block #{}", block_number);
-                match publish_perblock_records(perblock, &publisher).await {
-                    Ok(_) => info!(
-                        "Extracted, Transformed, and Published for block #{}",
-                        block_number
-                    ),
-                    Err(_) => error!(
-                        "Failed to to publish after successful extract_transform for block #{}",
-                        block_number
-                    ),
+    let transformation_stage = {
+        let request = request.clone();
+        async move {
+            // the blocks arrive in order, so each one can be checked against the one before it
+            'blocks: while let Ok((block_number, extracted)) = extracted_rx.recv().await {
+                report_queue_depth(metrics, PipelineQueue::Extracted, extracted_rx.len());
+
+                let checked = match extracted {
+                    Ok(extraction) => {
+                        ensure_continuity(
+                            block_number,
+                            extraction,
+                            chain,
+                            &request,
+                            metrics,
+                            &provider,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
+
+                let (replacements, extraction) = match checked {
+                    Ok(checked) => checked,
+                    Err(err) => {
+                        if transformed_tx
+                            .send((block_number, Err((err, None))))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // the blocks replacing orphaned ones go out first, along with their reorg record
+                let blocks = replacements
+                    .into_iter()
+                    .map(|(replacement, reorg)| (replacement, Some(reorg)))
+                    .chain(std::iter::once((extraction, None)));
+                for (extraction, reorg) in blocks {
+                    let number = extraction.basic.block_number();
+
+                    // archived before its records are published, so that every block published
+                    // can be transformed again from the archive
+                    let archived = match archive {
+                        Some(archive) => archive
+                            .archive(&extraction)
+                            .await
+                            .map_err(ExtractTransformErr::Archive),
+                        None => Ok(()),
+                    };
+
+                    let transformed = match archived {
+                        Ok(_) => {
+                            debug!("Transforming block #{}", number);
+                            transform(number, Some(request.clone()), &extraction, catalog.clone())
+                                .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    // a failed block keeps its extraction, for its dead letter
+                    let transformed = match transformed {
+                        Ok(mut records) => {
+                            records.reorgs = reorg.map(|reorg| vec![reorg]);
+                            Ok((records, keep_extractions.then_some(extraction)))
+                        }
+                        Err(err) => Err((err, Some(extraction))),
+                    };
+
+                    if transformed_tx.send((number, transformed)).await.is_err() {
+                        break 'blocks;
+                    }
+                    report_queue_depth(metrics, PipelineQueue::Transformed, transformed_tx.len());
                 }
             }
-            Err(err) => {
-                error!(
-                    "Failed to extract_transform block #{}: {:?}",
-                    block_number, err
-                );
-                errors.push((block_number, err));
+        }
+    };
+
+    let end = request.end;
+    let publishing_stage = async move {
+        let mut errors = Vec::new();
+
+        while let Ok((block_number, et_results)) = transformed_rx.recv().await {
+            report_queue_depth(metrics, PipelineQueue::Transformed, transformed_rx.len());
+
+            let (err, extraction) = match et_results {
+                Ok((mut perblock, extraction)) => {
+                    debug!("Completed extract_transform block #{}", block_number);
+                    let new_tokens = perblock.unseen_tokens();
+                    match publish_perblock_records(perblock, &publisher).await {
+                        Ok(_) => {
+                            info!(
+                                "Extracted, Transformed, and Published for block #{}",
+                                block_number
+                            );
+                            extraction::mark_tokens_seen(&new_tokens);
+                            on_indexed(block_number, true);
+                            continue;
+                        }
+                        Err(_) => {
+                            error!(
+                                "Failed to to publish after successful extract_transform for block #{}",
+                                block_number
+                            );
+                            (ExtractTransformErr::Publish, extraction)
+                        }
+                    }
+                }
+                Err((ExtractTransformErr::Interrupted, _)) => {
+                    warn!(
+                        "Shutting down, leaving blocks [{},{}] to be indexed again",
+                        block_number, end
+                    );
+                    errors.push((block_number, ExtractTransformErr::Interrupted));
+                    continue;
+                }
+                Err((err, extraction)) => {
+                    error!(
+                        "Failed to extract_transform block #{}: {:?}",
+                        block_number, err
+                    );
+                    (err, extraction)
+                }
+            };
+
+            if let Some(dead_letters) = &publisher.dead_letters {
+                dead_letters::publish_dead_letter(
+                    dead_letters,
+                    block_number,
+                    &err,
+                    extraction.as_ref(),
+                )
+                .await;
+                warn!("Published the dead letter of block #{}", block_number);
             }
+            errors.push((block_number, err));
+            on_indexed(block_number, false);
         }
-    }
+
+        errors
+    };
+
+    // each stage drops its end of the queues when done, which in turn ends the following stage.
+    let pipeline = async {
+        let (_, _, errors) = tokio::join!(extraction_stage, transformation_stage, publishing_stage);
+        errors
+    };
+    let shutdown_timeout = shutdown::shutdown_timeout();
+    let errors = tokio::select! {
+        errors = pipeline => errors,
+        _ = shutdown::drain_deadline(&shutdown, shutdown_timeout) => {
+            error!(
+                "The blocks in flight weren't published within {:?} of shutting down, leaving [{},{}] to be indexed again",
+                shutdown_timeout, request.start, request.end
+            );
+            vec![(request.start, ExtractTransformErr::Interrupted)]
+        }
+    };
 
     if !errors.is_empty() {
         Err(errors)
@@ -311,116 +691,150 @@ pub struct PerBlockRecords {
     transactions: Option<Vec<proto_codegen::etl::transactions::Transaction>>,
     events: Option<Vec<proto_codegen::etl::decoded_events::DecodedEvent>>,
     traces: Option<Vec<proto_codegen::etl::traces::Trace>>,
+    state_diffs: Option<Vec<proto_codegen::etl::state_diffs::StateDiff>>,
+    contracts: Option<Vec<proto_codegen::etl::contracts::Contract>>,
+    tokens: Option<Vec<proto_codegen::etl::tokens::Token>>,
+    token_transfers: Option<Vec<proto_codegen::etl::token_transfers::TokenTransfer>>,
+    native_transfers: Option<Vec<proto_codegen::etl::native_transfers::NativeTransfer>>,
+    reorgs: Option<Vec<proto_codegen::etl::reorgs::Reorg>>,
+}
+
+impl PerBlockRecords {
+    /// Drops the tokens published since their block was extracted (e.g. by a block extracted
+    /// alongside it), and returns the addresses of the others, to be marked as seen once the block
+    /// is published.
+    fn unseen_tokens(&mut self) -> Vec<Address> {
+        let Some(tokens) = self.tokens.as_mut() else {
+            return Vec::new();
+        };
+
+        let mut unseen = Vec::new();
+        tokens.retain(|token| match Address::from_str(&token.address) {
+            Ok(address) if extraction::is_seen_token(&address) || unseen.contains(&address) => {
+                false
+            }
+            Ok(address) => {
+                unseen.push(address);
+                true
+            }
+            Err(_) => true,
+        });
+        unseen
+    }
+}
+
+/// The raw data extracted from the node for a single block, before any transformation.
+#[derive(Debug, Clone)]
+pub struct BlockExtraction {
+    basic: EvmExtracted,
+    debug: DebugTraces,
+    state: Option<StateDiffs>,
+    tokens: Option<Vec<TokenMetadata>>,
 }
 
 /// The primary function for indexing, requests data and creates records to be sent to publishers based on the Sonic response.
-pub async fn extract_transform<C: EventCatalog>(
+pub async fn extract_transform<T, P, C>(
     block_number: u64,
     metrics: Option<Metrics>,
     request: Option<IndexingRequest>,
-    provider: Option<RootProvider<Http<Client>>>,
+    provider: P,
     catalog: C,
-) -> Result<PerBlockRecords, ExtractTransformErr> {
+) -> Result<PerBlockRecords, ExtractTransformErr>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+    C: EventCatalog,
+{
     info!("Extracting & Transforming block #{}", block_number);
 
-    let request = request.unwrap_or_default();
+    let extraction = extract(block_number, metrics, request.clone(), provider).await?;
+    transform(block_number, request, &extraction, catalog).await
+}
 
-    let provider = match provider {
-        Some(provider) => provider,
-        None => build_provider(),
-    };
+/// Requests all the data needed to transform a block from the node.
+pub async fn extract<T, P>(
+    block_number: u64,
+    metrics: Option<Metrics>,
+    request: Option<IndexingRequest>,
+    provider: P,
+) -> Result<BlockExtraction, ExtractTransformErr>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let request = request.unwrap_or_default();
 
     let extractor = extraction::EthExtractor::new_with_metrics(provider, metrics);
 
-    let n_retry: usize = match std::env::var(EXTRACT_N_RETRY_ENVKEY) {
-        Ok(n_rety) => match n_rety.parse() {
-            Ok(n_retry) => n_retry,
-            Err(err) => {
-                error!(
-                    "Failed to parse n_retry from envkey `{}`, (fallback to 1): {}  ",
-                    EXTRACT_N_RETRY_ENVKEY, err
-                );
-                1
-            }
-        },
-        Err(VarError::NotPresent) => 1,
-        Err(VarError::NotUnicode(badstr)) => {
-            error!(
-                "Failed to parse n_retry from envkey `{}`, (fallback to 1): bad string '{:?}'  ",
-                EXTRACT_N_RETRY_ENVKEY, badstr
-            );
-            1
-        }
+    let (basic, debug, state) = match extractor
+        .extract_batched(block_number, Some(request.clone()))
+        .await?
+    {
+        Some(extracted) => extracted,
+        None => return Err(ExtractTransformErr::ExtractorReturnedNone),
     };
 
-    let cooldown: usize = match std::env::var(EXTRACT_RETRY_COOLDOWN_ENVKEY) {
-        Ok(cooldown) => match cooldown.parse() {
-            Ok(cooldown) => cooldown,
-            Err(err) => {
-                error!(
-                    "Failed to parse retry_cooldown from envkey `{}`, (fallback to 5): {}  ",
-                    EXTRACT_RETRY_COOLDOWN_ENVKEY, err
-                );
-                5
-            }
-        },
-        Err(VarError::NotPresent) => 1,
-        Err(VarError::NotUnicode(badstr)) => {
-            error!(
-                "Failed to parse cooldown from envkey `{}`, (fallback to 5): bad string '{:?}'  ",
-                EXTRACT_RETRY_COOLDOWN_ENVKEY, badstr
-            );
-            5
-        }
+    let tokens = match basic.logs() {
+        Some(logs) if request.tokens() => Some(extractor.extract_tokens(block_number, logs).await?),
+        _ => None,
     };
 
-    // Perform the extraction
+    Ok(BlockExtraction {
+        basic,
+        debug,
+        state,
+        tokens,
+    })
+}
+
+/// Transforms the extracted data of a block into the records requested.
+pub async fn transform<C: EventCatalog>(
+    block_number: u64,
+    request: Option<IndexingRequest>,
+    extraction: &BlockExtraction,
+    catalog: C,
+) -> Result<PerBlockRecords, ExtractTransformErr> {
+    let request = request.unwrap_or_default();
+
+    let BlockExtraction {
+        basic: basic_extraction,
+        debug: debug_extraction,
+        state: state_extraction,
+        tokens: token_extraction,
+    } = extraction;
 
     let mut records = PerBlockRecords {
         block_number,
         ..PerBlockRecords::default()
     };
 
-    // =============================================================================================
-    // Extraction
-    // =============================================================================================
-    let basic_extraction = match extractor
-        .extract_basic(block_number, Some(request.clone()), n_retry, cooldown)
-        .await?
-    {
-        Some(basic) => basic,
-        None => return Err(ExtractTransformErr::ExtractorReturnedNone),
-    };
-    let debug_extraction = match extractor.extract_debug(block_number).await? {
-        Some(debug) => debug,
-        None => return Err(ExtractTransformErr::ExtractorReturnedNone),
-    };
-
     // =============================================================================================
     // Blocks (w/o decoded events count) & Transactions
     // =============================================================================================
 
     if request.blocks {
-        records.block = Some(transform_block(&basic_extraction)?);
+        records.block = Some(transform_block(basic_extraction)?);
     }
 
     if request.transactions {
-        records.transactions = Some(transform_transactions(&basic_extraction)?);
+        records.transactions = Some(transform_transactions(basic_extraction)?);
     }
 
     // =============================================================================================
     // Traces
     // =============================================================================================
 
-    if request.traces | request.blocks | request.transactions {
-        let (traces, block_trace_cnt, per_tx_trace_cnt) = transform_traces(
-            &debug_extraction,
+    if request.traces | request.blocks | request.transactions | request.native_transfers() {
+        let (traces, native_transfers, block_trace_cnt, per_tx_trace_cnt) = transform_traces(
+            debug_extraction,
             request.traces,
             request.blocks,
             request.transactions,
+            request.native_transfers(),
         )
         .await?;
         records.traces = traces;
+        records.native_transfers = native_transfers;
 
         if request.blocks {
             if let Some(block) = &mut records.block {
@@ -428,15 +842,17 @@ pub async fn extract_transform<C: EventCatalog>(
                     block.trace_count = match trace_count.try_into_integer() {
                         Ok(casted) => casted,
                         Err(err) => {
-                            return Err(TransformationErr::new(err.to_string(), None).into())
+                            return Err(TransformationErr::out_of_range("trace_count", err).into())
                         }
                     };
                 }
             } else {
-                return Err(ExtractTransformErr::Transformation(TransformationErr::new(
+                return Err(TransformationErr::with_code(
+                    TransformationErrCode::MissingField,
                     "Missing block trace count after transform_traces".to_string(),
-                    None,
-                )));
+                    Some("trace_count".to_string()),
+                )
+                .into());
             }
         }
 
@@ -453,34 +869,51 @@ pub async fn extract_transform<C: EventCatalog>(
         }
     }
 
+    // =============================================================================================
+    // Contracts
+    // =============================================================================================
+
+    if request.contracts() {
+        records.contracts = Some(transform_contracts(debug_extraction)?);
+    }
+
     // =============================================================================================
     // Logs & Events (+ Block log/event counts)
     // =============================================================================================
 
     // If filling the decoded events, logs, or block tables, we need to review logs and events and
     // blocks
-    if request.decoded_events | request.logs | request.blocks {
-        let (maybe_log_records, maybe_event_records, decode_count) = transform_logs_and_events(
-            &basic_extraction,
-            request.logs,
-            request.decoded_events,
-            Some(catalog),
-        )?;
+    if request.decoded_events | request.logs | request.blocks | request.token_transfers() {
+        let (maybe_log_records, maybe_event_records, maybe_token_transfer_records, decode_count) =
+            transform_logs_and_events(
+                basic_extraction,
+                request.logs,
+                request.decoded_events,
+                request.token_transfers(),
+                Some(catalog),
+            )?;
 
         if request.logs {
             if maybe_log_records.is_none() {
-                panic!("Panic")
+                return Err(TransformationErr::missing("logs").into());
             }
             records.logs = maybe_log_records;
         }
 
         if request.decoded_events {
             if maybe_event_records.is_none() {
-                panic!("Panic");
+                return Err(TransformationErr::missing("events").into());
             }
             records.events = maybe_event_records;
         }
 
+        if request.token_transfers() {
+            if maybe_token_transfer_records.is_none() {
+                return Err(TransformationErr::missing("token_transfers").into());
+            }
+            records.token_transfers = maybe_token_transfer_records;
+        }
+
         if let Some(block) = records.block {
             records.block = Some(set_event_count(block, decode_count));
         }
@@ -491,10 +924,41 @@ pub async fn extract_transform<C: EventCatalog>(
     // =============================================================================================
 
     if request.receipts {
-        let receipts = transform_receipts(&basic_extraction).await?;
+        let receipts = transform_receipts(basic_extraction).await?;
         records.receipts = Some(receipts);
     }
 
+    // =============================================================================================
+    // Tokens
+    // =============================================================================================
+
+    if request.tokens() {
+        records.tokens = Some(transform_tokens(
+            basic_extraction,
+            token_extraction.as_deref().unwrap_or_default(),
+        )?);
+    }
+
+    // =============================================================================================
+    // State Diffs
+    // =============================================================================================
+
+    if request.state_diffs() {
+        match state_extraction {
+            Some(state_extraction) => {
+                records.state_diffs = Some(transform_state_diffs(state_extraction)?);
+            }
+            None => {
+                return Err(TransformationErr::with_code(
+                    TransformationErrCode::MissingField,
+                    "Missing state diffs extraction".to_string(),
+                    None,
+                )
+                .into())
+            }
+        }
+    }
+
     Ok(records)
 }
 
@@ -570,30 +1034,152 @@ pub async fn publish_perblock_records(
         )
         .await;
     }
+
+    if let Some(contracts) = perblock.contracts {
+        let timestamps = contracts
+            .iter()
+            .map(|contract| contract.block_timestamp)
+            .collect::<Vec<_>>();
+        publish_records(
+            &publisher.contracts,
+            contracts,
+            Some(&format!("{}", perblock.block_number)),
+            timestamps,
+        )
+        .await;
+    }
+
+    if let Some(tokens) = perblock.tokens {
+        let timestamps = tokens
+            .iter()
+            .map(|token| token.block_timestamp)
+            .collect::<Vec<_>>();
+        publish_records(
+            &publisher.tokens,
+            tokens,
+            Some(&format!("{}", perblock.block_number)),
+            timestamps,
+        )
+        .await;
+    }
+
+    if let Some(token_transfers) = perblock.token_transfers {
+        let timestamps = token_transfers
+            .iter()
+            .map(|token_transfer| token_transfer.block_timestamp)
+            .collect::<Vec<_>>();
+        publish_records(
+            &publisher.token_transfers,
+            token_transfers,
+            Some(&format!("{}", perblock.block_number)),
+            timestamps,
+        )
+        .await;
+    }
+
+    if let Some(native_transfers) = perblock.native_transfers {
+        let timestamps = native_transfers
+            .iter()
+            .map(|native_transfer| native_transfer.block_timestamp)
+            .collect::<Vec<_>>();
+        publish_records(
+            &publisher.native_transfers,
+            native_transfers,
+            Some(&format!("{}", perblock.block_number)),
+            timestamps,
+        )
+        .await;
+    }
+
+    if let Some(reorgs) = perblock.reorgs {
+        for reorg in reorgs.iter() {
+            warn!(
+                "Republishing block #{} as {}, replacing the orphaned {}",
+                reorg.block_number, reorg.block_hash, reorg.orphaned_block_hash
+            );
+        }
+        if let Some(reorgs_publisher) = &publisher.reorgs {
+            let timestamps = reorgs
+                .iter()
+                .map(|reorg| reorg.block_timestamp)
+                .collect::<Vec<_>>();
+            publish_records(
+                reorgs_publisher,
+                reorgs,
+                Some(&format!("{}", perblock.block_number)),
+                timestamps,
+            )
+            .await;
+        }
+    }
+
+    if let Some(state_diffs) = perblock.state_diffs {
+        match &publisher.state_diffs {
+            Some(state_diffs_publisher) => {
+                let timestamps = state_diffs
+                    .iter()
+                    .map(|state_diff| state_diff.block_timestamp)
+                    .collect::<Vec<_>>();
+                publish_records(
+                    state_diffs_publisher,
+                    state_diffs,
+                    Some(&format!("{}", perblock.block_number)),
+                    timestamps,
+                )
+                .await;
+            }
+            None => {
+                error!(
+                    "State diffs of block #{} were requested, but `{}` is not set",
+                    perblock.block_number,
+                    streampublisher::QUEUE_NAME_STATE_DIFFS
+                );
+                return Err(());
+            }
+        }
+    }
     Ok(())
 }
 
 #[cfg(feature = "JSONL")]
-pub async fn save_range(
+pub async fn save_range<T, P>(
     request: IndexingRequest,
     _metrics: Option<Metrics>,
-    provider: Option<RootProvider<Http<Client>>>,
+    provider: P,
     dirpath: &Path,
-) -> Result<(), Option<RpcError<TransportErrorKind>>> {
+) -> Result<(), Option<RpcError<TransportErrorKind>>>
+where
+    T: Transport + Clone,
+    P: Provider<T> + Clone,
+{
     for block_number in request.start..=request.end {
         save_block(block_number, provider.clone(), dirpath).await?;
     }
     Ok(())
 }
 
-pub async fn save_block(
+pub async fn save_block<T, P>(
     block_number: u64,
-    provider: Option<RootProvider<Http<Client>>>,
+    provider: P,
     dirpath: &Path,
-) -> Result<(), Option<RpcError<TransportErrorKind>>> {
-    let extractor = extraction::EthExtractor::new(provider.unwrap_or(build_provider()));
+) -> Result<(), Option<RpcError<TransportErrorKind>>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let extractor = extraction::EthExtractor::new(provider);
 
-    let basic = match extractor.extract_basic(block_number, None, 5, 5).await? {
+    let extracted = extractor
+        .extract_basic(block_number, None)
+        .await
+        .map_err(|err| match err {
+            ExtractTransformErr::Rpc(err) => Some(err),
+            other => {
+                error!("Failed to extract block #{}: {}", block_number, other);
+                None
+            }
+        })?;
+    let basic = match extracted {
         Some(basic) => basic,
         None => panic!("Failed to extract the block to save"),
     };
@@ -618,8 +1204,77 @@ pub async fn save_block(
 #[derive(Debug)]
 pub enum ExtractTransformErr {
     ExtractorReturnedNone,
+    /// The block doesn't chain up with the blocks indexed before it, and couldn't be linked back
+    /// up by extracting the orphaned blocks again.
+    Discontinuity(u64),
     Rpc(RpcError<TransportErrorKind>),
     Transformation(TransformationErr),
+    /// A saved extraction couldn't be read back.
+    Load(std::io::Error),
+    /// The raw extraction couldn't be written to the archive.
+    Archive(std::io::Error),
+    /// The indexer shut down before the block was indexed.
+    Interrupted,
+    /// The records of the block couldn't be published.
+    Publish,
+}
+
+impl ExtractTransformErr {
+    /// The name of the kind of error, as recorded in the dead letters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ExtractorReturnedNone => "extractor_returned_none",
+            Self::Discontinuity(_) => "discontinuity",
+            Self::Rpc(_) => "rpc",
+            Self::Transformation(_) => "transformation",
+            Self::Load(_) => "load",
+            Self::Archive(_) => "archive",
+            Self::Interrupted => "interrupted",
+            Self::Publish => "publish",
+        }
+    }
+
+    /// The code of the error, as recorded in the dead letters: the code of the transformation
+    /// error, or the kind of the others.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Transformation(err) => err.code().as_str(),
+            other => other.kind(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExtractTransformErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExtractorReturnedNone => write!(f, "The extractor returned nothing"),
+            Self::Discontinuity(block_number) => write!(
+                f,
+                "Block #{} doesn't chain up with the blocks indexed before it",
+                block_number
+            ),
+            Self::Rpc(err) => write!(f, "{}", err),
+            Self::Transformation(err) => write!(f, "{}", err),
+            Self::Load(err) => write!(f, "Failed to load the saved extraction: {}", err),
+            Self::Archive(err) => write!(f, "Failed to archive the raw extraction: {}", err),
+            Self::Interrupted => write!(f, "The indexer shut down before indexing the block"),
+            Self::Publish => write!(f, "Failed to publish the records of the block"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractTransformErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ExtractorReturnedNone
+            | Self::Discontinuity(_)
+            | Self::Interrupted
+            | Self::Publish => None,
+            Self::Rpc(err) => Some(err),
+            Self::Transformation(err) => Some(err),
+            Self::Load(err) | Self::Archive(err) => Some(err),
+        }
+    }
 }
 
 impl From<TransformationErr> for ExtractTransformErr {
@@ -635,6 +1290,13 @@ impl From<RpcError<TransportErrorKind>> for ExtractTransformErr {
     }
 }
 
+impl From<std::io::Error> for ExtractTransformErr {
+    #[inline]
+    fn from(value: std::io::Error) -> Self {
+        Self::Load(value)
+    }
+}
+
 /// Publishes the records
 #[allow(unused_variables)]
 async fn publish_records<T>(
@@ -0,0 +1,495 @@
+//! A pool of RPC endpoints behind a single transport.  Every call is routed to the healthiest
+//! endpoint, scored on its latency and error rate, and falls over to the next one when the
+//! endpoint cannot be reached.  A background task follows the head of each endpoint, ejecting
+//! the ones that fail or fall behind, and re-admitting them once they have recovered.  The calls
+//! to each endpoint can be rate limited (see [ratelimit](super::ratelimit)).
+
+use std::{
+    env::{self, VarError},
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use alloy::{
+    primitives::U64,
+    rpc::{
+        client::{BuiltInConnectionString, RpcClient},
+        json_rpc::{RequestPacket, ResponsePacket},
+    },
+    transports::{BoxTransport, TransportError, TransportErrorKind, TransportFut},
+};
+use log::{error, info, warn};
+use tower::{Service, ServiceExt};
+
+use crate::metrics::Metrics;
+
+use super::{
+    positive_count_from_env,
+    ratelimit::{RateLimitConfig, TokenBucket},
+    FALLBACK_PROVIDER_URL_ENVKEY, PROVIDER_URL_ENVKEY,
+};
+
+pub const PROVIDER_URLS_ENVKEY: &str = "PROVIDER_URLS";
+pub const POOL_HEALTH_CHECK_INTERVAL_ENVKEY: &str = "POOL_HEALTH_CHECK_INTERVAL";
+pub const POOL_MAX_HEAD_LAG_ENVKEY: &str = "POOL_MAX_HEAD_LAG";
+pub const POOL_MAX_ERROR_PERCENT_ENVKEY: &str = "POOL_MAX_ERROR_PERCENT";
+pub const POOL_EJECTION_COOLDOWN_ENVKEY: &str = "POOL_EJECTION_COOLDOWN";
+
+/// Seconds between two health checks of the endpoints
+pub const DEFAULT_POOL_HEALTH_CHECK_INTERVAL: usize = 10;
+/// Number of blocks an endpoint may be behind the highest head of the pool before being ejected
+pub const DEFAULT_POOL_MAX_HEAD_LAG: usize = 20;
+/// Error rate (in percent) above which an endpoint is ejected
+pub const DEFAULT_POOL_MAX_ERROR_PERCENT: usize = 50;
+/// Minimum number of seconds an ejected endpoint stays out of the pool
+pub const DEFAULT_POOL_EJECTION_COOLDOWN: usize = 30;
+
+/// Weight of the most recent sample in the moving averages of latency and error rate
+const EWMA_WEIGHT: f64 = 0.2;
+/// Number of calls an endpoint must have served before its error rate can eject it
+const MIN_SAMPLES_FOR_EJECTION: u64 = 10;
+
+/// The tunables of a [ProviderPool], read from the environment.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub health_check_interval: Duration,
+    pub max_head_lag: u64,
+    pub max_error_rate: f64,
+    pub ejection_cooldown: Duration,
+    pub rate_limits: RateLimitConfig,
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        Self {
+            health_check_interval: Duration::from_secs(positive_count_from_env(
+                POOL_HEALTH_CHECK_INTERVAL_ENVKEY,
+                DEFAULT_POOL_HEALTH_CHECK_INTERVAL,
+            ) as u64),
+            max_head_lag: positive_count_from_env(
+                POOL_MAX_HEAD_LAG_ENVKEY,
+                DEFAULT_POOL_MAX_HEAD_LAG,
+            ) as u64,
+            max_error_rate: positive_count_from_env(
+                POOL_MAX_ERROR_PERCENT_ENVKEY,
+                DEFAULT_POOL_MAX_ERROR_PERCENT,
+            )
+            .min(100) as f64
+                / 100.0,
+            ejection_cooldown: Duration::from_secs(positive_count_from_env(
+                POOL_EJECTION_COOLDOWN_ENVKEY,
+                DEFAULT_POOL_EJECTION_COOLDOWN,
+            ) as u64),
+            rate_limits: RateLimitConfig::from_env(),
+        }
+    }
+}
+
+/// Returns the urls of the endpoints in the pool, from the comma separated `PROVIDER_URLS`
+/// envkey, or else from `PROVIDER_URL` and (if set) `FALLBACK_PROVIDER_URL`.
+pub fn endpoint_urls() -> Vec<String> {
+    let from_env = |envkey: &str| match env::var(envkey) {
+        Ok(value) => Some(value),
+        Err(VarError::NotPresent) => None,
+        Err(VarError::NotUnicode(badstr)) => {
+            panic!("Failed to decode env variable `{}`: {:?}", envkey, badstr)
+        }
+    };
+
+    if let Some(urls) = from_env(PROVIDER_URLS_ENVKEY) {
+        let urls: Vec<String> = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+        if urls.is_empty() {
+            panic!("Envkey `{}` does not list any url", PROVIDER_URLS_ENVKEY);
+        }
+        return urls;
+    }
+
+    match from_env(PROVIDER_URL_ENVKEY) {
+        Some(primary) => std::iter::once(primary)
+            .chain(from_env(FALLBACK_PROVIDER_URL_ENVKEY))
+            .collect(),
+        None => panic!(
+            "Missing `{}` or `{}` envkey for provider",
+            PROVIDER_URLS_ENVKEY, PROVIDER_URL_ENVKEY
+        ),
+    }
+}
+
+/// What the pool knows about the health of an endpoint.
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    /// moving average of the latency of the successful calls, in seconds
+    latency: f64,
+    /// moving average of the failure rate of the calls, within [0, 1]
+    error_rate: f64,
+    /// number of calls served (or failed) by the endpoint
+    samples: u64,
+    /// the most recent block number reported by the endpoint
+    head: Option<u64>,
+    /// number of blocks the endpoint is behind the highest head of the pool
+    head_lag: u64,
+    /// when the endpoint was ejected from the pool, if it currently is
+    ejected_at: Option<Instant>,
+}
+
+impl EndpointHealth {
+    fn record(&mut self, outcome: Result<Duration, ()>) {
+        let weight = if self.samples == 0 { 1.0 } else { EWMA_WEIGHT };
+        match outcome {
+            Ok(latency) => {
+                self.latency = (1.0 - weight) * self.latency + weight * latency.as_secs_f64();
+                self.error_rate *= 1.0 - weight;
+            }
+            Err(_) => self.error_rate = (1.0 - weight) * self.error_rate + weight,
+        }
+        self.samples += 1;
+    }
+
+    /// Lower is healthier.  Errors weigh heavily, since a failed call costs a whole round trip
+    /// before falling over to the next endpoint.
+    fn score(&self) -> f64 {
+        self.latency * (1.0 + 10.0 * self.error_rate) + 0.01 * self.head_lag as f64
+    }
+
+    #[inline]
+    fn is_ejected(&self) -> bool {
+        self.ejected_at.is_some()
+    }
+}
+
+struct Endpoint {
+    /// the position of the endpoint in the configuration, used to name it in logs & metrics
+    /// since the url itself may carry credentials.
+    index: usize,
+    transport: BoxTransport,
+    client: RpcClient<BoxTransport>,
+    health: Mutex<EndpointHealth>,
+    /// the rate limit of the calls to the endpoint, if it has one
+    limiter: Option<TokenBucket>,
+}
+
+struct PoolInner {
+    endpoints: Vec<Endpoint>,
+    config: PoolConfig,
+    #[cfg_attr(not(feature = "METRICS"), allow(dead_code))]
+    metrics: Option<Metrics>,
+}
+
+/// A transport spreading the calls over several endpoints.  See the [module](self) documentation.
+#[derive(Clone)]
+pub struct ProviderPool {
+    inner: Arc<PoolInner>,
+}
+
+impl std::fmt::Debug for ProviderPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProviderPool")
+            .field("endpoints", &self.inner.endpoints.len())
+            .field("config", &self.inner.config)
+            .finish()
+    }
+}
+
+impl ProviderPool {
+    /// Connects to every url (http(s), ws(s), or IPC path), and starts following their health.
+    /// Urls that cannot be connected to are left out of the pool, which fails only if none can.
+    pub async fn connect(
+        urls: &[String],
+        config: PoolConfig,
+        metrics: Option<Metrics>,
+    ) -> Result<Self, TransportError> {
+        let mut endpoints = Vec::with_capacity(urls.len());
+        let mut last_err = None;
+
+        for (index, url) in urls.iter().enumerate() {
+            let connected = match url.parse::<BuiltInConnectionString>() {
+                Ok(connection) => connection.connect_boxed().await,
+                Err(err) => Err(err),
+            };
+
+            match connected {
+                Ok(transport) => {
+                    info!("Connected to endpoint #{} of the provider pool", index);
+                    endpoints.push(Endpoint {
+                        index,
+                        client: RpcClient::new(transport.clone(), false),
+                        transport,
+                        health: Mutex::new(EndpointHealth::default()),
+                        limiter: config.rate_limits.bucket(index),
+                    });
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to connect to endpoint #{} of the provider pool: {}",
+                        index, err
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        if endpoints.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                TransportErrorKind::custom_str("No endpoint given to the provider pool")
+            }));
+        }
+
+        let inner = Arc::new(PoolInner {
+            endpoints,
+            config,
+            metrics,
+        });
+
+        tokio::spawn(health_check_loop(Arc::downgrade(&inner)));
+
+        Ok(Self { inner })
+    }
+
+    /// The indices (within the pool) of the endpoints, healthiest first.  Ejected endpoints are
+    /// only listed last, as a last resort.
+    fn ranked(&self) -> Vec<usize> {
+        let mut ranked: Vec<(bool, f64, usize)> = self
+            .inner
+            .endpoints
+            .iter()
+            .enumerate()
+            .map(|(i, endpoint)| {
+                let health = endpoint.health.lock().expect("poisoned endpoint health");
+                (health.is_ejected(), health.score(), i)
+            })
+            .collect();
+        ranked.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        ranked.into_iter().map(|(_, _, i)| i).collect()
+    }
+
+    /// Sends the request to the healthiest endpoint, falling over to the next one if the
+    /// endpoint cannot be reached.  JSON-RPC errors are returned as is, since they come from
+    /// the request rather than the endpoint.  Waits for the rate limit of the endpoint first.
+    async fn dispatch(self, request: RequestPacket) -> Result<ResponsePacket, TransportError> {
+        let mut last_err = None;
+        let weight = self.inner.config.rate_limits.weight(&request);
+
+        for i in self.ranked() {
+            let endpoint = &self.inner.endpoints[i];
+            let mut transport = endpoint.transport.clone();
+
+            if let Some(limiter) = &endpoint.limiter {
+                let waited = limiter.acquire(weight).await;
+                self.report_throttle(i, waited);
+            }
+
+            let started = Instant::now();
+            let result = match transport.ready().await {
+                Ok(transport) => transport.call(request.clone()).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(response) => {
+                    self.record(i, Ok(started.elapsed()));
+                    return Ok(response);
+                }
+                Err(err) => {
+                    warn!(
+                        "Endpoint #{} of the provider pool failed, falling over: {}",
+                        endpoint.index, err
+                    );
+                    self.record(i, Err(()));
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.expect("the pool has at least one endpoint"))
+    }
+
+    /// Records the outcome of a call to an endpoint, ejecting it if it now fails too often.
+    fn record(&self, i: usize, outcome: Result<Duration, ()>) {
+        let endpoint = &self.inner.endpoints[i];
+        {
+            let mut health = endpoint.health.lock().expect("poisoned endpoint health");
+            health.record(outcome);
+
+            if !health.is_ejected()
+                && health.samples >= MIN_SAMPLES_FOR_EJECTION
+                && health.error_rate > self.inner.config.max_error_rate
+            {
+                warn!(
+                    "Ejecting endpoint #{} from the provider pool, error rate at {:.0}%",
+                    endpoint.index,
+                    health.error_rate * 100.0
+                );
+                health.ejected_at = Some(Instant::now());
+            }
+        }
+        self.report(i);
+    }
+
+    /// Exports the health of an endpoint as metrics.
+    #[allow(unused_variables)]
+    fn report(&self, i: usize) {
+        #[cfg(feature = "METRICS")]
+        if let Some(metrics) = &self.inner.metrics {
+            let endpoint = &self.inner.endpoints[i];
+            let label = endpoint.index.to_string();
+            let health = endpoint.health.lock().expect("poisoned endpoint health");
+
+            metrics
+                .pool_endpoint_up
+                .with_label_values(&[&label])
+                .set(!health.is_ejected() as i64);
+            metrics
+                .pool_endpoint_latency_seconds
+                .with_label_values(&[&label])
+                .set(health.latency);
+            metrics
+                .pool_endpoint_error_rate
+                .with_label_values(&[&label])
+                .set(health.error_rate);
+            metrics
+                .pool_endpoint_head_lag
+                .with_label_values(&[&label])
+                .set(health.head_lag as i64);
+        }
+    }
+
+    /// Exports the time a call waited for the rate limit of an endpoint.
+    #[allow(unused_variables)]
+    fn report_throttle(&self, i: usize, waited: Duration) {
+        #[cfg(feature = "METRICS")]
+        if let Some(metrics) = &self.inner.metrics {
+            metrics
+                .pool_endpoint_throttle_seconds
+                .with_label_values(&[&self.inner.endpoints[i].index.to_string()])
+                .inc_by(waited.as_secs_f64());
+        }
+    }
+
+    /// Asks every endpoint for its head, then ejects the ones lagging behind or failing, and
+    /// re-admits the ejected ones that have recovered.
+    async fn check_health(&self) {
+        let heads = futures::future::join_all(self.inner.endpoints.iter().map(|endpoint| {
+            let started = Instant::now();
+            async move {
+                let head = endpoint
+                    .client
+                    .request::<_, U64>("eth_blockNumber", ())
+                    .await;
+                (head, started.elapsed())
+            }
+        }))
+        .await;
+
+        let pool_head = heads
+            .iter()
+            .filter_map(|(head, _)| head.as_ref().ok())
+            .map(|head| head.to::<u64>())
+            .max();
+
+        for (i, (head, latency)) in heads.into_iter().enumerate() {
+            let endpoint = &self.inner.endpoints[i];
+            {
+                let mut health = endpoint.health.lock().expect("poisoned endpoint health");
+                let healthy = match head {
+                    Ok(head) => {
+                        let head = head.to::<u64>();
+                        health.record(Ok(latency));
+                        health.head = Some(head);
+                        health.head_lag = pool_head.unwrap_or(head).saturating_sub(head);
+                        health.head_lag <= self.inner.config.max_head_lag
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Health check of endpoint #{} of the provider pool failed: {}",
+                            endpoint.index, err
+                        );
+                        health.record(Err(()));
+                        false
+                    }
+                };
+
+                match health.ejected_at {
+                    None if !healthy => {
+                        warn!(
+                            "Ejecting endpoint #{} from the provider pool, {} blocks behind",
+                            endpoint.index, health.head_lag
+                        );
+                        health.ejected_at = Some(Instant::now());
+                    }
+                    Some(ejected_at)
+                        if healthy
+                            && ejected_at.elapsed() >= self.inner.config.ejection_cooldown =>
+                    {
+                        info!(
+                            "Re-admitting endpoint #{} to the provider pool",
+                            endpoint.index
+                        );
+                        health.ejected_at = None;
+                        health.error_rate = 0.0;
+                        health.samples = 0;
+                    }
+                    _ => (),
+                }
+            }
+            self.report(i);
+        }
+    }
+}
+
+/// Periodically checks the health of the pool's endpoints, for as long as the pool is in use.
+async fn health_check_loop(pool: Weak<PoolInner>) {
+    loop {
+        let interval = match pool.upgrade() {
+            Some(inner) => {
+                let pool = ProviderPool { inner };
+                pool.check_health().await;
+                pool.inner.config.health_check_interval
+            }
+            None => return,
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+impl Service<RequestPacket> for ProviderPool {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // readiness is checked on the endpoint picked for each call
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        Box::pin(self.clone().dispatch(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_outweigh_latency() {
+        let mut fast_but_failing = EndpointHealth::default();
+        let mut slow_but_reliable = EndpointHealth::default();
+
+        for _ in 0..5 {
+            fast_but_failing.record(Ok(Duration::from_millis(20)));
+            fast_but_failing.record(Err(()));
+            slow_but_reliable.record(Ok(Duration::from_millis(100)));
+        }
+
+        assert!(slow_but_reliable.score() < fast_but_failing.score());
+        assert_eq!(slow_but_reliable.error_rate, 0.0);
+    }
+}
@@ -10,19 +10,13 @@ impl TryFrom<AlloyWithdrawal> for ProtoWithdrawal {
             index: match value.index.try_into_integer() {
                 Ok(num) => num,
                 Err(err) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("index".to_string()),
-                    ));
+                    return Err(TransformationErr::out_of_range("index", err));
                 }
             },
             validator_index: match value.validator_index.try_into_integer() {
                 Ok(num) => num,
                 Err(err) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("validator_index".to_string()),
-                    ));
+                    return Err(TransformationErr::out_of_range("validator_index", err));
                 }
             },
             address: format!("{}", value.address.0),
@@ -0,0 +1,189 @@
+//! Client-side rate limiting of the calls sent to each endpoint of the provider pool.
+//!
+//! Each endpoint gets a token bucket, refilled at the rate (in calls per second) the node operator
+//! allows, and shared by every worker of the process.  Calls are weighted by their RPC method,
+//! since replaying or tracing a whole block costs the node far more than fetching it: a call takes
+//! as many tokens as its weight (a batch, the sum of its calls' weights), waiting for the bucket
+//! to refill when it runs out.
+
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use alloy::rpc::json_rpc::RequestPacket;
+use log::error;
+
+use super::{method_overrides_from_env, parse_method_overrides};
+
+pub const RATE_LIMIT_ENVKEY: &str = "RATE_LIMIT";
+pub const RATE_LIMIT_BURST_ENVKEY: &str = "RATE_LIMIT_BURST";
+pub const RATE_LIMIT_WEIGHTS_ENVKEY: &str = "RATE_LIMIT_WEIGHTS";
+
+/// The weights of the methods costing the node more than a plain call, unless overridden by
+/// `RATE_LIMIT_WEIGHTS`.  Any other method weighs 1.
+pub const DEFAULT_RATE_LIMIT_WEIGHTS: &str = "trace_block=10,debug_traceBlockByNumber=10,\
+    trace_replayBlockTransactions=20,eth_getBlockReceipts=5,eth_getLogs=2";
+
+/// A token bucket, refilled continuously up to its capacity.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    /// the tokens left (negative once reserved ahead), as of the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// A bucket refilled with `rate` tokens per second, holding `capacity` tokens at most.  The
+    /// bucket starts full.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Takes the tokens out of the bucket, returning how long the caller has to wait for them.
+    /// The tokens are reserved even if not yet there, so that the callers are served in order.
+    fn reserve(&self, weight: f64, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("poisoned token bucket");
+        let (tokens, refilled_at) = *state;
+
+        let elapsed = now.saturating_duration_since(refilled_at).as_secs_f64();
+        let tokens = (tokens + elapsed * self.rate).min(self.capacity) - weight;
+        *state = (tokens, now.max(refilled_at));
+
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    /// Waits until the bucket holds the tokens, and takes them.  Returns how long it waited.
+    pub async fn acquire(&self, weight: f64) -> Duration {
+        let wait = self.reserve(weight, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+/// The rate limits of the endpoints, read from the environment.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    /// The calls per second allowed by each endpoint, in the order of the urls (a single rate
+    /// applying to every endpoint).  No rate, or 0, leaves the endpoint unlimited.
+    pub rates: Vec<f64>,
+    /// The number of tokens a bucket holds at most, a second's worth of calls if not set.
+    pub burst: Option<f64>,
+    /// The weights of the RPC methods, 1 for any other method.
+    pub weights: HashMap<String, f64>,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        let rates = match env::var(RATE_LIMIT_ENVKEY) {
+            Ok(rates) => rates
+                .split(',')
+                .map(str::trim)
+                .map(|rate| match rate.parse::<f64>() {
+                    Ok(rate) if rate >= 0.0 => rate,
+                    _ => {
+                        error!(
+                            "Invalid rate `{}` in envkey `{}`, (fallback to unlimited)",
+                            rate, RATE_LIMIT_ENVKEY
+                        );
+                        0.0
+                    }
+                })
+                .collect(),
+            Err(VarError::NotPresent) => Vec::new(),
+            Err(VarError::NotUnicode(badstr)) => {
+                error!(
+                    "Failed to parse rates from envkey `{}`, (fallback to unlimited): bad string '{:?}'",
+                    RATE_LIMIT_ENVKEY, badstr
+                );
+                Vec::new()
+            }
+        };
+
+        let burst = match env::var(RATE_LIMIT_BURST_ENVKEY) {
+            Ok(burst) => match burst.parse::<f64>() {
+                Ok(burst) if burst > 0.0 => Some(burst),
+                _ => {
+                    error!(
+                        "Invalid burst `{}` in envkey `{}`, (fallback to the rate)",
+                        burst, RATE_LIMIT_BURST_ENVKEY
+                    );
+                    None
+                }
+            },
+            Err(VarError::NotPresent) => None,
+            Err(VarError::NotUnicode(badstr)) => {
+                error!(
+                    "Failed to parse burst from envkey `{}`, (fallback to the rate): bad string '{:?}'",
+                    RATE_LIMIT_BURST_ENVKEY, badstr
+                );
+                None
+            }
+        };
+
+        let mut weights =
+            parse_method_overrides(RATE_LIMIT_WEIGHTS_ENVKEY, DEFAULT_RATE_LIMIT_WEIGHTS);
+        weights.extend(method_overrides_from_env(RATE_LIMIT_WEIGHTS_ENVKEY));
+
+        Self {
+            rates,
+            burst,
+            weights,
+        }
+    }
+
+    /// Returns the bucket of the endpoint at the index, `None` if it isn't limited.
+    pub fn bucket(&self, index: usize) -> Option<TokenBucket> {
+        let rate = match self.rates.as_slice() {
+            [rate] => *rate,
+            rates => rates.get(index).copied().unwrap_or_default(),
+        };
+        (rate > 0.0).then(|| TokenBucket::new(rate, self.burst.unwrap_or(rate)))
+    }
+
+    /// Returns the number of tokens the request takes.
+    pub fn weight(&self, request: &RequestPacket) -> f64 {
+        let weight = |method: &str| self.weights.get(method).copied().unwrap_or(1.0);
+        match request {
+            RequestPacket::Single(request) => weight(request.method()),
+            RequestPacket::Batch(requests) => requests
+                .iter()
+                .map(|request| weight(request.method()))
+                .sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heavy_calls_wait_for_the_bucket_to_refill() {
+        let bucket = TokenBucket::new(10.0, 10.0);
+        let start = *bucket.state.lock().unwrap();
+
+        // the bucket starts full, then serves the calls in order
+        assert_eq!(bucket.reserve(4.0, start.1), Duration::ZERO);
+        assert_eq!(bucket.reserve(10.0, start.1), Duration::from_millis(400));
+        assert_eq!(bucket.reserve(1.0, start.1), Duration::from_millis(500));
+
+        // a second later, 10 tokens were added back to the 5 owed
+        let later = start.1 + Duration::from_secs(1);
+        assert_eq!(bucket.reserve(5.0, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, later), Duration::from_millis(100));
+    }
+}
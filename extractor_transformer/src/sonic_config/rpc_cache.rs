@@ -0,0 +1,508 @@
+//! An on-disk cache of the raw responses of the node, below the extractors.
+//!
+//! Blocks, logs, receipts, traces, state diffs, and calls at a given block never change once the
+//! block is final, so their responses are cached in `RPC_CACHE_DIR`, keyed by the method and
+//! params of the call, and compressed with zstd.  Only the blocks at least
+//! `RPC_CACHE_CONFIRMATIONS` blocks behind the head are cached, so that a block reorged out
+//! isn't served from the cache.  When the cache outgrows `RPC_CACHE_MAX_SIZE`, the least recently
+//! used responses are evicted.
+//!
+//! Extracting a range again (e.g. to transform it with a new schema) is then served from the disk,
+//! without a single call to the node.
+
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use alloy::{
+    primitives::{keccak256, B256, U64},
+    rpc::{
+        client::RpcClient,
+        json_rpc::{
+            Id, RequestPacket, Response, ResponsePacket, ResponsePayload, SerializedRequest,
+        },
+    },
+    transports::{BoxTransport, Transport, TransportError, TransportFut},
+};
+use log::{debug, error, info, warn};
+use serde_json::value::RawValue;
+use tower::Service;
+
+use super::{count_from_env, positive_count_from_env};
+
+pub const RPC_CACHE_DIR_ENVKEY: &str = "RPC_CACHE_DIR";
+pub const RPC_CACHE_MAX_SIZE_ENVKEY: &str = "RPC_CACHE_MAX_SIZE";
+pub const RPC_CACHE_CONFIRMATIONS_ENVKEY: &str = "RPC_CACHE_CONFIRMATIONS";
+
+/// The size of the cache in MiB when `RPC_CACHE_MAX_SIZE` is not set
+pub const DEFAULT_RPC_CACHE_MAX_SIZE: usize = 10240;
+/// The number of blocks a block must be behind the head to be cached, when
+/// `RPC_CACHE_CONFIRMATIONS` is not set
+pub const DEFAULT_RPC_CACHE_CONFIRMATIONS: u64 = 64;
+
+/// The zstd compression level of the cached responses
+const COMPRESSION_LEVEL: i32 = 3;
+/// How long the head of the node is trusted before being asked again
+const HEAD_TTL: Duration = Duration::from_secs(30);
+/// The share of the maximum size the cache is evicted down to, so that it isn't evicted again on
+/// the very next response
+const EVICTION_TARGET: f64 = 0.9;
+
+/// The tunables of an [RpcCache], read from the environment.
+#[derive(Debug, Clone)]
+pub struct RpcCacheConfig {
+    pub dir: PathBuf,
+    pub max_size: u64,
+    pub confirmations: u64,
+}
+
+impl RpcCacheConfig {
+    /// Returns the configuration of the cache, `None` if `RPC_CACHE_DIR` isn't set (which
+    /// disables it).
+    pub fn from_env() -> Option<Self> {
+        let dir = match env::var(RPC_CACHE_DIR_ENVKEY) {
+            Ok(dir) => PathBuf::from(dir),
+            Err(VarError::NotPresent) => return None,
+            Err(VarError::NotUnicode(badstr)) => {
+                error!(
+                    "Failed to parse the cache directory from envkey `{}`, (cache disabled): bad string '{:?}'",
+                    RPC_CACHE_DIR_ENVKEY, badstr
+                );
+                return None;
+            }
+        };
+
+        Some(Self {
+            dir,
+            max_size: positive_count_from_env(RPC_CACHE_MAX_SIZE_ENVKEY, DEFAULT_RPC_CACHE_MAX_SIZE)
+                as u64
+                * 1024
+                * 1024,
+            confirmations: count_from_env(
+                RPC_CACHE_CONFIRMATIONS_ENVKEY,
+                DEFAULT_RPC_CACHE_CONFIRMATIONS,
+            ),
+        })
+    }
+}
+
+/// The responses in the cache, with their size and when they were last used.
+#[derive(Debug, Default)]
+struct CacheIndex {
+    /// the size and last use of each response
+    entries: HashMap<B256, (u64, u64)>,
+    size: u64,
+    /// incremented on every use, ordering the uses
+    clock: u64,
+}
+
+impl CacheIndex {
+    /// Marks the response as used, returning whether it is in the cache.
+    fn touch(&mut self, key: &B256) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some((_, used)) => {
+                *used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds the response, returning the responses to evict (least recently used first) if the
+    /// cache is now over its maximum size.
+    fn insert(&mut self, key: B256, size: u64, max_size: u64) -> Vec<B256> {
+        self.clock += 1;
+        if let Some((previous, _)) = self.entries.insert(key, (size, self.clock)) {
+            self.size -= previous;
+        }
+        self.size += size;
+        if self.size <= max_size {
+            return Vec::new();
+        }
+
+        let mut by_use: Vec<(u64, B256)> = self
+            .entries
+            .iter()
+            .map(|(key, (_, used))| (*used, *key))
+            .collect();
+        by_use.sort_unstable();
+
+        let target = (max_size as f64 * EVICTION_TARGET) as u64;
+        let mut evicted = Vec::new();
+        for (_, key) in by_use {
+            if self.size <= target {
+                break;
+            }
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &B256) {
+        if let Some((size, _)) = self.entries.remove(key) {
+            self.size -= size;
+        }
+    }
+}
+
+struct CacheInner {
+    config: RpcCacheConfig,
+    index: Mutex<CacheIndex>,
+    /// the most recent head of the node, and when it was retrieved
+    head: Mutex<Option<(u64, Instant)>>,
+    head_client: RpcClient<BoxTransport>,
+}
+
+/// A transport answering the calls from the cache, and forwarding the others to the transport
+/// below it.  See the [module](self) documentation.
+#[derive(Clone)]
+pub struct RpcCache<T> {
+    transport: T,
+    inner: Arc<CacheInner>,
+}
+
+impl<T> std::fmt::Debug for RpcCache<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcCache")
+            .field("config", &self.inner.config)
+            .finish()
+    }
+}
+
+impl<T: Transport + Clone> RpcCache<T> {
+    /// Opens the cache in the configured directory (creating it if needed), indexing the
+    /// responses already there.
+    pub fn open(transport: T, config: RpcCacheConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+
+        // the responses are ordered by their last modification, the best guess of their last use
+        let mut cached = Vec::new();
+        for entry in std::fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            let key = match entry.file_name().to_str().and_then(entry_key) {
+                Some(key) => key,
+                // e.g. a temporary file left by an interrupted write
+                None => continue,
+            };
+            let metadata = entry.metadata()?;
+            cached.push((metadata.modified()?, key, metadata.len()));
+        }
+        cached.sort_unstable();
+
+        let mut index = CacheIndex::default();
+        for (_, key, size) in cached {
+            for evicted in index.insert(key, size, config.max_size) {
+                remove_file(&config.dir, &evicted);
+            }
+        }
+        info!(
+            "Opened the RPC cache in `{}`, holding {} responses ({} MiB)",
+            config.dir.display(),
+            index.entries.len(),
+            index.size / 1024 / 1024
+        );
+
+        Ok(Self {
+            inner: Arc::new(CacheInner {
+                config,
+                index: Mutex::new(index),
+                head: Mutex::new(None),
+                head_client: RpcClient::new(transport.clone().boxed(), false),
+            }),
+            transport,
+        })
+    }
+
+    /// Returns the cached response to the request, if there is one.
+    async fn lookup(&self, request: &SerializedRequest) -> Option<Response> {
+        let key = cache_key(request)?;
+        if !self
+            .inner
+            .index
+            .lock()
+            .expect("poisoned cache index")
+            .touch(&key)
+        {
+            return None;
+        }
+
+        let path = entry_path(&self.inner.config.dir, &key);
+        let payload = match tokio::fs::read(&path).await.and_then(|compressed| {
+            let raw = String::from_utf8(zstd::decode_all(compressed.as_slice())?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            RawValue::from_string(raw).map_err(io::Error::from)
+        }) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(
+                    "Failed to read the cached response `{}`: {}",
+                    path.display(),
+                    err
+                );
+                self.inner
+                    .index
+                    .lock()
+                    .expect("poisoned cache index")
+                    .remove(&key);
+                return None;
+            }
+        };
+
+        debug!("Answering `{}` from the cache", request.method());
+        Some(Response {
+            id: request.id().clone(),
+            payload: ResponsePayload::Success(payload),
+        })
+    }
+
+    /// Caches the successful responses to the requests which are final.
+    async fn store(&self, requests: &[SerializedRequest], responses: &ResponsePacket) {
+        let responses = match responses {
+            ResponsePacket::Single(response) => std::slice::from_ref(response),
+            ResponsePacket::Batch(responses) => responses.as_slice(),
+        };
+        let requests: HashMap<&Id, &SerializedRequest> = requests
+            .iter()
+            .map(|request| (request.id(), request))
+            .collect();
+
+        for response in responses {
+            let (request, payload) = match (requests.get(&response.id), &response.payload) {
+                (Some(request), ResponsePayload::Success(payload)) if payload.get() != "null" => {
+                    (request, payload)
+                }
+                _ => continue,
+            };
+            let (key, block_number) = match (cache_key(request), cacheable_block(request)) {
+                (Some(key), Some(block_number)) => (key, block_number),
+                _ => continue,
+            };
+            match self.head().await {
+                Some(head) if block_number + self.inner.config.confirmations <= head => (),
+                _ => continue,
+            }
+
+            if let Err(err) = self.write(&key, payload).await {
+                warn!(
+                    "Failed to cache the response to `{}`: {}",
+                    request.method(),
+                    err
+                );
+            }
+        }
+    }
+
+    async fn write(&self, key: &B256, payload: &RawValue) -> io::Result<()> {
+        let compressed = zstd::encode_all(payload.get().as_bytes(), COMPRESSION_LEVEL)?;
+
+        // written aside then renamed, so that a response is never read half written
+        let path = entry_path(&self.inner.config.dir, key);
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, &compressed).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        let evicted = self
+            .inner
+            .index
+            .lock()
+            .expect("poisoned cache index")
+            .insert(*key, compressed.len() as u64, self.inner.config.max_size);
+        if !evicted.is_empty() {
+            debug!("Evicting {} responses from the RPC cache", evicted.len());
+        }
+        for key in evicted {
+            remove_file(&self.inner.config.dir, &key);
+        }
+        Ok(())
+    }
+
+    /// Returns the head of the node, asking for it again if the last one is too old.
+    async fn head(&self) -> Option<u64> {
+        if let Some((head, retrieved_at)) = *self.inner.head.lock().expect("poisoned cache head") {
+            if retrieved_at.elapsed() < HEAD_TTL {
+                return Some(head);
+            }
+        }
+
+        match self
+            .inner
+            .head_client
+            .request::<_, U64>("eth_blockNumber", ())
+            .await
+        {
+            Ok(head) => {
+                let head = head.to::<u64>();
+                *self.inner.head.lock().expect("poisoned cache head") =
+                    Some((head, Instant::now()));
+                Some(head)
+            }
+            Err(err) => {
+                warn!("Failed to retrieve the head, not caching: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Answers what it can from the cache, and forwards the rest to the transport below.
+    async fn dispatch(self, request: RequestPacket) -> Result<ResponsePacket, TransportError> {
+        let mut transport = self.transport.clone();
+
+        match request {
+            RequestPacket::Single(request) => {
+                if let Some(response) = self.lookup(&request).await {
+                    return Ok(ResponsePacket::Single(response));
+                }
+                let responses = transport
+                    .call(RequestPacket::Single(request.clone()))
+                    .await?;
+                self.store(std::slice::from_ref(&request), &responses).await;
+                Ok(responses)
+            }
+            RequestPacket::Batch(requests) => {
+                let mut hits = Vec::new();
+                let mut misses = Vec::new();
+                for request in requests {
+                    match self.lookup(&request).await {
+                        Some(response) => hits.push(response),
+                        None => misses.push(request),
+                    }
+                }
+                if misses.is_empty() {
+                    return Ok(ResponsePacket::Batch(hits));
+                }
+
+                let responses = transport.call(RequestPacket::Batch(misses.clone())).await?;
+                self.store(&misses, &responses).await;
+                match responses {
+                    ResponsePacket::Batch(mut responses) => {
+                        responses.extend(hits);
+                        Ok(ResponsePacket::Batch(responses))
+                    }
+                    // the batch was rejected as a whole
+                    response => Ok(response),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the key of the call, `None` if its result may change (see [cacheable_block]).
+fn cache_key(request: &SerializedRequest) -> Option<B256> {
+    cacheable_block(request)?;
+    let params = request.params().map_or("", |params| params.get());
+    Some(keccak256(format!("{}{}", request.method(), params)))
+}
+
+/// Returns the block the result of the call depends on, `None` if it depends on something else
+/// (e.g. the latest block, or a block hash).
+fn cacheable_block(request: &SerializedRequest) -> Option<u64> {
+    let position = match request.method() {
+        "eth_getBlockByNumber"
+        | "eth_getBlockReceipts"
+        | "eth_getLogs"
+        | "trace_block"
+        | "trace_replayBlockTransactions"
+        | "debug_traceBlockByNumber" => 0,
+        "eth_call" => 1,
+        _ => return None,
+    };
+
+    let params: Vec<serde_json::Value> = serde_json::from_str(request.params()?.get()).ok()?;
+    let block = match params.get(position)? {
+        // a logs filter is final once its last block is
+        serde_json::Value::Object(filter) => filter.get("toBlock")?,
+        block => block,
+    };
+    u64::from_str_radix(block.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+fn entry_path(dir: &Path, key: &B256) -> PathBuf {
+    dir.join(format!("{}.zst", hex::encode(key)))
+}
+
+/// Returns the key of the cached response in the file, the inverse of [entry_path].
+fn entry_key(file_name: &str) -> Option<B256> {
+    file_name.strip_suffix(".zst")?.parse().ok()
+}
+
+fn remove_file(dir: &Path, key: &B256) {
+    if let Err(err) = std::fs::remove_file(entry_path(dir, key)) {
+        if err.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to evict a response from the RPC cache: {}", err);
+        }
+    }
+}
+
+impl<T: Transport + Clone> Service<RequestPacket> for RpcCache<T> {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.transport.poll_ready(cx)
+    }
+
+    #[inline]
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        Box::pin(self.clone().dispatch(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::rpc::json_rpc::Request;
+
+    #[test]
+    fn caches_calls_at_a_block_and_evicts_the_least_recently_used() {
+        let request = |method: &'static str, params: serde_json::Value| {
+            Request::new(method, Id::Number(1), params)
+                .serialize()
+                .unwrap()
+        };
+
+        let block = request("eth_getBlockByNumber", serde_json::json!(["0x2a", true]));
+        let logs = request(
+            "eth_getLogs",
+            serde_json::json!([{ "fromBlock": "0x2a", "toBlock": "0x2a" }]),
+        );
+        let call = request("eth_call", serde_json::json!([{ "to": "0x00" }, "0x2a"]));
+        let latest = request("eth_getBlockByNumber", serde_json::json!(["latest", true]));
+        let head = request("eth_blockNumber", serde_json::json!([]));
+
+        assert_eq!(cacheable_block(&block), Some(42));
+        assert_eq!(cacheable_block(&logs), Some(42));
+        assert_eq!(cacheable_block(&call), Some(42));
+        assert_eq!(cacheable_block(&latest), None);
+        assert_eq!(cache_key(&head), None);
+        assert_ne!(cache_key(&block), cache_key(&logs));
+        let key = cache_key(&block).unwrap();
+        assert_eq!(
+            entry_path(Path::new("cache"), &key)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(entry_key),
+            Some(key)
+        );
+
+        let key = |byte: u8| B256::repeat_byte(byte);
+        let mut index = CacheIndex::default();
+        assert!(index.insert(key(1), 40, 100).is_empty());
+        assert!(index.insert(key(2), 40, 100).is_empty());
+        assert!(index.touch(&key(1)));
+        // evicts down to 90 bytes, the least recently used first
+        assert_eq!(index.insert(key(3), 40, 100), vec![key(2)]);
+        assert_eq!(index.size, 80);
+        assert!(!index.touch(&key(2)));
+    }
+}
@@ -0,0 +1,175 @@
+//! Transforms the extractions saved by [save_block](super::save_block), without a node.
+//!
+//! A saved block is made of its basic extraction (`basic_<n>.json`) and its debug traces
+//! (`debug_<n>.json`), either as is or compressed with zstd (`.json.zst`, as in a partition of
+//! the [raw archive](super::archive)).  Neither the state diffs nor the token metadata are saved,
+//! so the `state_diffs` and `tokens` tables are left out of the records.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::Path,
+};
+
+use log::{error, info};
+
+use super::{
+    output, proto_codegen::etl::request::IndexingRequest, publish_perblock_records, transform,
+    BlockExtraction, DebugTraces, ErcEventCatalog, EvmExtracted, ExtractTransformErr,
+};
+
+/// Returns the numbers of the blocks saved in the directory, in ascending order.
+pub fn saved_block_numbers(dirpath: &Path) -> io::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dirpath)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("basic_"))
+            .and_then(|name| {
+                name.strip_suffix(".json")
+                    .or_else(|| name.strip_suffix(".json.zst"))
+            })
+            .and_then(|number| number.parse().ok());
+        if let Some(number) = number {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    numbers.dedup();
+    Ok(numbers)
+}
+
+/// Loads the saved extraction of the block.
+pub fn load_saved_block(
+    dirpath: &Path,
+    block_number: u64,
+) -> Result<BlockExtraction, ExtractTransformErr> {
+    let basic: EvmExtracted = load_json(&dirpath.join(format!("basic_{}.json", block_number)))?;
+    let debug: DebugTraces = load_json(&dirpath.join(format!("debug_{}.json", block_number)))?;
+
+    Ok(BlockExtraction {
+        basic,
+        debug,
+        state: None,
+        tokens: None,
+    })
+}
+
+/// Reads the JSON file, or its compressed `.zst` version if there is no plain one.
+fn load_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<T> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let file = File::open(path.with_extension("json.zst"))?;
+            Ok(serde_json::from_reader(zstd::Decoder::new(file)?)?)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Transforms every block saved in the directory, and publishes its records.  A block failing to
+/// be loaded, transformed or published is logged, and the others are still published.
+///
+/// Returns the blocks which failed, or the error listing the saved extractions.
+pub async fn transform_saved(
+    dirpath: &Path,
+    publisher: output::publish::StreamPublisher,
+    catalog: Option<ErcEventCatalog>,
+) -> io::Result<Vec<(u64, ExtractTransformErr)>> {
+    let numbers = saved_block_numbers(dirpath)?;
+    info!(
+        "Transforming {} blocks saved in `{}`",
+        numbers.len(),
+        dirpath.display()
+    );
+
+    let catalog = catalog.unwrap_or_default();
+    let mut errors = Vec::new();
+
+    for block_number in numbers {
+        let request = IndexingRequest {
+            start: block_number,
+            end: block_number,
+            state_diffs: Some(false),
+            tokens: Some(false),
+            ..Default::default()
+        };
+
+        let transformed = match load_saved_block(dirpath, block_number) {
+            Ok(extraction) => {
+                transform(block_number, Some(request), &extraction, catalog.clone()).await
+            }
+            Err(err) => Err(err),
+        };
+
+        match transformed {
+            Ok(perblock) => match publish_perblock_records(perblock, &publisher).await {
+                Ok(_) => info!("Transformed and Published saved block #{}", block_number),
+                Err(_) => {
+                    error!(
+                        "Failed to publish after successful transform for saved block #{}",
+                        block_number
+                    );
+                    errors.push((block_number, ExtractTransformErr::Publish));
+                }
+            },
+            Err(err) => {
+                error!(
+                    "Failed to transform saved block #{}: {:?}",
+                    block_number, err
+                );
+                errors.push((block_number, err));
+            }
+        }
+    }
+
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::primitives::FixedBytes;
+
+    #[test]
+    fn loads_the_saved_blocks_in_order() {
+        let dirpath = std::env::temp_dir().join(format!("saved_blocks_{}", std::process::id()));
+        fs::create_dir_all(&dirpath).unwrap();
+
+        for number in [12, 3] {
+            let basic = EvmExtracted {
+                block_number: number,
+                block_hash: FixedBytes::repeat_byte(number as u8),
+                parent_hash: FixedBytes::ZERO,
+                block_timestamp: 1_700_000_000,
+                block: None,
+                logs: Some(Vec::new()),
+                receipts: Some(Vec::new()),
+            };
+            let debug = DebugTraces::new(number, None, 1_700_000_000, Vec::new());
+            let basic_file = File::create(dirpath.join(format!("basic_{}.json", number))).unwrap();
+            serde_json::to_writer(basic_file, &basic).unwrap();
+            let debug_file = File::create(dirpath.join(format!("debug_{}.json", number))).unwrap();
+            serde_json::to_writer(debug_file, &debug).unwrap();
+        }
+        // a block missing its debug traces is listed, but fails to load
+        fs::write(dirpath.join("basic_7.json"), "{}").unwrap();
+        fs::write(dirpath.join("notes.txt"), "").unwrap();
+
+        assert_eq!(saved_block_numbers(&dirpath).unwrap(), vec![3, 7, 12]);
+        let extraction = load_saved_block(&dirpath, 12).unwrap();
+        assert_eq!(extraction.basic.block_hash, FixedBytes::repeat_byte(12));
+        assert_eq!(
+            extraction.debug,
+            DebugTraces::new(12, None, 1_700_000_000, Vec::new())
+        );
+        assert!(matches!(
+            load_saved_block(&dirpath, 7),
+            Err(ExtractTransformErr::Load(_))
+        ));
+
+        fs::remove_dir_all(&dirpath).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Graceful shutdown upon a SIGTERM or SIGINT.
+//!
+//! The signals cancel a process-wide [CancellationToken], which stops the indexer from pulling
+//! new messages or starting new blocks.  The blocks already in flight are still transformed and
+//! published (moving the checkpoint of the follower), and the rest of the range is left to be
+//! indexed again, the message of the range being nacked.
+//!
+//! Draining the blocks in flight is given at most `SHUTDOWN_TIMEOUT` seconds, so that the
+//! publishers can still be flushed within the termination grace period of the pod.
+
+use std::time::Duration;
+
+use log::warn;
+use once_cell::sync::Lazy;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+use super::count_from_env;
+
+pub const SHUTDOWN_TIMEOUT_ENVKEY: &str = "SHUTDOWN_TIMEOUT";
+
+/// The number of seconds the blocks in flight are given to be published once shutting down, when
+/// `SHUTDOWN_TIMEOUT` is not set.  Leaves a minute of the 300 seconds of grace period of the
+/// chart to nack the message and flush the publishers.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 240;
+
+/// Cancelled once a shutdown signal is received
+static SHUTDOWN: Lazy<CancellationToken> = Lazy::new(CancellationToken::new);
+
+/// Returns the token cancelled upon shutdown.  It is never cancelled unless
+/// [handle_shutdown_signals] was called.
+pub fn shutdown_token() -> CancellationToken {
+    SHUTDOWN.clone()
+}
+
+/// Spawns a task cancelling the shutdown token upon a SIGTERM or SIGINT, and returns the token.
+pub fn handle_shutdown_signals() -> CancellationToken {
+    let token = shutdown_token();
+    let terminator = token.clone();
+    tokio::spawn(async move {
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to set up SIGTERM handler");
+        let mut sigint = signal(SignalKind::interrupt()).expect("Failed to set up SIGINT handler");
+
+        // hangs until receiving the signal.
+        tokio::select! {
+            _ = sigterm.recv() => warn!("SIGTERM received, shutting down gracefully..."),
+            _ = sigint.recv() => warn!("SIGINT received, shutting down gracefully..."),
+        }
+        terminator.cancel();
+    });
+    token
+}
+
+/// Returns the time the blocks in flight are given to be published once shutting down, read
+/// from the `SHUTDOWN_TIMEOUT` envkey.
+pub fn shutdown_timeout() -> Duration {
+    Duration::from_secs(count_from_env(
+        SHUTDOWN_TIMEOUT_ENVKEY,
+        DEFAULT_SHUTDOWN_TIMEOUT,
+    ))
+}
+
+/// Completes once the blocks in flight have had their time to be published after the token was
+/// cancelled.
+pub async fn drain_deadline(token: &CancellationToken, timeout: Duration) {
+    token.cancelled().await;
+    tokio::time::sleep(timeout).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn drains_for_the_timeout_once_shutting_down() {
+        let token = CancellationToken::new();
+        let timeout = Duration::from_millis(20);
+
+        // not shutting down, so never completes
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), drain_deadline(&token, timeout))
+                .await
+                .is_err()
+        );
+
+        token.cancel();
+        let cancelled_at = Instant::now();
+        drain_deadline(&token, timeout).await;
+        assert!(cancelled_at.elapsed() >= timeout);
+    }
+}
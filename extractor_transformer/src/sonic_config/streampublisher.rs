@@ -22,6 +22,39 @@ use blockchain_generic::output::rabbitmq_classic::connect;
 #[cfg(feature = "RABBITMQ_STREAM")]
 use blockchain_generic::output::rabbitmq_stream::connect;
 
+/// The envkey of the optional state diffs output, only connected to when set since the state
+/// diffs have to be requested explicitly.
+pub const QUEUE_NAME_STATE_DIFFS: &str = "QUEUE_NAME_STATE_DIFFS";
+/// The envkey of the optional reorgs output.  When not set, the reorgs are only logged.
+pub const QUEUE_NAME_REORGS: &str = "QUEUE_NAME_REORGS";
+/// The envkey of the optional dead-letter output, holding the blocks which failed to be extracted
+/// or transformed.  When not set, a failed block fails its whole range.
+pub const QUEUE_NAME_DEAD_LETTERS: &str = "QUEUE_NAME_DEAD_LETTERS";
+
+/// Connects to the output of the envkey, if set.
+#[cfg(feature = "SEPARATE_PUBLISHERS")]
+async fn connect_optional(queue_env: &str) -> Option<StreamPublisherConnection> {
+    match dotenvy::var(queue_env) {
+        Ok(_) => Some(connect(queue_env).await),
+        Err(_) => {
+            info!("`{}` is not set, skipping its publisher", queue_env);
+            None
+        }
+    }
+}
+
+/// Connects to the output of the envkey within a custom directory, if set.
+#[cfg(all(feature = "SEPARATE_PUBLISHERS", feature = "PUBLISHER_CUSTOMDIR"))]
+async fn connect_customdir_optional(
+    dir: &str,
+    queue_env: &str,
+) -> Option<StreamPublisherConnection> {
+    match dotenvy::var(queue_env) {
+        Ok(_) => Some(connect_customdir(dir, queue_env).await),
+        Err(_) => None,
+    }
+}
+
 /// StreamPublisher struct (seperate-publisher version) that contains various output
 /// streams for different content.
 #[cfg(feature = "SEPARATE_PUBLISHERS")]
@@ -32,7 +65,14 @@ pub struct StreamPublisher {
     pub logs: StreamPublisherConnection,
     pub receipts: StreamPublisherConnection,
     pub transactions: StreamPublisherConnection,
-    pub traces: StreamPublisherConnection
+    pub traces: StreamPublisherConnection,
+    pub contracts: StreamPublisherConnection,
+    pub tokens: StreamPublisherConnection,
+    pub token_transfers: StreamPublisherConnection,
+    pub native_transfers: StreamPublisherConnection,
+    pub state_diffs: Option<StreamPublisherConnection>,
+    pub reorgs: Option<StreamPublisherConnection>,
+    pub dead_letters: Option<StreamPublisherConnection>,
 }
 
 #[cfg(feature = "SEPARATE_PUBLISHERS")]
@@ -46,7 +86,23 @@ impl StreamPublisher {
             logs: self.logs.with_producer().await,
             receipts: self.receipts.with_producer().await,
             transactions: self.transactions.with_producer().await,
-            traces: self.traces.with_producer().await
+            traces: self.traces.with_producer().await,
+            contracts: self.contracts.with_producer().await,
+            tokens: self.tokens.with_producer().await,
+            token_transfers: self.token_transfers.with_producer().await,
+            native_transfers: self.native_transfers.with_producer().await,
+            state_diffs: match self.state_diffs {
+                Some(state_diffs) => Some(state_diffs.with_producer().await),
+                None => None,
+            },
+            reorgs: match self.reorgs {
+                Some(reorgs) => Some(reorgs.with_producer().await),
+                None => None,
+            },
+            dead_letters: match self.dead_letters {
+                Some(dead_letters) => Some(dead_letters.with_producer().await),
+                None => None,
+            },
         }
     }
 
@@ -59,7 +115,23 @@ impl StreamPublisher {
             logs: self.logs.with_channel().await,
             receipts: self.receipts.with_channel().await,
             transactions: self.transactions.with_channel().await,
-            traces: self.traces.with_channel().await
+            traces: self.traces.with_channel().await,
+            contracts: self.contracts.with_channel().await,
+            tokens: self.tokens.with_channel().await,
+            token_transfers: self.token_transfers.with_channel().await,
+            native_transfers: self.native_transfers.with_channel().await,
+            state_diffs: match self.state_diffs {
+                Some(state_diffs) => Some(state_diffs.with_channel().await),
+                None => None,
+            },
+            reorgs: match self.reorgs {
+                Some(reorgs) => Some(reorgs.with_channel().await),
+                None => None,
+            },
+            dead_letters: match self.dead_letters {
+                Some(dead_letters) => Some(dead_letters.with_channel().await),
+                None => None,
+            },
         }
     }
 
@@ -71,7 +143,14 @@ impl StreamPublisher {
             logs: connect("QUEUE_NAME_LOGS").await,
             receipts: connect("QUEUE_NAME_RECEIPTS").await,
             transactions: connect("QUEUE_NAME_TRANSACTIONS").await,
-            traces: connect("QUEUE_NAME_TRACES").await
+            traces: connect("QUEUE_NAME_TRACES").await,
+            contracts: connect("QUEUE_NAME_CONTRACTS").await,
+            tokens: connect("QUEUE_NAME_TOKENS").await,
+            token_transfers: connect("QUEUE_NAME_TOKEN_TRANSFERS").await,
+            native_transfers: connect("QUEUE_NAME_NATIVE_TRANSFERS").await,
+            state_diffs: connect_optional(QUEUE_NAME_STATE_DIFFS).await,
+            reorgs: connect_optional(QUEUE_NAME_REORGS).await,
+            dead_letters: connect_optional(QUEUE_NAME_DEAD_LETTERS).await,
         }
     }
 
@@ -83,7 +162,14 @@ impl StreamPublisher {
             logs: connect_customdir(dir, "QUEUE_NAME_LOGS").await,
             receipts: connect_customdir(dir, "QUEUE_NAME_RECEIPTS").await,
             transactions: connect_customdir(dir, "QUEUE_NAME_TRANSACTIONS").await,
-            traces: connect_customdir(dir, "QUEUE_NAME_TRACES").await
+            traces: connect_customdir(dir, "QUEUE_NAME_TRACES").await,
+            contracts: connect_customdir(dir, "QUEUE_NAME_CONTRACTS").await,
+            tokens: connect_customdir(dir, "QUEUE_NAME_TOKENS").await,
+            token_transfers: connect_customdir(dir, "QUEUE_NAME_TOKEN_TRANSFERS").await,
+            native_transfers: connect_customdir(dir, "QUEUE_NAME_NATIVE_TRANSFERS").await,
+            state_diffs: connect_customdir_optional(dir, QUEUE_NAME_STATE_DIFFS).await,
+            reorgs: connect_customdir_optional(dir, QUEUE_NAME_REORGS).await,
+            dead_letters: connect_customdir_optional(dir, QUEUE_NAME_DEAD_LETTERS).await,
         }
     }
 
@@ -96,5 +182,18 @@ impl StreamPublisher {
         self.receipts.disconnect().await;
         self.transactions.disconnect().await;
         self.traces.disconnect().await;
+        self.contracts.disconnect().await;
+        self.tokens.disconnect().await;
+        self.token_transfers.disconnect().await;
+        self.native_transfers.disconnect().await;
+        if let Some(state_diffs) = self.state_diffs {
+            state_diffs.disconnect().await;
+        }
+        if let Some(reorgs) = self.reorgs {
+            reorgs.disconnect().await;
+        }
+        if let Some(dead_letters) = self.dead_letters {
+            dead_letters.disconnect().await;
+        }
     }
 }
@@ -120,18 +120,24 @@ impl TestSet {
     }
 
     pub async fn save_extractions(&self) -> Result<(), Option<RpcError<TransportErrorKind>>> {
-        save_range(self.request.clone(), None, None, &self.extraction_path()).await
+        save_range(
+            self.request.clone(),
+            None,
+            build_provider().await,
+            &self.extraction_path(),
+        )
+        .await
     }
 
     pub async fn save_transformations(&self) -> Result<(), Vec<(u64, ExtractTransformErr)>> {
-        let provider = build_provider();
+        let provider = build_provider().await;
 
         for block_number in self.request.start..self.request.end {
             let perblock = extract_transform(
                 block_number,
                 None,
                 Some(self.request.clone()),
-                Some(provider.clone()),
+                provider.clone(),
                 build_catalog(),
             )
             .await
@@ -150,14 +156,14 @@ impl TestSet {
     }
 
     pub async fn validate_extraction(&self) {
-        let extractor = EthExtractor::new(build_provider());
+        let extractor = EthExtractor::new(build_provider().await);
 
         for number in self.request.start..=self.request.end {
             let (evm, debug) = deserialize_extracted_files(&self.extraction_path(), number)
                 .expect("Missing extractable files");
 
             let evm_now = extractor
-                .extract_basic(number, Some(self.request.clone()), 5, 5)
+                .extract_basic(number, Some(self.request.clone()))
                 .await
                 .expect("Failed")
                 .expect("Bad index, returned None");
@@ -173,7 +179,7 @@ impl TestSet {
 
     pub async fn validate_transformation(&self) {
         let (_, request) = self.name_and_request();
-        let provider = build_provider();
+        let provider = build_provider().await;
 
         for entry in fs::read_dir(self.transformation_path()).expect("Failed to read directory") {
             let entry = entry.expect("Failed to get entry");
@@ -207,7 +213,7 @@ impl TestSet {
                 block_number,
                 None,
                 Some(request.clone()),
-                Some(provider.clone()),
+                provider.clone(),
                 build_catalog(),
             )
             .await
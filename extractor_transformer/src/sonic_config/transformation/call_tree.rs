@@ -0,0 +1,154 @@
+//! Rebuilds the call tree of each transaction from its flattened traces.
+//!
+//! Both trace backends return the traces of a transaction in pre-order (each trace followed by
+//! the traces of its subcalls), so the tree can be rebuilt from the `subtraces` count of each
+//! trace alone, which every variant carries (unlike the trace address).
+
+use std::collections::HashMap;
+
+use crate::blockchain_config::extraction::{TraceType, TxTrace};
+
+/// The position of a trace within the call tree of its transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceNode {
+    /// The index of the parent trace within the block's traces, `None` for the top-level trace.
+    pub parent: Option<usize>,
+    /// The number of ancestors of the trace, 0 for the top-level trace.
+    pub depth: usize,
+    /// Whether the trace or any of its ancestors errored, in which case its effects were
+    /// reverted.
+    pub failed: bool,
+}
+
+/// Returns the node of every trace, in the same order as the traces.
+pub fn call_tree(traces: &[TxTrace]) -> Vec<TraceNode> {
+    let mut nodes: Vec<TraceNode> = Vec::with_capacity(traces.len());
+
+    // the ancestors of the current trace, with the number of subtraces left to visit
+    let mut ancestors: Vec<(usize, u64)> = Vec::new();
+    let mut current_tx = None;
+
+    for (index, trace) in traces.iter().enumerate() {
+        let (tx_hash, _) = trace.get_tx_info();
+        // block-level traces (rewards) and the first trace of a transaction are roots
+        if tx_hash.is_none() || tx_hash != current_tx {
+            ancestors.clear();
+            current_tx = tx_hash;
+        }
+        while matches!(ancestors.last(), Some((_, 0))) {
+            ancestors.pop();
+        }
+
+        let parent = ancestors.last_mut().map(|(parent, remaining)| {
+            *remaining -= 1;
+            *parent
+        });
+        let node = TraceNode {
+            parent,
+            depth: ancestors.len(),
+            failed: trace.error().is_some() || parent.map_or(false, |parent| nodes[parent].failed),
+        };
+        nodes.push(node);
+
+        if trace.subtraces() > 0 {
+            ancestors.push((index, trace.subtraces()));
+        }
+    }
+
+    nodes
+}
+
+/// Returns the ethereum-etl style ID of every trace, in the same order as the traces.
+///
+/// The traces of a transaction are identified by their type, the transaction hash, and their
+/// trace address (`call_<tx>_0_1`).  Block-level traces (rewards, and the trace of an empty block)
+/// are identified by their type, the block number, and their position among the block-level
+/// traces of the same type (`reward_<block>_0`).
+pub fn trace_ids(traces: &[TxTrace], block_number: u64) -> Vec<String> {
+    let mut block_level_counts: HashMap<TraceType, usize> = HashMap::new();
+
+    traces
+        .iter()
+        .map(|trace| match (trace.trace_type(), trace.get_tx_info()) {
+            (TraceType::Reward | TraceType::Empty, _) | (_, (None, _)) => {
+                let count = block_level_counts.entry(trace.trace_type()).or_default();
+                let id = format!("{}_{}_{}", trace.trace_type(), block_number, count);
+                *count += 1;
+                id
+            }
+            (trace_type, (Some(tx_hash), _)) => {
+                let mut id = format!("{}_{}", trace_type, tx_hash);
+                for index in trace.trace_address() {
+                    id.push_str(&format!("_{}", index));
+                }
+                id
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tx: u8, trace_address: &[u64], subtraces: u64, error: Option<&str>) -> TxTrace {
+        serde_json::from_value(serde_json::json!({
+            "type": "call",
+            "action": {
+                "from": "0x0000000000000000000000000000000000000001",
+                "callType": "call",
+                "gas": "0x0",
+                "input": "0x",
+                "to": "0x0000000000000000000000000000000000000002",
+                "value": "0x1"
+            },
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": 1,
+            "error": error,
+            "result": null,
+            "subtraces": subtraces,
+            "traceAddress": trace_address,
+            "transactionHash": format!("0x{:064x}", tx),
+            "transactionPosition": tx
+        }))
+        .expect("valid call trace")
+    }
+
+    #[test]
+    fn propagates_failures_to_descendants_only() {
+        // tx 0: 0 -> [1 (reverted) -> [2], 3]; tx 1: 4
+        let traces = vec![
+            call(0, &[], 2, None),
+            call(0, &[0], 1, Some("Reverted")),
+            call(0, &[0, 0], 0, None),
+            call(0, &[1], 0, None),
+            call(1, &[], 0, None),
+        ];
+
+        let nodes = call_tree(&traces);
+
+        assert_eq!(
+            nodes.iter().map(|node| node.parent).collect::<Vec<_>>(),
+            vec![None, Some(0), Some(1), Some(0), None]
+        );
+        assert_eq!(
+            nodes.iter().map(|node| node.depth).collect::<Vec<_>>(),
+            vec![0, 1, 2, 1, 0]
+        );
+        assert_eq!(
+            nodes.iter().map(|node| node.failed).collect::<Vec<_>>(),
+            vec![false, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn ids_follow_trace_addresses() {
+        let traces = vec![call(2, &[], 1, None), call(2, &[0], 0, None)];
+        let tx = format!("0x{:064x}", 2);
+
+        assert_eq!(
+            trace_ids(&traces, 1),
+            vec![format!("call_{}", tx), format!("call_{}_0", tx)]
+        );
+    }
+}
@@ -2,10 +2,13 @@ use std::collections::HashMap;
 use std::str::FromStr;
 
 use alloy::primitives::{FixedBytes, Uint, U256};
+use alloy::rpc::types::trace::parity::Delta;
 use log::{debug, info};
 
-use crate::blockchain_config::extraction::{DebugExtraction, TxTrace};
-use crate::blockchain_config::transformation::err::TransformationErr;
+use crate::blockchain_config::extraction::{DebugExtraction, StateDiffs, TokenMetadata, TxTrace};
+use crate::blockchain_config::transformation::call_tree::{call_tree, trace_ids, TraceNode};
+use crate::blockchain_config::transformation::err::{TransformationErr, TransformationErrCode};
+use crate::blockchain_config::transformation::token_transfers::decode_token_transfers;
 use crate::blockchain_config::transformation::{bq::integer::TryIntoInteger, events::EventCatalog};
 use crate::blockchain_config::ExtractTransformErr;
 
@@ -13,7 +16,11 @@ use super::proto_codegen::etl::{
     blocks::{block::Withdrawal, Block},
     decoded_events::DecodedEvent,
     logs::Log,
+    native_transfers::NativeTransfer,
     receipts::Receipt,
+    state_diffs::StateDiff,
+    token_transfers::TokenTransfer,
+    tokens::Token,
     traces::{
         trace::{TraceAction, TraceResult},
         Trace,
@@ -31,13 +38,7 @@ pub fn transform_block<T: EvmExtraction>(extracted: &T) -> Result<Block, Extract
     debug!("Handling block #{}", extracted.block_number());
     let extracted_block = match extracted.block() {
         Some(block) => block,
-        None => {
-            return Err(TransformationErr::new(
-                "Missing critical extractions for this transformation".to_string(),
-                Some("block".to_string()),
-            )
-            .into())
-        }
+        None => return Err(TransformationErr::missing("block").into()),
     };
     Ok(Block {
         block_hash: format_fixed_bytes(extracted.block_hash()),
@@ -79,27 +80,13 @@ pub fn transform_block<T: EvmExtraction>(extracted: &T) -> Result<Block, Extract
         transactions_count: match extracted_block.transactions.len().try_into_integer() {
             Ok(item) => item,
             Err(err) => {
-                return Err(TransformationErr::new(
-                    err.to_string(),
-                    Some("transactions_count".to_string()),
-                )
-                .into())
+                return Err(TransformationErr::out_of_range("transactions_count", err).into())
             }
         },
         size: match extracted_block.size.map(|size| size.try_into_integer()) {
             Some(Ok(size_integer)) => size_integer,
-            Some(Err(err)) => {
-                return Err(
-                    TransformationErr::new(err.to_string(), Some("size".to_string())).into(),
-                )
-            }
-            None => {
-                return Err(TransformationErr::new(
-                    "Missing size".to_string(),
-                    Some("size".to_string()),
-                )
-                .into())
-            }
+            Some(Err(err)) => return Err(TransformationErr::out_of_range("size", err).into()),
+            None => return Err(TransformationErr::missing("size").into()),
         },
         withdrawals: {
             match &extracted_block.withdrawals {
@@ -113,35 +100,33 @@ pub fn transform_block<T: EvmExtraction>(extracted: &T) -> Result<Block, Extract
         extra_data: format!("{}", extracted_block.header.extra_data),
         log_count: match extracted.logs() {
             Some(logs) => logs.len() as i64,
-            None => {
-                return Err(TransformationErr::new(
-                    "Missing logs from extracted".to_string(),
-                    Some("Logs".to_string()),
-                )
-                .into())
-            }
+            None => return Err(TransformationErr::missing("logs").into()),
         },
         trace_count: 0,         // Value gets filled in later
         decoded_event_count: 0, // Value gets filled in later
-        epoch: extract_tarnsform_epoch(extracted_block)
-            .expect("Failed to parse epoch")
-            .expect("Missing epoch in block response"),
+        epoch: match extract_tarnsform_epoch(extracted_block)? {
+            Some(epoch) => epoch,
+            None => return Err(TransformationErr::missing("epoch").into()),
+        },
     })
 }
 
 pub fn extract_tarnsform_epoch(
     extracted_block: &alloy::rpc::types::Block,
-) -> Result<Option<i64>, std::num::ParseIntError> {
+) -> Result<Option<i64>, TransformationErr> {
     match extracted_block.other.get("epoch") {
         Some(serde_json::Value::String(string)) => {
             let trimmed = string.strip_prefix("0x").unwrap_or(string);
             match i64::from_str_radix(trimmed, 16) {
                 Ok(epoch_num) => Ok(Some(epoch_num)),
-                Err(err) => Err(err),
+                Err(err) => Err(TransformationErr::invalid("epoch", err)),
             }
         }
         None => Ok(None),
-        _ => unreachable!("Unexpected response, expected a hex"),
+        Some(other) => Err(TransformationErr::invalid(
+            "epoch",
+            format!("Unexpected response, expected a hex: {}", other),
+        )),
     }
 }
 
@@ -165,13 +150,7 @@ pub fn transform_transactions<T: EvmExtraction>(
     debug!("Handling transactions #{}", extracted.block_number());
     let extracted_block = match extracted.block() {
         Some(block) => block,
-        None => {
-            return Err(TransformationErr::new(
-                "Missing critical extractions for this transformation".to_string(),
-                Some("block".to_string()),
-            )
-            .into())
-        }
+        None => return Err(TransformationErr::missing("block").into()),
     };
     let capacity = extracted_block.transactions.len();
     let mut transactions = Vec::with_capacity(capacity);
@@ -179,10 +158,9 @@ pub fn transform_transactions<T: EvmExtraction>(
     let txs = match extracted_block.transactions.as_transactions() {
         Some(txs) => txs,
         None => {
-            return Err(TransformationErr::new(
-                "Expected extracted.block.transactions.is_full() to be returned as true"
-                    .to_string(),
-                Some("block.transactions".to_string()),
+            return Err(TransformationErr::invalid(
+                "block.transactions",
+                "Expected the full transactions of the block, not their hashes",
             )
             .into())
         }
@@ -200,27 +178,25 @@ pub fn transform_transactions<T: EvmExtraction>(
             },
             nonce: match tx.nonce.try_into_integer() {
                 Ok(nonce) => nonce,
-                Err(err) => {
-                    return Err(
-                        TransformationErr::new(err.to_string(), Some("nonce".to_string())).into(),
-                    )
-                }
+                Err(err) => return Err(TransformationErr::out_of_range("nonce", err).into()),
             },
             from_address: format!("{}", tx.from),
             to_address: tx.to.map(|to| format!("{}", to)),
             value: cap_bignumeric(&tx.value),
             value_lossless: format!("{}", tx.value),
-            gas_price: tx.gas_price.map(|price| price as i64),
-            gas: tx.gas as i64,
+            gas_price: match tx.gas_price.map(i64::try_from).transpose() {
+                Ok(gas_price) => gas_price,
+                Err(err) => return Err(TransformationErr::out_of_range("gas_price", err).into()),
+            },
+            gas: match i64::try_from(tx.gas) {
+                Ok(gas) => gas,
+                Err(err) => return Err(TransformationErr::out_of_range("gas", err).into()),
+            },
             max_fee_per_gas: match tx.max_fee_per_gas {
                 Some(mfpg_u128) => match mfpg_u128.try_into_integer_opt() {
                     Ok(mfpg_i64) => mfpg_i64,
                     Err(err) => {
-                        return Err(TransformationErr::new(
-                            err.to_string(),
-                            Some("max_fee_per_gas".to_string()),
-                        )
-                        .into())
+                        return Err(TransformationErr::out_of_range("max_fee_per_gas", err).into())
                     }
                 },
                 None => None,
@@ -229,9 +205,9 @@ pub fn transform_transactions<T: EvmExtraction>(
                 Some(mpfpg_u128) => match mpfpg_u128.try_into_integer_opt() {
                     Ok(mpfpg_i64) => mpfpg_i64,
                     Err(err) => {
-                        return Err(TransformationErr::new(
-                            err.to_string(),
-                            Some("max_priority_fee_per_gas".to_string()),
+                        return Err(TransformationErr::out_of_range(
+                            "max_priority_fee_per_gas",
+                            err,
                         )
                         .into())
                     }
@@ -241,22 +217,12 @@ pub fn transform_transactions<T: EvmExtraction>(
             input: format!("{}", tx.input),
             transaction_type: match tx.transaction_type {
                 Some(txtype) => txtype as u32,
-                None => {
-                    return Err(TransformationErr::new(
-                        "Missing tx.transaction_type".to_string(),
-                        Some("transaction_type".to_string()),
-                    )
-                    .into())
-                }
+                None => return Err(TransformationErr::missing("transaction_type").into()),
             },
             chain_id: match tx.chain_id.map(|id| id.try_into_integer_opt()) {
                 Some(Ok(chain_id)) => chain_id,
                 Some(Err(err)) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("chain_id".to_string()),
-                    )
-                    .into())
+                    return Err(TransformationErr::out_of_range("chain_id", err).into())
                 }
                 None => None,
             },
@@ -303,27 +269,31 @@ pub fn transform_transactions<T: EvmExtraction>(
     Ok(transactions)
 }
 
-use crate::blockchain_config::transformation::events::{GetEventBySigErr, LogDecodeErr};
+use crate::blockchain_config::transformation::events::{
+    generic::DecodedEventExt, GetEventBySigErr, LogDecodeErr,
+};
 
 #[allow(clippy::type_complexity)]
 pub fn transform_logs_and_events<T: EvmExtraction, C: EventCatalog>(
     extracted: &T,
     incl_logs: bool,
     incl_events: bool,
+    incl_token_transfers: bool,
     catalog: Option<C>,
-) -> Result<(Option<Vec<Log>>, Option<Vec<DecodedEvent>>, usize), ExtractTransformErr> {
+) -> Result<
+    (
+        Option<Vec<Log>>,
+        Option<Vec<DecodedEvent>>,
+        Option<Vec<TokenTransfer>>,
+        usize,
+    ),
+    ExtractTransformErr,
+> {
     debug!("Handling Logs & Events #{}", extracted.block_number());
 
     let extracted_logs = match extracted.logs() {
         Some(logs) => logs,
-        None => {
-            return Err(TransformationErr::new(
-                "Expected extracted.block.transactions.is_full() to be returned as true"
-                    .to_string(),
-                Some("block.transactions".to_string()),
-            )
-            .into())
-        }
+        None => return Err(TransformationErr::missing("logs").into()),
     };
 
     let mut logs = match incl_logs {
@@ -343,10 +313,23 @@ pub fn transform_logs_and_events<T: EvmExtraction, C: EventCatalog>(
         }
     };
 
+    let mut token_transfers: Option<Vec<TokenTransfer>> = match incl_token_transfers {
+        true => Some(Vec::new()),
+        false => {
+            info!("Skipping token transfers");
+            None
+        }
+    };
+
     let mut event_count: usize = 0;
 
-    if incl_events && catalog.is_none() {
-        panic!("Need to provide catalog if handling decoded events");
+    if (incl_events || incl_token_transfers) && catalog.is_none() {
+        return Err(TransformationErr::with_code(
+            TransformationErrCode::MissingCatalog,
+            "Need to provide catalog if handling decoded events".to_string(),
+            None,
+        )
+        .into());
     }
 
     for log in extracted_logs.iter() {
@@ -366,36 +349,16 @@ pub fn transform_logs_and_events<T: EvmExtraction, C: EventCatalog>(
                 {
                     Some(Ok(index)) => index,
                     Some(Err(err)) => {
-                        return Err(TransformationErr::new(
-                            err.to_string(),
-                            Some("transaction_index".to_string()),
-                        )
-                        .into())
-                    }
-                    None => {
-                        return Err(TransformationErr::new(
-                            "Missing transaction_index".to_string(),
-                            Some("transaction_index".to_string()),
-                        )
-                        .into())
+                        return Err(TransformationErr::out_of_range("transaction_index", err).into())
                     }
+                    None => return Err(TransformationErr::missing("transaction_index").into()),
                 },
                 log_index: match log.log_index.map(|index| index.try_into_integer()) {
                     Some(Ok(index)) => index,
                     Some(Err(err)) => {
-                        return Err(TransformationErr::new(
-                            err.to_string(),
-                            Some("log_index".to_string()),
-                        )
-                        .into())
-                    }
-                    None => {
-                        return Err(TransformationErr::new(
-                            "Missing log_index".to_string(),
-                            Some("log_index".to_string()),
-                        )
-                        .into())
+                        return Err(TransformationErr::out_of_range("log_index", err).into())
                     }
+                    None => return Err(TransformationErr::missing("log_index").into()),
                 },
                 address: Some(format!("{}", log.address().0)),
                 data: Some(format!("0x{:?}", log.data().data)),
@@ -427,35 +390,22 @@ pub fn transform_logs_and_events<T: EvmExtraction, C: EventCatalog>(
                             transaction_hash: match log.transaction_hash {
                                 Some(tx_hash) => format!("{}", tx_hash),
                                 None => {
-                                    return Err(TransformationErr::new(
-                                        "Missing critical extractions for this transformation"
-                                            .to_string(),
-                                        Some("transaction_hash".to_string()),
+                                    return Err(
+                                        TransformationErr::missing("transaction_hash").into()
                                     )
-                                    .into())
                                 }
                             },
                             transaction_index: match log.transaction_index {
                                 Some(tx_index) => tx_index as i64,
                                 None => {
-                                    return Err(TransformationErr::new(
-                                        "Missing critical extractions for this transformation"
-                                            .to_string(),
-                                        Some("transaction_index".to_string()),
+                                    return Err(
+                                        TransformationErr::missing("transaction_index").into()
                                     )
-                                    .into())
                                 }
                             },
                             log_index: match log.log_index {
                                 Some(log_index) => log_index as i64,
-                                None => {
-                                    return Err(TransformationErr::new(
-                                        "Missing critical extractions for this transformation"
-                                            .to_string(),
-                                        Some("log_index".to_string()),
-                                    )
-                                    .into())
-                                }
+                                None => return Err(TransformationErr::missing("log_index").into()),
                             },
                             address: Some(format!("{}", log.address())),
                             event_hash: Some(format!("{}", event.event.selector())),
@@ -469,30 +419,87 @@ pub fn transform_logs_and_events<T: EvmExtraction, C: EventCatalog>(
                                 Ok(json) => match serde_json::to_string(&json) {
                                     Ok(string) => Some(string),
                                     Err(err) => {
-                                        unreachable!("Failed to turn json into string: {}", err)
+                                        return Err(TransformationErr::with_code(
+                                            TransformationErrCode::Serialization,
+                                            format!("Failed to turn json into string: {}", err),
+                                            Some("args".to_string()),
+                                        )
+                                        .into())
                                     }
                                 },
-                                Err(err) => unreachable!(
-                                    "Failed to convert decoded arguments into json: {:?}",
-                                    err
-                                ),
+                                Err(err) => {
+                                    return Err(TransformationErr::with_code(
+                                        TransformationErrCode::Serialization,
+                                        format!(
+                                            "Failed to convert decoded arguments into json: {:?}",
+                                            err
+                                        ),
+                                        Some("args".to_string()),
+                                    )
+                                    .into())
+                                }
                             },
                             removed: Some(log.removed),
                         });
                     }
+                    if let Some(token_transfers) = &mut token_transfers {
+                        token_transfers.extend(transform_token_transfers(extracted, log, &event)?);
+                    }
                 }
                 Err(LogDecodeErr::EventRetrievalErr(GetEventBySigErr::NotFound)) => {}
                 Err(LogDecodeErr::LogHasNoTopics) => {}
-                Err(err) => {
-                    return Err(ExtractTransformErr::Transformation(TransformationErr::new(
-                        err.to_string(),
-                        None,
-                    )))
-                }
+                Err(err) => return Err(TransformationErr::invalid("data", err).into()),
             }
         }
     }
-    Ok((logs, events, event_count))
+    Ok((logs, events, token_transfers, event_count))
+}
+
+/// Creates a record for every token transferred by the decoded event of the log, if it is an
+/// ERC-20, ERC-721 or ERC-1155 transfer.
+fn transform_token_transfers<T: EvmExtraction>(
+    extracted: &T,
+    log: &alloy::rpc::types::Log,
+    event: &DecodedEventExt,
+) -> Result<Vec<TokenTransfer>, ExtractTransformErr> {
+    let transfers = decode_token_transfers(event);
+    if transfers.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (transaction_hash, transaction_index, log_index) =
+        match (log.transaction_hash, log.transaction_index, log.log_index) {
+            (Some(tx_hash), Some(tx_index), Some(log_index)) => {
+                (format!("{}", tx_hash), tx_index as i64, log_index as i64)
+            }
+            (None, _, _) => return Err(TransformationErr::missing("transaction_hash").into()),
+            (_, None, _) => return Err(TransformationErr::missing("transaction_index").into()),
+            (_, _, None) => return Err(TransformationErr::missing("log_index").into()),
+        };
+
+    Ok(transfers
+        .into_iter()
+        .map(|transfer| TokenTransfer {
+            block_hash: match log.block_hash {
+                Some(block_hash) => format!("{}", block_hash),
+                None => format!("{}", extracted.block_hash()),
+            },
+            block_number: extracted.block_number() as i64,
+            block_timestamp: extracted.block_timestamp(),
+            transaction_hash: transaction_hash.clone(),
+            transaction_index,
+            log_index,
+            token_address: format!("{}", log.address()),
+            token_standard: transfer.standard.to_string(),
+            from_address: format!("{}", transfer.from),
+            to_address: format!("{}", transfer.to),
+            operator_address: transfer.operator.map(|operator| format!("{}", operator)),
+            token_id: transfer.token_id.map(|token_id| token_id.to_string()),
+            value: transfer.value.as_ref().map(cap_bignumeric),
+            value_lossless: transfer.value.map(|value| value.to_string()),
+            batch_index: transfer.batch_index.map(|index| index as i64),
+        })
+        .collect())
 }
 
 pub fn set_event_count(block: Block, event_count: usize) -> Block {
@@ -507,13 +514,7 @@ pub async fn transform_receipts<T: EvmExtraction>(
 ) -> Result<Vec<Receipt>, ExtractTransformErr> {
     let extracted_receipts = match extracted.receipts() {
         Some(receipts) => receipts,
-        None => {
-            return Err(TransformationErr::new(
-                "Missing receipts from extraction".to_string(),
-                None,
-            )
-            .into())
-        }
+        None => return Err(TransformationErr::missing("receipts").into()),
     };
 
     let mut vec = Vec::with_capacity(extracted_receipts.len());
@@ -522,13 +523,7 @@ pub async fn transform_receipts<T: EvmExtraction>(
         vec.push(Receipt {
             block_hash: match receipt.block_hash {
                 Some(hash) => format!("{}", hash),
-                None => {
-                    return Err(TransformationErr::new(
-                        "Missing critical extractions for this transformation".to_string(),
-                        Some("block_hash".to_string()),
-                    )
-                    .into())
-                }
+                None => return Err(TransformationErr::missing("block_hash").into()),
             },
             block_number: extracted.block_number() as i64,
             block_timestamp: extracted.block_timestamp(),
@@ -536,19 +531,9 @@ pub async fn transform_receipts<T: EvmExtraction>(
             transaction_index: match receipt.transaction_index.map(|i| i.try_into_integer()) {
                 Some(Ok(fixed_idx)) => fixed_idx,
                 Some(Err(err)) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("transaction_index".to_string()),
-                    )
-                    .into())
-                }
-                None => {
-                    return Err(TransformationErr::new(
-                        "No transaction index in receipt".to_string(),
-                        Some("transaction_index".to_string()),
-                    )
-                    .into())
+                    return Err(TransformationErr::out_of_range("transaction_index", err).into())
                 }
+                None => return Err(TransformationErr::missing("transaction_index").into()),
             },
             from_address: format!("{}", receipt.from),
             to_address: receipt.to.map(|to| format!("{}", to)),
@@ -556,50 +541,47 @@ pub async fn transform_receipts<T: EvmExtraction>(
             cumulative_gas_used: match receipt.inner.cumulative_gas_used().try_into_integer() {
                 Ok(gas) => gas,
                 Err(err) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("cumulative_gas_used".to_string()),
-                    )
-                    .into())
+                    return Err(TransformationErr::out_of_range("cumulative_gas_used", err).into())
                 }
             },
             gas_used: match receipt.gas_used.try_into_integer() {
                 Ok(gas) => gas,
-                Err(err) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("gas_used".to_string()),
-                    )
-                    .into())
-                }
+                Err(err) => return Err(TransformationErr::out_of_range("gas_used", err).into()),
             },
             effective_gas_price: match receipt.effective_gas_price.try_into_integer() {
                 Ok(price) => price,
                 Err(err) => {
-                    return Err(TransformationErr::new(
-                        err.to_string(),
-                        Some("effective_gas_price".to_string()),
-                    )
-                    .into())
+                    return Err(TransformationErr::out_of_range("effective_gas_price", err).into())
                 }
             },
             logs_bloom: format_fixed_bytes(receipt.inner.logs_bloom().0),
             root: receipt.state_root.map(format_fixed_bytes),
             status: Some(receipt.status().into()),
+            log_count: Some(receipt.inner.logs().len() as i64),
         })
     }
 
     Ok(vec)
 }
 
+/// Converts an amount of gas of a trace into its column, failing if it doesn't fit.
+#[inline]
+fn trace_gas(field: &str, gas: Option<u128>) -> Result<Option<i64>, TransformationErr> {
+    gas.map(i64::try_from)
+        .transpose()
+        .map_err(|err| TransformationErr::out_of_range(field, err))
+}
+
 pub async fn transform_traces<T: DebugExtraction>(
     extracted: &T,
     incl_traces: bool,
     incl_count: bool,
     incl_pertx_count: bool,
+    incl_native_transfers: bool,
 ) -> Result<
     (
         Option<Vec<Trace>>,
+        Option<Vec<NativeTransfer>>,
         Option<usize>,
         Option<HashMap<Option<u64>, u64>>,
     ),
@@ -631,8 +613,32 @@ pub async fn transform_traces<T: DebugExtraction>(
         }
     };
 
+    let mut native_transfers: Option<Vec<NativeTransfer>> = {
+        if incl_native_transfers {
+            Some(Vec::new())
+        } else {
+            None
+        }
+    };
+    let nodes = match incl_traces || incl_native_transfers {
+        true => call_tree(&traces),
+        false => Vec::new(),
+    };
+    let ids = match incl_traces {
+        true => trace_ids(&traces, extracted.block_number()),
+        false => Vec::new(),
+    };
+
     // Iterate through the traces
     for (trace_index, trace) in traces.iter().enumerate() {
+        if let Some(transfers) = &mut native_transfers {
+            if let Some(transfer) =
+                transform_native_transfer(extracted, trace_index, trace, &nodes[trace_index])?
+            {
+                transfers.push(transfer);
+            }
+        }
+
         // If storing the pertx hashmap, we add it
         if let Some(txmap) = &mut pertx {
             let (_, txpos) = trace.get_tx_info();
@@ -646,66 +652,69 @@ pub async fn transform_traces<T: DebugExtraction>(
 
         // If storing trace records, add them here
         if let Some(records) = &mut trace_records_vec {
+            let node = &nodes[trace_index];
+            let depth = Some(node.depth as i64);
+            let parent_trace_index = node.parent.map(|parent| parent as i64);
+            let trace_id = Some(ids[trace_index].clone());
+            let status = Some(if node.failed { 0 } else { 1 });
+
             let trace_out = match trace {
-                TxTrace::Call(call) => Trace {
-                    block_hash: format!("{}", call.block_hash),
-                    block_number: extracted.block_number(),
-                    block_timestamp: extracted.block_timestamp() as u64,
-                    transaction_hash: Some(format!("{}", call.transaction_hash)),
-                    transaction_index: Some(call.transaction_position as i64),
-                    trace_type: "call".to_string(),
-                    trace_address: call
-                        .trace_address
-                        .iter()
-                        .map(|addr| *addr as i64)
-                        .collect::<Vec<i64>>(),
-                    subtrace_count: call.subtraces as i64,
-                    action: TraceAction {
-                        from_address: Some(format!("{}", call.action.from)),
-                        to_address: Some(format!("{}", call.action.to)),
-                        call_type: Some(call.action.call_type.clone()),
-                        gas: Some(call.action.gas as i64),
-                        input: Some(call.action.input.clone()),
-                        value: match U256::from_str_radix(
-                            call.action
-                                .value
-                                .strip_prefix("0x")
-                                .unwrap_or(&call.action.value),
-                            16,
-                        ) {
-                            Ok(num) => Some(cap_bignumeric(&num)),
-                            Err(err) => panic!("Failed to parse number: {}", err),
-                        },
-                        value_lossless: match U256::from_str_radix(
-                            call.action
-                                .value
-                                .strip_prefix("0x")
-                                .unwrap_or(&call.action.value),
-                            16,
-                        ) {
-                            Ok(num) => Some(num.to_string()),
-                            Err(err) => panic!("Failed to parse number: {}", err),
+                TxTrace::Call(call) => {
+                    let value = U256::from_str_radix(
+                        call.action
+                            .value
+                            .strip_prefix("0x")
+                            .unwrap_or(&call.action.value),
+                        16,
+                    )
+                    .map_err(|err| TransformationErr::invalid("value", err))?;
+
+                    Trace {
+                        block_hash: format!("{}", call.block_hash),
+                        block_number: extracted.block_number(),
+                        block_timestamp: extracted.block_timestamp() as u64,
+                        transaction_hash: Some(format!("{}", call.transaction_hash)),
+                        transaction_index: Some(call.transaction_position as i64),
+                        trace_type: "call".to_string(),
+                        trace_address: call
+                            .trace_address
+                            .iter()
+                            .map(|addr| *addr as i64)
+                            .collect::<Vec<i64>>(),
+                        subtrace_count: call.subtraces as i64,
+                        action: TraceAction {
+                            from_address: Some(format!("{}", call.action.from)),
+                            to_address: Some(format!("{}", call.action.to)),
+                            call_type: Some(call.action.call_type.clone()),
+                            gas: trace_gas("gas", call.action.gas)?,
+                            input: Some(call.action.input.clone()),
+                            value: Some(cap_bignumeric(&value)),
+                            value_lossless: Some(value.to_string()),
+                            init: None,
+                            author: None,
+                            reward_type: None,
+                            refund_address: None,
+                            refund_balance: None,
+                            refund_balance_lossless: None,
+                            self_destructed_address: None,
                         },
-                        init: None,
-                        author: None,
-                        reward_type: None,
-                        refund_address: None,
-                        refund_balance: None,
-                        refund_balance_lossless: None,
-                        self_destructed_address: None,
-                    },
-                    result: call.result.as_ref().map(|res| TraceResult {
-                        gas_used: match i64::try_from(res.gas_used) {
-                            Ok(gas_used) => Some(gas_used),
-                            Err(err) => panic!("Failed to convert gas_used to i64: {err}"),
+                        result: match &call.result {
+                            Some(res) => Some(TraceResult {
+                                gas_used: trace_gas("gas_used", res.gas_used)?,
+                                output: Some(res.output.clone()),
+                                address: None,
+                                code: None,
+                            }),
+                            None => None,
                         },
-                        output: Some(res.output.clone()),
-                        address: None,
-                        code: None,
-                    }),
-                    error: call.error.clone(),
-                    trace_index: trace_index as u64,
-                },
+                        error: call.error.clone(),
+                        trace_index: trace_index as u64,
+                        depth,
+                        parent_trace_index,
+                        trace_id,
+                        status,
+                    }
+                }
                 TxTrace::Reward(rwd) => Trace {
                     block_hash: format!("{}", rwd.block_hash),
                     block_number: extracted.block_number(),
@@ -735,14 +744,21 @@ pub async fn transform_traces<T: DebugExtraction>(
                         refund_balance_lossless: None,
                         self_destructed_address: None,
                     },
-                    result: rwd.result.as_ref().map(|res| TraceResult {
-                        gas_used: Some(res.gas_used as i64),
-                        output: Some(res.output.clone()),
-                        address: None,
-                        code: None,
-                    }),
+                    result: match &rwd.result {
+                        Some(res) => Some(TraceResult {
+                            gas_used: trace_gas("gas_used", res.gas_used)?,
+                            output: Some(res.output.clone()),
+                            address: None,
+                            code: None,
+                        }),
+                        None => None,
+                    },
                     error: rwd.error.clone(),
                     trace_index: trace_index as u64,
+                    depth,
+                    parent_trace_index,
+                    trace_id,
+                    status,
                 },
                 TxTrace::Suicide(suicide) => Trace {
                     block_hash: format!("{}", suicide.block_hash),
@@ -779,6 +795,10 @@ pub async fn transform_traces<T: DebugExtraction>(
                     result: None,
                     error: suicide.error.clone(),
                     trace_index: trace_index as u64,
+                    depth,
+                    parent_trace_index,
+                    trace_id,
+                    status,
                 },
                 TxTrace::Create(create) => Trace {
                     block_hash: format!("{}", create.block_hash),
@@ -787,13 +807,17 @@ pub async fn transform_traces<T: DebugExtraction>(
                     transaction_hash: Some(format!("{}", create.transaction_hash)),
                     transaction_index: Some(create.transaction_position as i64),
                     trace_type: "create".to_string(),
-                    trace_address: Vec::new(),
+                    trace_address: create
+                        .trace_address
+                        .iter()
+                        .map(|x| *x as i64)
+                        .collect::<Vec<i64>>(),
                     subtrace_count: create.subtraces as i64,
                     action: TraceAction {
                         from_address: Some(format!("{}", create.action.from)),
                         to_address: None,
                         call_type: None,
-                        gas: Some(create.action.gas as i64),
+                        gas: trace_gas("gas", create.action.gas)?,
                         input: None,
                         value: Some(create.action.value.to_string()),
                         value_lossless: Some(create.action.value.to_string()),
@@ -805,14 +829,21 @@ pub async fn transform_traces<T: DebugExtraction>(
                         refund_balance_lossless: None,
                         self_destructed_address: None,
                     },
-                    result: create.result.as_ref().map(|res| TraceResult {
-                        gas_used: Some(res.gas_used as i64),
-                        output: None,
-                        address: Some(format!("{}", res.address)),
-                        code: Some(res.code.clone()),
-                    }),
+                    result: match &create.result {
+                        Some(res) => Some(TraceResult {
+                            gas_used: trace_gas("gas_used", res.gas_used)?,
+                            output: None,
+                            address: Some(format!("{}", res.address)),
+                            code: Some(res.code.clone()),
+                        }),
+                        None => None,
+                    },
                     error: create.error.clone(),
                     trace_index: trace_index as u64,
+                    depth,
+                    parent_trace_index,
+                    trace_id,
+                    status,
                 },
                 TxTrace::Empty(empty) => Trace {
                     block_hash: format!("{}", empty.block_hash),
@@ -821,13 +852,17 @@ pub async fn transform_traces<T: DebugExtraction>(
                     transaction_hash: Some(format!("{}", empty.transaction_hash)),
                     transaction_index: Some(empty.transaction_position as i64),
                     trace_type: "empty".to_string(),
-                    trace_address: Vec::new(),
+                    trace_address: empty
+                        .trace_address
+                        .iter()
+                        .map(|x| *x as i64)
+                        .collect::<Vec<i64>>(),
                     subtrace_count: empty.subtraces as i64,
                     action: TraceAction {
                         from_address: Some(format!("{}", empty.action.from)),
                         to_address: None,
                         call_type: None,
-                        gas: Some(empty.action.gas as i64),
+                        gas: trace_gas("gas", empty.action.gas)?,
                         input: None,
                         value: Some(empty.action.value.to_string()),
                         value_lossless: Some(empty.action.value.to_string()),
@@ -839,19 +874,292 @@ pub async fn transform_traces<T: DebugExtraction>(
                         refund_balance_lossless: None,
                         self_destructed_address: None,
                     },
-                    result: empty.result.as_ref().map(|res| TraceResult {
-                        gas_used: Some(res.gas_used as i64),
-                        output: None,
-                        address: None,
-                        code: None,
-                    }),
+                    result: match &empty.result {
+                        Some(res) => Some(TraceResult {
+                            gas_used: trace_gas("gas_used", Some(res.gas_used))?,
+                            output: None,
+                            address: None,
+                            code: None,
+                        }),
+                        None => None,
+                    },
                     error: empty.error.clone(),
                     trace_index: trace_index as u64,
+                    depth,
+                    parent_trace_index,
+                    trace_id,
+                    status,
                 },
             };
             records.push(trace_out);
         }
     }
 
-    Ok((trace_records_vec, count, pertx))
+    Ok((trace_records_vec, native_transfers, count, pertx))
+}
+
+/// Creates the record of the native value moved by the trace, if any: the value sent along with
+/// a call or a contract creation, or the balance of a self-destructed contract.  Delegate calls
+/// and call codes don't move value, as the value they carry is the one of their parent call.
+fn transform_native_transfer<T: DebugExtraction>(
+    extracted: &T,
+    trace_index: usize,
+    trace: &TxTrace,
+    node: &TraceNode,
+) -> Result<Option<NativeTransfer>, ExtractTransformErr> {
+    let (trace_type, from, to, value, block_hash, transaction_hash, transaction_position) =
+        match trace {
+            TxTrace::Call(call) => {
+                if matches!(
+                    call.action.call_type.as_str(),
+                    "delegatecall" | "callcode" | "staticcall"
+                ) {
+                    return Ok(None);
+                }
+                let value = match U256::from_str_radix(
+                    call.action
+                        .value
+                        .strip_prefix("0x")
+                        .unwrap_or(&call.action.value),
+                    16,
+                ) {
+                    Ok(value) => value,
+                    Err(err) => return Err(TransformationErr::invalid("value", err).into()),
+                };
+                (
+                    "call",
+                    call.action.from,
+                    Some(call.action.to),
+                    value,
+                    call.block_hash,
+                    call.transaction_hash,
+                    call.transaction_position,
+                )
+            }
+            TxTrace::Create(create) => (
+                "create",
+                create.action.from,
+                create.result.as_ref().map(|result| result.address),
+                U256::from(create.action.value),
+                create.block_hash,
+                create.transaction_hash,
+                create.transaction_position,
+            ),
+            TxTrace::Suicide(suicide) => (
+                "suicide",
+                suicide.action.self_destructed_address,
+                suicide.action.refund_address,
+                U256::from(suicide.action.balance),
+                suicide.block_hash,
+                suicide.transaction_hash,
+                suicide.transaction_position,
+            ),
+            TxTrace::Reward(_) | TxTrace::Empty(_) => return Ok(None),
+        };
+
+    if value.is_zero() {
+        return Ok(None);
+    }
+
+    Ok(Some(NativeTransfer {
+        block_hash: format!("{}", block_hash),
+        block_number: extracted.block_number() as i64,
+        block_timestamp: extracted.block_timestamp(),
+        transaction_hash: format!("{}", transaction_hash),
+        transaction_index: transaction_position as i64,
+        trace_index: trace_index as i64,
+        trace_type: trace_type.to_string(),
+        from_address: format!("{}", from),
+        to_address: to.map(|to| format!("{}", to)),
+        value: cap_bignumeric(&value),
+        value_lossless: value.to_string(),
+        status: if node.failed { 0 } else { 1 },
+    }))
+}
+
+/// The change type, and the values before and after, of a changed value.  Returns `None` if the
+/// value didn't change.
+fn delta_values<T>(
+    delta: &Delta<T>,
+    format: impl Fn(&T) -> String,
+) -> Option<(&'static str, Option<String>, Option<String>)> {
+    match delta {
+        Delta::Unchanged => None,
+        Delta::Added(to) => Some(("added", None, Some(format(to)))),
+        Delta::Removed(from) => Some(("removed", Some(format(from)), None)),
+        Delta::Changed(changed) => Some((
+            "changed",
+            Some(format(&changed.from)),
+            Some(format(&changed.to)),
+        )),
+    }
+}
+
+/// Creates a record for every value changed by the transactions of the block: the balance,
+/// nonce, and code of each account, and each of its storage slots.
+pub fn transform_state_diffs(
+    extracted: &StateDiffs,
+) -> Result<Vec<StateDiff>, ExtractTransformErr> {
+    debug!("Handling state diffs #{}", extracted.block_number());
+
+    let mut records = Vec::new();
+
+    for tx_diff in extracted.iter() {
+        let transaction_index = match tx_diff.transaction_position.try_into_integer() {
+            Ok(casted) => casted,
+            Err(err) => {
+                return Err(TransformationErr::out_of_range("transaction_index", err).into())
+            }
+        };
+
+        for (address, account_diff) in tx_diff.state_diff.iter() {
+            let mut changes = Vec::new();
+            changes.extend(
+                delta_values(&account_diff.balance, |balance| balance.to_string())
+                    .map(|values| ("balance", None, values)),
+            );
+            changes.extend(
+                delta_values(&account_diff.nonce, |nonce| nonce.to_string())
+                    .map(|values| ("nonce", None, values)),
+            );
+            changes.extend(
+                delta_values(&account_diff.code, |code| code.to_string())
+                    .map(|values| ("code", None, values)),
+            );
+            for (key, delta) in account_diff.storage.iter() {
+                changes.extend(
+                    delta_values(delta, |value| format_fixed_bytes(*value))
+                        .map(|values| ("storage", Some(format_fixed_bytes(*key)), values)),
+                );
+            }
+
+            for (field, storage_key, (change_type, from_value, to_value)) in changes {
+                records.push(StateDiff {
+                    block_hash: format_fixed_bytes(extracted.block_hash()),
+                    block_number: extracted.block_number() as i64,
+                    block_timestamp: extracted.block_timestamp(),
+                    transaction_hash: format_fixed_bytes(tx_diff.transaction_hash),
+                    transaction_index,
+                    address: format!("{}", address),
+                    field: field.to_string(),
+                    storage_key,
+                    change_type: change_type.to_string(),
+                    from_value,
+                    to_value,
+                    state_diff_index: records.len() as u64,
+                });
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Creates a record for every token first seen within the block.
+pub fn transform_tokens<T: EvmExtraction>(
+    extracted: &T,
+    tokens: &[TokenMetadata],
+) -> Result<Vec<Token>, ExtractTransformErr> {
+    debug!("Handling tokens #{}", extracted.block_number());
+
+    Ok(tokens
+        .iter()
+        .map(|token| Token {
+            block_hash: format_fixed_bytes(extracted.block_hash()),
+            block_number: extracted.block_number() as i64,
+            block_timestamp: extracted.block_timestamp(),
+            address: format!("{}", token.address),
+            name: token.name.clone(),
+            symbol: token.symbol.clone(),
+            decimals: token.decimals.map(|decimals| decimals as i64),
+            total_supply: token.total_supply.as_ref().map(cap_bignumeric),
+            total_supply_lossless: token.total_supply.map(|supply| supply.to_string()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::blockchain_config::extraction::DebugTraces;
+
+    fn call(value: &str, gas_used: u64) -> TxTrace {
+        serde_json::from_value(serde_json::json!({
+            "type": "call",
+            "action": {
+                "from": "0x0000000000000000000000000000000000000001",
+                "callType": "call",
+                "gas": "0x0",
+                "input": "0x",
+                "to": "0x0000000000000000000000000000000000000002",
+                "value": value
+            },
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": 1,
+            "result": { "gasUsed": format!("0x{:x}", gas_used), "output": "0x" },
+            "subtraces": 0,
+            "traceAddress": [],
+            "transactionHash": format!("0x{:064x}", 0),
+            "transactionPosition": 0
+        }))
+        .expect("valid call trace")
+    }
+
+    #[tokio::test]
+    async fn odd_traces_fail_the_block_instead_of_panicking() {
+        let transform = |trace: TxTrace| async move {
+            let extracted = DebugTraces::new(1, None, 1_700_000_000, vec![trace]);
+            transform_traces(&extracted, true, false, false, false).await
+        };
+
+        let (traces, _, _, _) = transform(call("0x10", 21000)).await.unwrap();
+        assert_eq!(
+            traces.unwrap()[0].action.value_lossless.as_deref(),
+            Some("16")
+        );
+
+        match transform(call("0xnothex", 21000)).await {
+            Err(ExtractTransformErr::Transformation(err)) => {
+                assert_eq!(err.code(), TransformationErrCode::InvalidField);
+                assert_eq!(err.field(), Some("value"));
+            }
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+
+        match transform(call("0x0", u64::MAX)).await {
+            Err(ExtractTransformErr::Transformation(err)) => {
+                assert_eq!(err.code(), TransformationErrCode::OutOfRange);
+                assert_eq!(err.field(), Some("gas_used"));
+            }
+            other => panic!("expected an oversized gas_used, got {:?}", other),
+        }
+
+        // the gas of every kind of trace is checked, not only the one of calls
+        let create: TxTrace = serde_json::from_value(serde_json::json!({
+            "type": "create",
+            "action": {
+                "from": "0x0000000000000000000000000000000000000001",
+                "gas": format!("0x{:x}", u128::MAX),
+                "init": "0x",
+                "value": "0x0"
+            },
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": 1,
+            "result": null,
+            "error": "Out of gas",
+            "subtraces": 0,
+            "traceAddress": [],
+            "transactionHash": format!("0x{:064x}", 0),
+            "transactionPosition": 0
+        }))
+        .expect("valid create trace");
+        match transform(create).await {
+            Err(ExtractTransformErr::Transformation(err)) => {
+                assert_eq!(err.code(), TransformationErrCode::OutOfRange);
+                assert_eq!(err.field(), Some("gas"));
+            }
+            other => panic!("expected an oversized gas, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,226 @@
+//! Derives the `contracts` records from the `create` traces, along with what can be told about
+//! each contract from its deployed bytecode alone.
+
+use std::collections::BTreeSet;
+
+use alloy::primitives::keccak256;
+use log::debug;
+
+use crate::blockchain_config::extraction::{DebugExtraction, TxTrace};
+use crate::blockchain_config::transformation::call_tree::call_tree;
+use crate::blockchain_config::transformation::err::TransformationErr;
+use crate::blockchain_config::ExtractTransformErr;
+
+use super::proto_codegen::etl::contracts::Contract;
+
+const EQ: u8 = 0x14;
+const PUSH1: u8 = 0x60;
+const PUSH3: u8 = 0x62;
+const PUSH4: u8 = 0x63;
+const PUSH32: u8 = 0x7f;
+
+/// totalSupply, balanceOf, transfer, transferFrom, approve, allowance
+const ERC20_SIGHASHES: [u32; 6] = [
+    0x18160ddd, 0x70a08231, 0xa9059cbb, 0x23b872dd, 0x095ea7b3, 0xdd62ed3e,
+];
+
+/// balanceOf, ownerOf, safeTransferFrom (with and without data), transferFrom, approve,
+/// setApprovalForAll, getApproved, isApprovedForAll
+const ERC721_SIGHASHES: [u32; 9] = [
+    0x70a08231, 0x6352211e, 0x42842e0e, 0xb88d4fde, 0x23b872dd, 0x095ea7b3, 0xa22cb465, 0x081812fc,
+    0xe985e9c5,
+];
+
+/// balanceOf, balanceOfBatch, setApprovalForAll, isApprovedForAll, safeTransferFrom,
+/// safeBatchTransferFrom
+const ERC1155_SIGHASHES: [u32; 6] = [
+    0x00fdd58e, 0x4e1273f4, 0xa22cb465, 0xe985e9c5, 0xf242432a, 0x2eb2c2d6,
+];
+
+/// Returns the function selectors the dispatcher of the bytecode compares the calldata to.
+///
+/// The selectors are the values pushed right before an `EQ`, with either a `PUSH4`, or a `PUSH3`
+/// for the selectors starting with a zero byte.  The push data is skipped over, so that it isn't
+/// read as opcodes.
+pub fn function_sighashes(bytecode: &[u8]) -> BTreeSet<u32> {
+    let mut sighashes = BTreeSet::new();
+
+    let mut pc = 0;
+    while pc < bytecode.len() {
+        let opcode = bytecode[pc];
+        if !(PUSH1..=PUSH32).contains(&opcode) {
+            pc += 1;
+            continue;
+        }
+
+        let data_len = (opcode - PUSH1 + 1) as usize;
+        let data_end = pc + 1 + data_len;
+        if (opcode == PUSH3 || opcode == PUSH4) && bytecode.get(data_end) == Some(&EQ) {
+            let sighash = bytecode[pc + 1..data_end]
+                .iter()
+                .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+            sighashes.insert(sighash);
+        }
+        pc = data_end;
+    }
+
+    sighashes
+}
+
+#[inline]
+fn implements(sighashes: &BTreeSet<u32>, interface: &[u32]) -> bool {
+    interface.iter().all(|sighash| sighashes.contains(sighash))
+}
+
+/// Creates a record for every contract successfully created within the block.
+pub fn transform_contracts<T: DebugExtraction>(
+    extracted: &T,
+) -> Result<Vec<Contract>, ExtractTransformErr> {
+    debug!("Handling contracts #{}", extracted.block_number());
+
+    let traces = extracted.traces();
+    let nodes = call_tree(&traces);
+    let mut records = Vec::new();
+
+    for (trace, node) in traces.into_iter().zip(nodes) {
+        let create = match trace {
+            TxTrace::Create(create) => create,
+            _ => continue,
+        };
+        // failed creations don't deploy anything, nor do the ones reverted along with a trace
+        // they are nested in
+        let result = match create.result {
+            Some(result) if !node.failed => result,
+            _ => continue,
+        };
+
+        let bytecode = match hex::decode(result.code.strip_prefix("0x").unwrap_or(&result.code)) {
+            Ok(bytecode) => bytecode,
+            Err(err) => return Err(TransformationErr::invalid("bytecode", err).into()),
+        };
+        let sighashes = function_sighashes(&bytecode);
+
+        records.push(Contract {
+            block_hash: format!("{}", create.block_hash),
+            block_number: extracted.block_number() as i64,
+            block_timestamp: extracted.block_timestamp(),
+            transaction_hash: format!("{}", create.transaction_hash),
+            transaction_index: create.transaction_position as i64,
+            address: format!("{}", result.address),
+            creator_address: format!("{}", create.action.from),
+            bytecode_hash: format!("{}", keccak256(&bytecode)),
+            bytecode: result.code,
+            function_sighashes: sighashes
+                .iter()
+                .map(|sighash| format!("0x{:08x}", sighash))
+                .collect(),
+            is_erc20: implements(&sighashes, &ERC20_SIGHASHES),
+            is_erc721: implements(&sighashes, &ERC721_SIGHASHES),
+            is_erc1155: implements(&sighashes, &ERC1155_SIGHASHES),
+        });
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::blockchain_config::extraction::DebugTraces;
+
+    fn trace(
+        trace_type: &str,
+        trace_address: &[u64],
+        subtraces: u64,
+        error: Option<&str>,
+    ) -> TxTrace {
+        let (action, result) = match trace_type {
+            "create" => (
+                serde_json::json!({
+                    "from": "0x0000000000000000000000000000000000000001",
+                    "gas": "0x0",
+                    "init": "0x",
+                    "value": "0x0"
+                }),
+                serde_json::json!({
+                    "address": format!("0x{:040x}", trace_address.len() + 10),
+                    "code": "0x6000",
+                    "gasUsed": "0x0"
+                }),
+            ),
+            _ => (
+                serde_json::json!({
+                    "from": "0x0000000000000000000000000000000000000001",
+                    "callType": "call",
+                    "gas": "0x0",
+                    "input": "0x",
+                    "to": "0x0000000000000000000000000000000000000002",
+                    "value": "0x0"
+                }),
+                serde_json::Value::Null,
+            ),
+        };
+        serde_json::from_value(serde_json::json!({
+            "type": trace_type,
+            "action": action,
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": 1,
+            "error": error,
+            "result": result,
+            "subtraces": subtraces,
+            "traceAddress": trace_address,
+            "transactionHash": format!("0x{:064x}", 1),
+            "transactionPosition": 0
+        }))
+        .expect("valid trace")
+    }
+
+    #[test]
+    fn skips_creations_reverted_by_an_ancestor() {
+        // 0 -> [create 0 (deployed), call 1 (reverted) -> [create 1_0]]
+        let traces = vec![
+            trace("call", &[], 2, None),
+            trace("create", &[0], 0, None),
+            trace("call", &[1], 1, Some("Reverted")),
+            trace("create", &[1, 0], 0, None),
+        ];
+        let extracted = DebugTraces::new(1, None, 1_700_000_000, traces);
+
+        let records = transform_contracts(&extracted).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].address, format!("0x{:040x}", 11));
+    }
+
+    #[test]
+    fn finds_dispatcher_sighashes_only() {
+        let mut bytecode = vec![
+            // PUSH32 whose data looks like `PUSH4 0x12345678 EQ`
+            0x7f, 0x63, 0x12, 0x34, 0x56, 0x78, 0x14,
+        ];
+        bytecode.extend([0u8; 26]);
+        // PUSH1 0xe0 SHR
+        bytecode.extend([0x60, 0xe0, 0x1c]);
+        for sighash in ERC20_SIGHASHES {
+            // DUP1 PUSH4 <sighash> EQ PUSH2 0x0000 JUMPI
+            bytecode.extend([0x80, 0x63]);
+            bytecode.extend(sighash.to_be_bytes());
+            bytecode.extend([0x14, 0x61, 0x00, 0x00, 0x57]);
+        }
+        // DUP1 PUSH3 0xfdd58e EQ, the ERC-1155 balanceOf
+        bytecode.extend([0x80, 0x62, 0xfd, 0xd5, 0x8e, 0x14]);
+        // PUSH4 0xffffffff AND, not a comparison
+        bytecode.extend([0x63, 0xff, 0xff, 0xff, 0xff, 0x16]);
+
+        let sighashes = function_sighashes(&bytecode);
+
+        assert_eq!(sighashes.len(), 7);
+        assert!(!sighashes.contains(&0x12345678));
+        assert!(!sighashes.contains(&0xffffffff));
+        assert!(sighashes.contains(&0x00fdd58e));
+        assert!(implements(&sighashes, &ERC20_SIGHASHES));
+        assert!(!implements(&sighashes, &ERC721_SIGHASHES));
+        assert!(!implements(&sighashes, &ERC1155_SIGHASHES));
+    }
+}
@@ -1,16 +1,90 @@
+/// What went wrong while transforming a block, recorded along with the failed block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformationErrCode {
+    /// A field needed by the transformation is missing from the extraction
+    MissingField,
+    /// A field of the extraction couldn't be parsed
+    InvalidField,
+    /// A number doesn't fit in the type of its column
+    OutOfRange,
+    /// A field couldn't be serialized into its column (e.g. the decoded arguments of an event)
+    Serialization,
+    /// The decoded events or token transfers were requested without an event catalog
+    MissingCatalog,
+}
+
+impl TransformationErrCode {
+    /// The code of the error, as recorded in the dead letters.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MissingField => "missing_field",
+            Self::InvalidField => "invalid_field",
+            Self::OutOfRange => "out_of_range",
+            Self::Serialization => "serialization",
+            Self::MissingCatalog => "missing_catalog",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TransformationErr {
+    code: TransformationErrCode,
     err: String,
     field: Option<String>,
 }
 
 impl TransformationErr {
     #[inline]
-    pub fn new(err: String, field: Option<String>) -> Self {
-        Self {
-            err: err.to_string(),
-            field: field.map(|item| item.to_string()),
-        }
+    pub fn with_code(code: TransformationErrCode, err: String, field: Option<String>) -> Self {
+        Self { code, err, field }
+    }
+
+    /// The field is missing from the extraction.
+    #[inline]
+    pub fn missing(field: &str) -> Self {
+        Self::with_code(
+            TransformationErrCode::MissingField,
+            format!("Missing {}", field),
+            Some(field.to_string()),
+        )
+    }
+
+    /// The field of the extraction couldn't be parsed.
+    #[inline]
+    pub fn invalid(field: &str, err: impl std::fmt::Display) -> Self {
+        Self::with_code(
+            TransformationErrCode::InvalidField,
+            err.to_string(),
+            Some(field.to_string()),
+        )
+    }
+
+    /// The field doesn't fit in the type of its column.
+    #[inline]
+    pub fn out_of_range(field: &str, err: impl std::fmt::Display) -> Self {
+        Self::with_code(
+            TransformationErrCode::OutOfRange,
+            err.to_string(),
+            Some(field.to_string()),
+        )
+    }
+
+    /// What went wrong.
+    #[inline]
+    pub fn code(&self) -> TransformationErrCode {
+        self.code
+    }
+
+    /// The message of the error.
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.err
+    }
+
+    /// The field being transformed when the error occurred, if known.
+    #[inline]
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
     }
 }
 
@@ -20,10 +94,17 @@ impl std::fmt::Display for TransformationErr {
         match &self.field {
             Some(field_name) => write!(
                 f,
-                "Error during transformation with field `{}`: {}",
-                field_name, self.err
+                "Error during transformation with field `{}` ({}): {}",
+                field_name,
+                self.code.as_str(),
+                self.err
+            ),
+            None => write!(
+                f,
+                "Error during transformation ({}): {}",
+                self.code.as_str(),
+                self.err
             ),
-            None => write!(f, "Error during transformation: {}", self.err),
         }
     }
 }
@@ -1,6 +1,9 @@
 use super::{extraction::EvmExtraction, proto_codegen};
 
 pub mod bq;
+pub mod call_tree;
 pub mod common;
+pub mod contracts;
 pub mod err;
 pub mod events;
+pub mod token_transfers;
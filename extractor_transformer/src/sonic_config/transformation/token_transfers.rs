@@ -0,0 +1,212 @@
+//! Derives the `token_transfers` records from the ERC-20, ERC-721 and ERC-1155 transfer events
+//! decoded by the event catalog.  ERC-1155 batch transfers are exploded into one record per ID.
+
+use alloy::dyn_abi::DynSolValue;
+use alloy::primitives::{Address, U256};
+use log::warn;
+
+use super::events::generic::DecodedEventExt;
+
+pub const ERC20: &str = "ERC20";
+pub const ERC721: &str = "ERC721";
+pub const ERC1155: &str = "ERC1155";
+
+const TRANSFER_SIGNATURE: &str = "Transfer(address,address,uint256)";
+const TRANSFER_SINGLE_SIGNATURE: &str = "TransferSingle(address,address,address,uint256,uint256)";
+const TRANSFER_BATCH_SIGNATURE: &str = "TransferBatch(address,address,address,uint256[],uint256[])";
+
+/// A single token transfer, as read from the arguments of a transfer event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedTransfer {
+    pub standard: &'static str,
+    pub operator: Option<Address>,
+    pub from: Address,
+    pub to: Address,
+    pub token_id: Option<U256>,
+    pub value: Option<U256>,
+    pub batch_index: Option<usize>,
+}
+
+/// The arguments of a decoded event, in the order of its inputs.
+struct Args<'a>(Vec<&'a DynSolValue>);
+
+impl<'a> Args<'a> {
+    /// Interleaves the indexed arguments and the body back in the order of the inputs.
+    fn new(event: &'a DecodedEventExt) -> Option<Self> {
+        let mut indexed = event.decoded.indexed.iter();
+        let mut body = event.decoded.body.iter();
+
+        event
+            .event
+            .inputs
+            .iter()
+            .map(|input| match input.indexed {
+                true => indexed.next(),
+                false => body.next(),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Self)
+    }
+
+    fn address(&self, index: usize) -> Option<Address> {
+        self.0.get(index)?.as_address()
+    }
+
+    fn uint(&self, index: usize) -> Option<U256> {
+        self.0.get(index)?.as_uint().map(|(uint, _)| uint)
+    }
+
+    fn uints(&self, index: usize) -> Option<Vec<U256>> {
+        self.0
+            .get(index)?
+            .as_array()?
+            .iter()
+            .map(|value| value.as_uint().map(|(uint, _)| uint))
+            .collect()
+    }
+}
+
+/// Returns the transfers of the event, which is empty if the event isn't a token transfer.
+pub fn decode_token_transfers(event: &DecodedEventExt) -> Vec<DecodedTransfer> {
+    let signature = event.event.signature();
+    let decode = match signature.as_str() {
+        TRANSFER_SIGNATURE => decode_transfer,
+        TRANSFER_SINGLE_SIGNATURE => decode_transfer_single,
+        TRANSFER_BATCH_SIGNATURE => decode_transfer_batch,
+        _ => return Vec::new(),
+    };
+
+    match Args::new(event).and_then(|args| decode(event, &args)) {
+        Some(transfers) => transfers,
+        None => {
+            warn!("Unexpected arguments in decoded {} event", signature);
+            Vec::new()
+        }
+    }
+}
+
+/// `Transfer` is shared by ERC-20 and ERC-721, the latter indexing the token ID while the former
+/// leaves the value in the body.
+fn decode_transfer(event: &DecodedEventExt, args: &Args) -> Option<Vec<DecodedTransfer>> {
+    let (standard, token_id, value) = match event.event.inputs[2].indexed {
+        true => (ERC721, Some(args.uint(2)?), None),
+        false => (ERC20, None, Some(args.uint(2)?)),
+    };
+    Some(vec![DecodedTransfer {
+        standard,
+        operator: None,
+        from: args.address(0)?,
+        to: args.address(1)?,
+        token_id,
+        value,
+        batch_index: None,
+    }])
+}
+
+fn decode_transfer_single(_event: &DecodedEventExt, args: &Args) -> Option<Vec<DecodedTransfer>> {
+    Some(vec![DecodedTransfer {
+        standard: ERC1155,
+        operator: Some(args.address(0)?),
+        from: args.address(1)?,
+        to: args.address(2)?,
+        token_id: Some(args.uint(3)?),
+        value: Some(args.uint(4)?),
+        batch_index: None,
+    }])
+}
+
+fn decode_transfer_batch(_event: &DecodedEventExt, args: &Args) -> Option<Vec<DecodedTransfer>> {
+    let (operator, from, to) = (args.address(0)?, args.address(1)?, args.address(2)?);
+    let (ids, values) = (args.uints(3)?, args.uints(4)?);
+    if ids.len() != values.len() {
+        return None;
+    }
+
+    Some(
+        ids.into_iter()
+            .zip(values)
+            .enumerate()
+            .map(|(batch_index, (token_id, value))| DecodedTransfer {
+                standard: ERC1155,
+                operator: Some(operator),
+                from,
+                to,
+                token_id: Some(token_id),
+                value: Some(value),
+                batch_index: Some(batch_index),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloy::dyn_abi::{DecodedEvent, DynSolValue};
+    use alloy::json_abi::Event;
+    use alloy::primitives::address;
+
+    #[test]
+    fn explodes_batch_transfers() {
+        let operator = address!("00ad14c150da7a21872d4e934f4fa37a13e3d8d0");
+        let from = address!("039e2fb66102314ce7b64ce5ce3e5183bc94ad38");
+        let to = address!("7ae8a64a3d3a0a0f1c0a5b1bd2f0ce0e3a9b1f35");
+        let uints = |uints: &[u64]| {
+            DynSolValue::Array(
+                uints
+                    .iter()
+                    .map(|uint| DynSolValue::Uint(U256::from(*uint), 256))
+                    .collect(),
+            )
+        };
+
+        let batch = DecodedEventExt {
+            event: Event::parse("TransferBatch(address indexed operator, address indexed from, address indexed to, uint256[] ids, uint256[] values)").unwrap(),
+            decoded: DecodedEvent {
+                selector: None,
+                indexed: vec![
+                    DynSolValue::Address(operator),
+                    DynSolValue::Address(from),
+                    DynSolValue::Address(to),
+                ],
+                body: vec![uints(&[7, 8]), uints(&[1, 2])],
+            },
+        };
+        let transfers = decode_token_transfers(&batch);
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(
+            transfers[1],
+            DecodedTransfer {
+                standard: ERC1155,
+                operator: Some(operator),
+                from,
+                to,
+                token_id: Some(U256::from(8)),
+                value: Some(U256::from(2)),
+                batch_index: Some(1),
+            }
+        );
+
+        let nft = DecodedEventExt {
+            event: Event::parse(
+                "Transfer(address indexed from, address indexed to, uint256 indexed tokenId)",
+            )
+            .unwrap(),
+            decoded: DecodedEvent {
+                selector: None,
+                indexed: vec![
+                    DynSolValue::Address(from),
+                    DynSolValue::Address(to),
+                    DynSolValue::Uint(U256::from(42), 256),
+                ],
+                body: vec![],
+            },
+        };
+        let transfers = decode_token_transfers(&nft);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].standard, ERC721);
+        assert_eq!(transfers[0].token_id, Some(U256::from(42)));
+        assert_eq!(transfers[0].value, None);
+    }
+}
@@ -0,0 +1,20 @@
+
+
+contracts.protoetl.contracts"
+Contract
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp)
+transaction_hash (	RtransactionHash+
+transaction_index (RtransactionIndex
+address (	Raddress'
+creator_address (	RcreatorAddress#
+bytecode_hash (	RbytecodeHash
+bytecode	 (	Rbytecode-
+function_sighashes
+ (	RfunctionSighashes
+is_erc20 (RisErc20
+	is_erc721 (RisErc721
+
+is_erc1155 (R	isErc1155
\ No newline at end of file
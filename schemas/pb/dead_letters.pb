@@ -0,0 +1,14 @@
+
+
+dead_letters.protoetl.dead_letters"
+
+DeadLetter!
+block_number (RblockNumber
+
+error_kind (	R	errorKind
+
+error_code (	R	errorCode
+field (	Rfield
+message (	Rmessage)
+basic_extraction (	RbasicExtraction)
+debug_extraction (	RdebugExtraction
\ No newline at end of file
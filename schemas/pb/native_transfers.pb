@@ -0,0 +1,21 @@
+
+
+native_transfers.protoetl.native_transfers"
+NativeTransfer
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp)
+transaction_hash (	RtransactionHash+
+transaction_index (RtransactionIndex
+trace_index (R
+traceIndex
+
+trace_type (	R	traceType!
+from_address (	RfromAddress
+
+to_address	 (	R	toAddress
+value
+ (	Rvalue%
+value_lossless (	RvalueLossless
+status (Rstatus
\ No newline at end of file
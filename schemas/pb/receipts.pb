@@ -1,6 +1,6 @@
 
-
-receipts.protoetl.receipts"
+
+receipts.protoetl.receipts"
 Receipt
 
 block_hash (	R	blockHash!
@@ -19,4 +19,5 @@ to_address (	R	toAddress)
 
 logs_bloom (	R	logsBloom
 root (	Rroot
-status (Rstatus
\ No newline at end of file
+status (Rstatus
+	log_count (RlogCount
\ No newline at end of file
@@ -0,0 +1,11 @@
+
+
+reorgs.proto
+etl.reorgs"
+Reorg
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp.
+orphaned_block_hash (	RorphanedBlockHash7
+detected_at_block_number (RdetectedAtBlockNumber
\ No newline at end of file
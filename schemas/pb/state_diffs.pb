@@ -0,0 +1,21 @@
+
+
+state_diffs.protoetl.state_diffs"
+	StateDiff
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp)
+transaction_hash (	RtransactionHash+
+transaction_index (RtransactionIndex
+address (	Raddress
+field (	Rfield
+storage_key (	R
+storageKey
+change_type	 (	R
+changeType
+
+from_value
+ (	R	fromValue
+to_value (	RtoValue(
+state_diff_index (RstateDiffIndex
\ No newline at end of file
@@ -0,0 +1,23 @@
+
+
+token_transfers.protoetl.token_transfers"
+TokenTransfer
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp)
+transaction_hash (	RtransactionHash+
+transaction_index (RtransactionIndex
+	log_index (RlogIndex#
+token_address (	RtokenAddress%
+token_standard (	RtokenStandard!
+from_address	 (	RfromAddress
+
+to_address
+ (	R	toAddress)
+operator_address (	RoperatorAddress
+token_id (	RtokenId
+value (	Rvalue%
+value_lossless (	RvalueLossless
+batch_index (R
+batchIndex
\ No newline at end of file
@@ -0,0 +1,15 @@
+
+
+tokens.proto
+etl.tokens"
+Token
+
+block_hash (	R	blockHash!
+block_number (RblockNumber'
+block_timestamp (RblockTimestamp
+address (	Raddress
+name (	Rname
+symbol (	Rsymbol
+decimals (Rdecimals!
+total_supply (	RtotalSupply2
+total_supply_lossless	 (	RtotalSupplyLossless
\ No newline at end of file
@@ -1,7 +1,7 @@
 
-�
+	
 traces.proto
-etl.traces"�
+etl.traces"	
 Trace
 
 block_hash (	R	blockHash!
@@ -11,16 +11,18 @@ block_hash (	R	blockHash!
 transaction_index (RtransactionIndex
 
 trace_type (	R	traceType#
-
-trace_address (RtraceAddress%
-subtrace_count (R
-subtraceCount5
+trace_address (RtraceAddress%
+subtrace_count (RsubtraceCount5
 action	 (2.etl.traces.Trace.TraceActionRaction5
 result
  (2.etl.traces.Trace.TraceResultRresult
 error (	Rerror
 trace_index (R
-traceIndex�
+traceIndex
+depth (Rdepth,
+parent_trace_index (RparentTraceIndex
+trace_id (	RtraceId
+status (Rstatus
 TraceAction!
 from_address (	RfromAddress
 
@@ -29,19 +31,15 @@ to_address (	R	toAddress
 gas (Rgas
 input (	Rinput
 value (	Rvalue%
-value_lossless (	R
-valueLossless
+value_lossless (	RvalueLossless
 init (	Rinit
 author	 (	Rauthor
 reward_type
  (	R
 rewardType%
-refund_address (	R
-refundAddress%
-refund_balance (	R
-refundBalance6
-refund_balance_lossless
- (	RrefundBalanceLossless6
+refund_address (	RrefundAddress%
+refund_balance (	RrefundBalance6
+refund_balance_lossless (	RrefundBalanceLossless6
 self_destructed_address (	RselfDestructedAddressn
 TraceResult
 gas_used (RgasUsed